@@ -22,6 +22,7 @@ pub struct DiagnosticsConfig {
   pub unused_definition: Severity,
   pub repeated_bind: Severity,
   pub recursion_cycle: Severity,
+  pub infinite_expansion: Severity,
   pub missing_main: Severity,
   pub import_shadow: Severity,
 }
@@ -59,6 +60,7 @@ pub enum WarningType {
   UnusedDefinition,
   RepeatedBind,
   RecursionCycle,
+  InfiniteExpansion,
   MissingMain,
   ImportShadow,
 }
@@ -239,6 +241,7 @@ impl DiagnosticsConfig {
       unused_definition: severity,
       repeated_bind: severity,
       recursion_cycle: severity,
+      infinite_expansion: severity,
       import_shadow: severity,
       // Should only be changed manually, as a missing main is always a error to hvm
       missing_main: Severity::Error,
@@ -251,6 +254,7 @@ impl DiagnosticsConfig {
       WarningType::UnusedDefinition => self.unused_definition,
       WarningType::RepeatedBind => self.repeated_bind,
       WarningType::RecursionCycle => self.recursion_cycle,
+      WarningType::InfiniteExpansion => self.infinite_expansion,
       WarningType::IrrefutableMatch => self.irrefutable_match,
       WarningType::RedundantMatch => self.redundant_match,
       WarningType::UnreachableMatch => self.unreachable_match,
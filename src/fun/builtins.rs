@@ -35,15 +35,43 @@ pub const TREE_LEAF: &str = "Tree/Leaf";
 pub const MAP: &str = "Map";
 pub const MAP_NODE: &str = "Map/Node";
 pub const MAP_LEAF: &str = "Map/Leaf";
+pub const MAP_HOLE: &str = "Map/Hole";
+pub const MAP_HASHED: &str = "Map/Hashed";
+
+pub const RESULT: &str = "Result";
+pub const RESULT_OK: &str = "Result/Ok";
+pub const RESULT_ERR: &str = "Result/Err";
+
+pub const OPTION: &str = "Option";
+pub const OPTION_SOME: &str = "Option/Some";
+pub const OPTION_NONE: &str = "Option/None";
 
 pub const IO: &str = "IO";
 pub const IO_DONE: &str = "IO/Done";
 pub const IO_CALL: &str = "IO/Call";
 
-pub const BUILTIN_CTRS: &[&str] =
-  &[LCONS, LNIL, SCONS, SNIL, NAT_SUCC, NAT_ZERO, TREE_NODE, TREE_LEAF, MAP_NODE, MAP_LEAF, IO_DONE, IO_CALL];
-
-pub const BUILTIN_TYPES: &[&str] = &[LIST, STRING, NAT, TREE, MAP, IO];
+pub const BUILTIN_CTRS: &[&str] = &[
+  LCONS,
+  LNIL,
+  SCONS,
+  SNIL,
+  NAT_SUCC,
+  NAT_ZERO,
+  TREE_NODE,
+  TREE_LEAF,
+  MAP_NODE,
+  MAP_LEAF,
+  MAP_HOLE,
+  MAP_HASHED,
+  RESULT_OK,
+  RESULT_ERR,
+  OPTION_SOME,
+  OPTION_NONE,
+  IO_DONE,
+  IO_CALL,
+];
+
+pub const BUILTIN_TYPES: &[&str] = &[LIST, STRING, NAT, TREE, MAP, RESULT, OPTION, IO];
 
 impl ParseBook {
   pub fn builtins() -> Self {
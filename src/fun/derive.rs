@@ -0,0 +1,222 @@
+use super::{Adt, CtrField, Definition, Name, Num, Op, Pattern, Rule, Term, Type};
+
+/// A function that can be generated for a datatype with a `deriving` clause.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Derive {
+  /// `Type/equal(a, b) -> u24`
+  Equal,
+  /// `Type/compare(a, b) -> i24`
+  Compare,
+  /// `Type/show(a) -> String`
+  Show,
+  /// `Type/hash(a) -> u24`
+  Hash,
+}
+
+impl Derive {
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "equal" => Some(Derive::Equal),
+      "compare" => Some(Derive::Compare),
+      "show" => Some(Derive::Show),
+      "hash" => Some(Derive::Hash),
+      _ => None,
+    }
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Derive::Equal => "equal",
+      Derive::Compare => "compare",
+      Derive::Show => "show",
+      Derive::Hash => "hash",
+    }
+  }
+
+  /// Generates the definition of `{typ}/{derive}` from the constructors of the datatype.
+  ///
+  /// For each type parameter, the generated function first receives the function
+  /// to use on the values of that type, followed by the values being operated on.
+  pub fn gen_def(self, typ: &Name, adt: &Adt, builtin: bool) -> Result<Definition, String> {
+    let name = Name::new(format!("{typ}/{}", self.name()));
+    let params = adt.params.iter().map(|p| Pattern::Var(Some(p.clone()))).collect::<Vec<_>>();
+    let mut rules = vec![];
+    for (ctr_idx, (ctr, fields)) in adt.ctrs.iter().enumerate() {
+      let field_fns = fields
+        .iter()
+        .map(|field| {
+          self.field_fn(typ, &adt.params, field).ok_or_else(|| {
+            format!(
+              "Can't derive '{}' for type '{typ}' because the field '{}' of '{ctr}' is a function.",
+              self.name(),
+              field.nam
+            )
+          })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+      let (pats, body) = match self {
+        Derive::Equal => {
+          (vec![ctr_pat(ctr, fields, "a"), ctr_pat(ctr, fields, "b")], gen_equal(fields, field_fns))
+        }
+        Derive::Compare => {
+          (vec![ctr_pat(ctr, fields, "a"), ctr_pat(ctr, fields, "b")], gen_compare(fields, field_fns))
+        }
+        Derive::Show => (vec![ctr_pat(ctr, fields, "a")], gen_show(ctr, fields, field_fns)),
+        Derive::Hash => (vec![ctr_pat(ctr, fields, "a")], gen_hash(ctr_idx, fields, field_fns)),
+      };
+      rules.push(Rule { pats: params.iter().cloned().chain(pats).collect(), body });
+    }
+    // Values with different constructors.
+    if adt.ctrs.len() > 1 {
+      let erased_params = adt.params.iter().map(|_| Pattern::Var(None));
+      match self {
+        Derive::Equal => {
+          let pats = erased_params.chain([Pattern::Var(None), Pattern::Var(None)]).collect();
+          rules.push(Rule { pats, body: Term::Num { val: Num::U24(0) } });
+        }
+        Derive::Compare => {
+          let pats = erased_params
+            .chain([Pattern::Var(Some(Name::new("a"))), Pattern::Var(Some(Name::new("b")))])
+            .collect();
+          let body = Term::call(Term::r#ref("u24/compare"), [ctr_index(adt, "a"), ctr_index(adt, "b")]);
+          rules.push(Rule { pats, body });
+        }
+        Derive::Show | Derive::Hash => {}
+      }
+    }
+    Ok(Definition::new_gen(name, rules, builtin))
+  }
+
+  /// Returns the function to apply on the values of a constructor field,
+  /// or `None` if it can't be derived for the field's type.
+  ///
+  /// Fields without a type annotation are either recursive or treated as `u24` numbers.
+  fn field_fn(self, typ: &Name, params: &[Name], field: &CtrField) -> Option<Term> {
+    match &field.typ {
+      Some(field_typ) => self.type_fn(field_typ),
+      None if field.rec => {
+        let self_typ = Type::Ctr(typ.clone(), params.iter().map(|p| Type::Var(p.clone())).collect());
+        self.type_fn(&self_typ)
+      }
+      None => self.type_fn(&Type::Ctr(Name::new("u24"), vec![])),
+    }
+  }
+
+  fn type_fn(self, typ: &Type) -> Option<Term> {
+    match typ {
+      Type::Var(param) => Some(Term::Var { nam: param.clone() }),
+      Type::Ctr(name, args) if args.is_empty() && matches!(name.as_ref(), "u24" | "i24" | "f24") => {
+        Some(match self {
+          Derive::Equal => num_lam(Op::EQ),
+          Derive::Compare => Term::r#ref(&format!("{name}/compare")),
+          Derive::Show => Term::r#ref(&format!("{name}/to_string")),
+          Derive::Hash if name == "u24" => {
+            Term::lam(Pattern::Var(Some(Name::new("x"))), Term::Var { nam: Name::new("x") })
+          }
+          Derive::Hash => Term::r#ref("to_u24"),
+        })
+      }
+      Type::Ctr(name, args) => {
+        let fun = match (name.as_ref(), self) {
+          ("String", Derive::Equal) => Term::r#ref("String/equals"),
+          _ => Term::r#ref(&format!("{name}/{}", self.name())),
+        };
+        let args = args.iter().map(|arg| self.type_fn(arg)).collect::<Option<Vec<_>>>()?;
+        Some(Term::call(fun, args))
+      }
+      Type::Arr(..) => None,
+    }
+  }
+}
+
+/// `(Ctr {side}.field1 {side}.field2 ...)`
+fn ctr_pat(ctr: &Name, fields: &[CtrField], side: &str) -> Pattern {
+  Pattern::Ctr(ctr.clone(), fields.iter().map(|f| Pattern::Var(Some(field_var(side, f)))).collect())
+}
+
+fn field_var(side: &str, field: &CtrField) -> Name {
+  Name::new(format!("{side}.{}", field.nam))
+}
+
+fn apply_field(fun: Term, field: &CtrField, sides: &[&str]) -> Term {
+  Term::call(fun, sides.iter().map(|side| Term::Var { nam: field_var(side, field) }))
+}
+
+/// `(& (eq a.x b.x) (& (eq a.y b.y) ...))`
+fn gen_equal(fields: &[CtrField], field_fns: Vec<Term>) -> Term {
+  let eqs = fields.iter().zip(field_fns).map(|(field, fun)| apply_field(fun, field, &["a", "b"]));
+  eqs.rev().reduce(|acc, eq| oper(Op::AND, eq, acc)).unwrap_or(Term::Num { val: Num::U24(1) })
+}
+
+/// Compares the fields in order, stopping at the first one that is different.
+fn gen_compare(fields: &[CtrField], field_fns: Vec<Term>) -> Term {
+  let fields = fields.iter().zip(field_fns).rev();
+  fields.fold(Term::Num { val: Num::I24(0) }, |acc, (field, fun)| {
+    let cmp = Name::new(format!("%cmp.{}", field.nam));
+    Term::Let {
+      pat: Box::new(Pattern::Var(Some(cmp.clone()))),
+      val: Box::new(apply_field(fun, field, &["a", "b"])),
+      nxt: Box::new(Term::Swt {
+        bnd: Some(Name::new("%cmp")),
+        arg: Box::new(oper(Op::EQ, Term::Var { nam: cmp.clone() }, Term::Num { val: Num::I24(0) })),
+        with_bnd: vec![],
+        with_arg: vec![],
+        pred: Some(Name::new("%cmp-1")),
+        arms: vec![Term::Var { nam: cmp }, acc],
+      }),
+    }
+  })
+}
+
+/// `"Type/Ctr { x: " ++ (show a.x) ++ ", y: " ++ (show a.y) ++ " }"`
+fn gen_show(ctr: &Name, fields: &[CtrField], field_fns: Vec<Term>) -> Term {
+  if fields.is_empty() {
+    return Term::str(ctr);
+  }
+  let mut parts = vec![];
+  for (i, (field, fun)) in fields.iter().zip(field_fns).enumerate() {
+    let sep = if i == 0 { format!("{ctr} {{ ") } else { ", ".to_string() };
+    parts.push(Term::str(&format!("{sep}{}: ", field.nam)));
+    parts.push(apply_field(fun, field, &["a"]));
+  }
+  parts.push(Term::str(" }"));
+  parts.into_iter().rev().reduce(|acc, part| Term::call(Term::r#ref("String/concat"), [part, acc])).unwrap()
+}
+
+/// Combines the index of the constructor with the hashes of the fields.
+fn gen_hash(ctr_idx: usize, fields: &[CtrField], field_fns: Vec<Term>) -> Term {
+  let fields = fields.iter().zip(field_fns);
+  fields.fold(Term::Num { val: Num::U24(ctr_idx as u32) }, |acc, (field, fun)| {
+    oper(Op::ADD, oper(Op::MUL, acc, Term::Num { val: Num::U24(31) }), apply_field(fun, field, &["a"]))
+  })
+}
+
+/// The index of the constructor of the value in `var`.
+fn ctr_index(adt: &Adt, var: &str) -> Term {
+  let arms = adt
+    .ctrs
+    .iter()
+    .enumerate()
+    .map(|(i, (ctr, fields))| {
+      (Some(ctr.clone()), vec![None; fields.len()], Term::Num { val: Num::U24(i as u32) }, None)
+    })
+    .collect();
+  Term::Mat {
+    bnd: Some(Name::new(var)),
+    arg: Box::new(Term::Var { nam: Name::new(var) }),
+    with_bnd: vec![],
+    with_arg: vec![],
+    arms,
+  }
+}
+
+/// `λx λy (op x y)`
+fn num_lam(op: Op) -> Term {
+  let (x, y) = (Name::new("x"), Name::new("y"));
+  let body = oper(op, Term::Var { nam: x.clone() }, Term::Var { nam: y.clone() });
+  Term::rfold_lams(body, [Some(x), Some(y)].into_iter())
+}
+
+fn oper(opr: Op, fst: Term, snd: Term) -> Term {
+  Term::Oper { opr, fst: Box::new(fst), snd: Box::new(snd) }
+}
@@ -1,4 +1,4 @@
-use super::{Book, Definition, FanKind, Name, Num, Op, Pattern, Rule, Tag, Term};
+use super::{Adt, Book, CtrField, Definition, FanKind, Name, Num, Op, Pattern, Rule, Tag, Term, Type};
 use crate::maybe_grow;
 use std::{fmt, ops::Deref, sync::atomic::AtomicU64};
 
@@ -92,6 +92,9 @@ impl fmt::Display for Term {
           for var in &arm.1 {
             write!(f, " {}", var_as_str(var))?;
           }
+          if let Some(guard) = &arm.3 {
+            write!(f, " if {guard}")?;
+          }
           write!(f, ": {}; ", arm.2)?;
         }
         write!(f, "}}")
@@ -140,6 +143,9 @@ impl fmt::Display for Term {
           for var in &arm.1 {
             write!(f, " {}", var_as_str(var))?;
           }
+          if let Some(guard) = &arm.3 {
+            write!(f, " if {guard}")?;
+          }
           write!(f, ": {}; ", arm.2)?;
         }
         write!(f, "}}")
@@ -200,10 +206,12 @@ impl fmt::Display for Pattern {
         write!(f, "({}{})", nam, DisplayJoin(|| pats.iter().map(|p| display!(" {p}")), ""))
       }
       Pattern::Num(num) => write!(f, "{num}"),
+      Pattern::Range(lo, hi) => write!(f, "{lo}..{hi}"),
       Pattern::Fan(FanKind::Tup, tag, pats) => write!(f, "{}({})", tag, DisplayJoin(|| pats, ", ")),
       Pattern::Fan(FanKind::Dup, tag, pats) => write!(f, "{}{{{}}}", tag, DisplayJoin(|| pats, " ")),
       Pattern::Lst(pats) => write!(f, "[{}]", DisplayJoin(|| pats, ", ")),
       Pattern::Str(str) => write!(f, "\"{str}\""),
+      Pattern::Or(pats) => write!(f, "({})", DisplayJoin(|| pats, " | ")),
     }
   }
 }
@@ -228,6 +236,11 @@ impl fmt::Display for Definition {
 
 impl fmt::Display for Book {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    // The constructors are already shown as definitions,
+    // so only the type declarations with type information are shown.
+    for (nam, adt) in self.adts.iter().filter(|(_, adt)| !adt.source.is_builtin() && adt.has_types()) {
+      write!(f, "{}\n\n", adt.display(nam))?;
+    }
     write!(f, "{}", DisplayJoin(|| self.defs.values(), "\n\n"))?;
     for def in self.hvm_defs.values() {
       writeln!(f, "hvm {}:\n{}\n", def.name, def.body.show())?;
@@ -236,6 +249,71 @@ impl fmt::Display for Book {
   }
 }
 
+impl fmt::Display for Type {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    maybe_grow(|| match self {
+      Type::Var(nam) => write!(f, "{nam}"),
+      Type::Ctr(nam, args) if args.is_empty() => write!(f, "{nam}"),
+      Type::Ctr(nam, args) => write!(f, "{nam}({})", DisplayJoin(|| args, ", ")),
+      Type::Arr(arg, ret) if matches!(**arg, Type::Arr(..)) => write!(f, "({arg}) -> {ret}"),
+      Type::Arr(arg, ret) => write!(f, "{arg} -> {ret}"),
+    })
+  }
+}
+
+impl fmt::Display for CtrField {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let rec = if self.rec { "~" } else { "" };
+    match &self.typ {
+      Some(typ) => write!(f, "({rec}{}: {typ})", self.nam),
+      None => write!(f, "{rec}{}", self.nam),
+    }
+  }
+}
+
+impl Adt {
+  /// Displays the datatype using the fun syntax for type declarations,
+  /// or as an `object` if its only constructor has the name of the type.
+  pub fn display<'a>(&'a self, name: &'a Name) -> impl fmt::Display + 'a {
+    let params = DisplayFn(move |f| match self.params.is_empty() {
+      true => Ok(()),
+      false => write!(f, "({})", DisplayJoin(|| &self.params, ", ")),
+    });
+    DisplayFn(move |f| match self.ctrs.get(name) {
+      Some(fields) if self.ctrs.len() == 1 => {
+        let fields = DisplayJoin(
+          || {
+            fields.iter().map(|field| {
+              let rec = if field.rec { "~" } else { "" };
+              DisplayFn(move |f| match &field.typ {
+                Some(typ) => write!(f, "{rec}{}: {typ}", field.nam),
+                None => write!(f, "{rec}{}", field.nam),
+              })
+            })
+          },
+          ", ",
+        );
+        write!(f, "object {name}{params} {{ {fields} }}")
+      }
+      _ => {
+        let ctrs = DisplayJoin(
+          || {
+            self.ctrs.iter().map(|(ctr, fields)| {
+              let ctr = ctr.strip_prefix(&format!("{name}/")).unwrap_or(ctr).to_string();
+              DisplayFn(move |f| match fields.is_empty() {
+                true => write!(f, "{ctr}"),
+                false => write!(f, "({ctr} {})", DisplayJoin(|| fields, " ")),
+              })
+            })
+          },
+          " | ",
+        );
+        write!(f, "type {name}{params} = {ctrs}")
+      }
+    })
+  }
+}
+
 impl fmt::Display for Name {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     self.0.fmt(f)
@@ -424,6 +502,9 @@ impl Term {
             for var in &arm.1 {
               write!(f, " {}", var_as_str(var))?;
             }
+            if let Some(guard) = &arm.3 {
+              write!(f, " if {}", guard.display_pretty(tab + 4))?;
+            }
             write!(f, ": {}; ", arm.2.display_pretty(tab + 4))?;
           }
           write!(f, "\n{:tab$}}}", "")
@@ -472,6 +553,9 @@ impl Term {
             for var in &arm.1 {
               write!(f, " {}", var_as_str(var))?;
             }
+            if let Some(guard) = &arm.3 {
+              write!(f, " if {}", guard.display_pretty(tab + 4))?;
+            }
             write!(f, ": {}; ", arm.2.display_pretty(tab + 4))?;
           }
           write!(f, "\n{:tab$}}}", "")
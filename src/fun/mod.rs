@@ -8,12 +8,14 @@ use interner::global::{GlobalPool, GlobalString};
 use itertools::Itertools;
 use std::{
   borrow::Cow,
+  collections::BTreeMap,
   hash::Hash,
   ops::{Deref, Range},
 };
 
 pub mod builtins;
 pub mod check;
+pub mod derive;
 pub mod display;
 pub mod load_book;
 pub mod net_to_term;
@@ -57,6 +59,10 @@ pub struct Book {
 
   /// Imports declared in the program.
   pub imports: Vec<Import>,
+
+  /// Why some recursive references of each definition couldn't be made lazy.
+  /// Used to explain the recursion cycles found when compiling.
+  pub lazy_recursion_notes: BTreeMap<Name, Vec<String>>,
 }
 
 pub type Definitions = IndexMap<Name, Definition>;
@@ -70,6 +76,20 @@ pub struct Definition {
   pub name: Name,
   pub rules: Vec<Rule>,
   pub source: Source,
+  /// Whether the definition was annotated with `@inline` or `@noinline`.
+  pub inline: Inline,
+}
+
+/// An annotation that forces or forbids inlining a definition in its callers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Inline {
+  /// Inlined if it's small enough.
+  #[default]
+  Auto,
+  /// `@inline`, always inlined.
+  Always,
+  /// `@noinline`, never inlined.
+  Never,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -89,6 +109,9 @@ pub struct HvmDefinition {
   pub name: Name,
   pub body: hvm::ast::Net,
   pub source: Source,
+  /// Whether references to it can be copied, as in [`Book::float_combinators`].
+  /// Only known for the definitions taken from the compilation cache.
+  pub safe: bool,
 }
 
 /// A pattern matching rule of a definition.
@@ -207,7 +230,10 @@ pub enum Term {
   Err,
 }
 
-pub type MatchRule = (Option<Name>, Vec<Option<Name>>, Term);
+/// A `match` arm: the matched constructor (or a var), the field binds, the body and an optional guard.
+///
+/// Guards are removed by `fix_match_terms`, so after that pass they're always `None`.
+pub type MatchRule = (Option<Name>, Vec<Option<Name>>, Term, Option<Term>);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum FanKind {
@@ -256,10 +282,14 @@ pub enum Pattern {
   Chn(Name),
   Ctr(Name, Vec<Pattern>),
   Num(u32),
+  /// An inclusive range of numbers
+  Range(u32, u32),
   /// Either a tuple or a duplication
   Fan(FanKind, Tag, Vec<Pattern>),
   Lst(Vec<Pattern>),
   Str(GlobalString),
+  /// Matches if any of the alternatives matches
+  Or(Vec<Pattern>),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
@@ -274,6 +304,8 @@ pub enum Tag {
 /// A user defined datatype
 #[derive(Debug, Clone)]
 pub struct Adt {
+  /// The type parameters of the datatype, like `T` in `type List(T)`.
+  pub params: Vec<Name>,
   pub ctrs: IndexMap<Name, Vec<CtrField>>,
   pub source: Source,
 }
@@ -282,6 +314,21 @@ pub struct Adt {
 pub struct CtrField {
   pub nam: Name,
   pub rec: bool,
+  /// The optional type annotation of the field.
+  pub typ: Option<Type>,
+}
+
+/// A type expression, used to annotate the fields of datatype constructors.
+///
+/// Types are not checked, they're only kept for documentation and tooling.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Type {
+  /// A type parameter of the datatype being defined.
+  Var(Name),
+  /// A type constructor applied to its arguments, like `List(T)` or `u24`.
+  Ctr(Name, Vec<Type>),
+  /// A function type `A -> B`.
+  Arr(Box<Type>, Box<Type>),
 }
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Name(GlobalString);
@@ -542,18 +589,24 @@ impl Term {
   pub fn children(&self) -> impl DoubleEndedIterator<Item = &Term> + Clone {
     multi_iterator!(ChildrenIter { Zero, One, Two, Vec, Mat, Swt, Bend, Fold });
     match self {
-      Term::Mat { arg, bnd: _, with_bnd: _, with_arg, arms } => {
-        ChildrenIter::Mat([arg.as_ref()].into_iter().chain(with_arg.iter()).chain(arms.iter().map(|r| &r.2)))
-      }
+      Term::Mat { arg, bnd: _, with_bnd: _, with_arg, arms } => ChildrenIter::Mat(
+        [arg.as_ref()]
+          .into_iter()
+          .chain(with_arg.iter())
+          .chain(arms.iter().flat_map(|r| r.3.iter().chain([&r.2]))),
+      ),
       Term::Swt { arg, bnd: _, with_bnd: _, with_arg, pred: _, arms } => {
         ChildrenIter::Swt([arg.as_ref()].into_iter().chain(with_arg.iter()).chain(arms))
       }
       Term::Bend { bnd: _, arg: init, cond, step, base } => {
         ChildrenIter::Bend(init.iter().chain([cond.as_ref(), step.as_ref(), base.as_ref()]))
       }
-      Term::Fold { bnd: _, arg, with_bnd: _, with_arg, arms } => {
-        ChildrenIter::Fold([arg.as_ref()].into_iter().chain(with_arg.iter()).chain(arms.iter().map(|r| &r.2)))
-      }
+      Term::Fold { bnd: _, arg, with_bnd: _, with_arg, arms } => ChildrenIter::Fold(
+        [arg.as_ref()]
+          .into_iter()
+          .chain(with_arg.iter())
+          .chain(arms.iter().flat_map(|r| r.3.iter().chain([&r.2]))),
+      ),
       Term::Fan { els, .. } | Term::List { els } => ChildrenIter::Vec(els),
       Term::Let { val: fst, nxt: snd, .. }
       | Term::Ask { val: fst, nxt: snd, .. }
@@ -579,7 +632,10 @@ impl Term {
     multi_iterator!(ChildrenIter { Zero, One, Two, Vec, Mat, Swt, Bend, Fold });
     match self {
       Term::Mat { arg, bnd: _, with_bnd: _, with_arg, arms } => ChildrenIter::Mat(
-        [arg.as_mut()].into_iter().chain(with_arg.iter_mut()).chain(arms.iter_mut().map(|r| &mut r.2)),
+        [arg.as_mut()]
+          .into_iter()
+          .chain(with_arg.iter_mut())
+          .chain(arms.iter_mut().flat_map(|(_, _, bod, guard)| guard.iter_mut().chain([bod]))),
       ),
       Term::Swt { arg, bnd: _, with_bnd: _, with_arg, pred: _, arms } => {
         ChildrenIter::Swt([arg.as_mut()].into_iter().chain(with_arg.iter_mut()).chain(arms))
@@ -588,7 +644,10 @@ impl Term {
         ChildrenIter::Bend(init.iter_mut().chain([cond.as_mut(), step.as_mut(), base.as_mut()]))
       }
       Term::Fold { bnd: _, arg, with_bnd: _, with_arg, arms } => ChildrenIter::Fold(
-        [arg.as_mut()].into_iter().chain(with_arg.iter_mut()).chain(arms.iter_mut().map(|r| &mut r.2)),
+        [arg.as_mut()]
+          .into_iter()
+          .chain(with_arg.iter_mut())
+          .chain(arms.iter_mut().flat_map(|(_, _, bod, guard)| guard.iter_mut().chain([bod]))),
       ),
       Term::Fan { els, .. } | Term::List { els } => ChildrenIter::Vec(els),
       Term::Let { val: fst, nxt: snd, .. }
@@ -630,9 +689,16 @@ impl Term {
       | Term::Fold { bnd, arg, with_bnd, with_arg, arms } => {
         let arg = [(arg.as_ref(), BindsIter::Zero([]))].into_iter();
         let with_arg = with_arg.iter().map(|a| (a, BindsIter::Zero([])));
-        let arms = arms
-          .iter()
-          .map(move |r| (&r.2, BindsIter::Mat([bnd].into_iter().chain(r.1.iter()).chain(with_bnd.iter()))));
+        let arms = arms.iter().flat_map(move |(_, fields, bod, guard)| {
+          let binds = BindsIter::Mat([bnd].into_iter().chain(fields.iter()).chain(with_bnd.iter()));
+          guard
+            .iter()
+            .map({
+              let binds = binds.clone();
+              move |guard| (guard, binds.clone())
+            })
+            .chain([(bod, binds)])
+        });
         ChildrenIter::Mat(arg.chain(with_arg).chain(arms))
       }
       Term::Swt { arg, bnd, with_bnd, with_arg, pred, arms } => {
@@ -692,9 +758,13 @@ impl Term {
       | Term::Fold { bnd, arg, with_bnd, with_arg, arms } => {
         let arg = [(arg.as_mut(), BindsIter::Zero([]))].into_iter();
         let with_arg = with_arg.iter_mut().map(|a| (a, BindsIter::Zero([])));
-        let arms = arms
-          .iter_mut()
-          .map(|r| (&mut r.2, BindsIter::Mat([&*bnd].into_iter().chain(r.1.iter()).chain(with_bnd.iter()))));
+        let bnd = &*bnd;
+        let with_bnd = &*with_bnd;
+        let arms = arms.iter_mut().flat_map(move |(_, fields, bod, guard)| {
+          let fields = &*fields;
+          let binds = move || BindsIter::Mat([bnd].into_iter().chain(fields.iter()).chain(with_bnd.iter()));
+          guard.iter_mut().map(move |guard| (guard, binds())).chain([(bod, binds())])
+        });
         ChildrenIter::Mat(arg.chain(with_arg).chain(arms))
       }
       Term::Swt { arg, bnd, with_bnd, with_arg, pred, arms } => {
@@ -781,7 +851,7 @@ impl Term {
 
     match self {
       Term::Fold { arms, .. } | Term::Mat { arms, .. } => {
-        for (arm, _, _) in arms {
+        for (arm, _, _, _) in arms {
           if let Some(nam) = arm {
             if nam == from {
               *nam = to.clone();
@@ -876,6 +946,12 @@ impl Term {
     (decls, uses)
   }
 
+  /// Checks if the term declares or uses any unscoped variable.
+  pub fn has_unscoped_vars(&self) -> bool {
+    let (decls, uses) = self.unscoped_vars();
+    !decls.is_empty() || !uses.is_empty()
+  }
+
   pub fn has_unscoped(&self) -> bool {
     maybe_grow(|| {
       let mut has_unscoped = match self {
@@ -961,16 +1037,24 @@ impl Pattern {
   pub fn children(&self) -> impl DoubleEndedIterator<Item = &Pattern> + Clone {
     multi_iterator!(ChildrenIter { Zero, Vec });
     match self {
-      Pattern::Ctr(_, els) | Pattern::Fan(.., els) | Pattern::Lst(els) => ChildrenIter::Vec(els.iter()),
-      Pattern::Var(_) | Pattern::Chn(_) | Pattern::Num(_) | Pattern::Str(_) => ChildrenIter::Zero([]),
+      Pattern::Ctr(_, els) | Pattern::Fan(.., els) | Pattern::Lst(els) | Pattern::Or(els) => {
+        ChildrenIter::Vec(els.iter())
+      }
+      Pattern::Var(_) | Pattern::Chn(_) | Pattern::Num(_) | Pattern::Range(..) | Pattern::Str(_) => {
+        ChildrenIter::Zero([])
+      }
     }
   }
 
   pub fn children_mut(&mut self) -> impl DoubleEndedIterator<Item = &mut Pattern> {
     multi_iterator!(ChildrenIter { Zero, Vec });
     match self {
-      Pattern::Ctr(_, els) | Pattern::Fan(.., els) | Pattern::Lst(els) => ChildrenIter::Vec(els.iter_mut()),
-      Pattern::Var(_) | Pattern::Chn(_) | Pattern::Num(_) | Pattern::Str(_) => ChildrenIter::Zero([]),
+      Pattern::Ctr(_, els) | Pattern::Fan(.., els) | Pattern::Lst(els) | Pattern::Or(els) => {
+        ChildrenIter::Vec(els.iter_mut())
+      }
+      Pattern::Var(_) | Pattern::Chn(_) | Pattern::Num(_) | Pattern::Range(..) | Pattern::Str(_) => {
+        ChildrenIter::Zero([])
+      }
     }
   }
 
@@ -1001,15 +1085,17 @@ impl Pattern {
       Pattern::Fan(fan, tag, args) => {
         Term::Fan { fan: *fan, tag: tag.clone(), els: args.iter().map(|p| p.to_term()).collect() }
       }
-      Pattern::Lst(_) | Pattern::Str(_) => todo!(),
+      Pattern::Range(..) | Pattern::Lst(_) | Pattern::Str(_) | Pattern::Or(_) => todo!(),
     }
   }
 
   pub fn has_unscoped(&self) -> bool {
     match self {
       Pattern::Chn(_) => true,
-      Pattern::Var(_) | Pattern::Str(_) | Pattern::Num(_) => false,
-      Pattern::Ctr(_, x) | Pattern::Fan(_, _, x) | Pattern::Lst(x) => x.iter().any(|x| x.has_unscoped()),
+      Pattern::Var(_) | Pattern::Str(_) | Pattern::Num(_) | Pattern::Range(..) => false,
+      Pattern::Ctr(_, x) | Pattern::Fan(_, _, x) | Pattern::Lst(x) | Pattern::Or(x) => {
+        x.iter().any(|x| x.has_unscoped())
+      }
     }
   }
 }
@@ -1022,12 +1108,12 @@ impl Rule {
 
 impl Definition {
   pub fn new(name: Name, rules: Vec<Rule>, source: Source) -> Self {
-    Self { name, rules, source }
+    Self { name, rules, source, inline: Inline::Auto }
   }
 
   pub fn new_gen(name: Name, rules: Vec<Rule>, builtin: bool) -> Self {
     let source = if builtin { Source::Builtin } else { Source::Generated };
-    Self { name, rules, source }
+    Self { name, rules, source, inline: Inline::Auto }
   }
 
   pub fn is_builtin(&self) -> bool {
@@ -1119,6 +1205,13 @@ impl Book {
   }
 }
 
+impl Adt {
+  /// Whether the datatype has type parameters or fields with type annotations.
+  pub fn has_types(&self) -> bool {
+    !self.params.is_empty() || self.ctrs.values().flatten().any(|field| field.typ.is_some())
+  }
+}
+
 impl Source {
   pub fn is_builtin(&self) -> bool {
     matches!(self, Source::Builtin)
@@ -2,10 +2,10 @@ use std::ops::Range;
 
 use crate::{
   fun::{
-    display::DisplayFn, Adt, Adts, Constructors, CtrField, FanKind, HvmDefinition, HvmDefinitions, MatchRule,
-    Name, Num, Op, Pattern, Rule, Source, Tag, Term, STRINGS,
+    derive::Derive, display::DisplayFn, Adt, Adts, Constructors, CtrField, FanKind, HvmDefinition,
+    HvmDefinitions, Inline, MatchRule, Name, Num, Op, Pattern, Rule, Source, Tag, Term, Type, STRINGS,
   },
-  imp::{parser::PyParser, Enum, RepeatedNames, Variant},
+  imp::{parser::PyParser, Enum, Expr, RepeatedNames, Stmt},
   imports::{Import, ImportCtx, ImportType},
   maybe_grow,
 };
@@ -58,10 +58,14 @@ impl ParseBook {
 }
 
 // Bend grammar description:
-// <Book>       ::= (<Data> | <Rule>)*
-// <ADT>        ::= "type" <Name> "=" ( <Name> | "(" <Name> (<Name>)* ")" )+
+// <Book>       ::= (<Data> | <Annotation>? <Rule>)*
+// <Annotation> ::= "@" ("inline" | "noinline")
+// <ADT>        ::= "type" <Name> <TypeParams>? "=" ( <Name> | "(" <Name> (<Field>)* ")" )+
+// <TypeParams> ::= "(" <Name> ("," <Name>)* ")"
+// <Field>      ::= "~"? <Name> | "(" "~"? <Name> ":" <Type> ")"
+// <Type>       ::= ( "(" <Type> ")" | <Name> ("(" <Type> ("," <Type>)* ")")? ) ("->" <Type>)?
 // <Rule>       ::= ("(" <Name> <Pattern>* ")" | <Name> <Pattern>*) "=" <Term>
-// <Pattern>    ::= "(" <Name> <Pattern>* ")" | <NameEra> | <Number> | "(" <Pattern> ("," <Pattern>)+ ")"
+// <Pattern>    ::= "(" <Name> <Pattern>* ")" | <NameEra> | <Number> | "(" <Pattern> ("," <Pattern>)+ ")" | "(" <Pattern> ("|" <Pattern>)+ ")"
 // <Term>       ::=
 //   <Number> | <NumOp> | <Tup> | <App> | <Group> | <Nat> | <Lam> | <UnscopedLam> | <Bend> | <Fold> |
 //   <Use> | <Dup> | <LetTup> | <Let> | <With> | <Match> | <Switch> | <Era> | <UnscopedVar> | <Var>
@@ -84,9 +88,10 @@ impl ParseBook {
 // <Fold>       ::= "fold" <MatchArg> <WithClause>? "{" <MatchArm>+ "}"
 // <MatchArg>   ::= (<Name> "=" <Term>) | <Term>
 // <WithClause> ::= "with" (<Name> ("=" <Term>)? ","?)+
-// <MatchArm>   ::= "|"? <Pattern> ":" <Term> ";"?
+// <MatchArm>   ::= "|"? <NameEra> ("if" <Term>)? ":" <Term> ";"?
 // <Switch>     ::= "switch" <MatchArg> <WithClause>? "{" <SwitchArm>+ "}"
-// <SwitchArm>  ::= "|"? (<Num>|"_") ":" <Term> ";"?
+// <SwitchArm>  ::= "|"? (<NumPat> ("|" <NumPat>)* | "_") ":" <Term> ";"?
+// <NumPat>     ::= (<Num> | <Char>) (".." (<Num> | <Char>))?
 // <Bend>       ::= "bend" (<MatchArg> ","?)+ "{" "when" <Term> ":" <Term> "else" ":" <Term> "}"
 // <Var>        ::= <Name>
 // <UnscopedVar>::= "$" <Name>
@@ -115,16 +120,32 @@ impl<'a> TermParser<'a> {
     let mut book = default_book;
     let mut indent = self.advance_newlines()?;
     let mut last_rule = None;
+    let mut inline = Inline::Auto;
     while !self.is_eof() {
       let ini_idx = *self.index();
 
+      // Inlining annotation of the next function definition
+      if self.starts_with("@") {
+        inline = self.parse_inline_annotation()?;
+        indent = self.advance_newlines()?;
+        let is_def =
+          ["object", "type", "hvm", "from", "import"].iter().all(|kw| !self.starts_with_keyword(kw));
+        if self.is_eof() || self.starts_with("@") || !is_def {
+          let idx = *self.index();
+          return self.expected_spanned("function definition after the annotation", idx..idx + 1);
+        }
+        continue;
+      }
+
       // Record type definition
       if self.try_parse_keyword("object") {
         let mut prs = PyParser { input: self.input, index: *self.index() };
         let (obj, nxt_indent) = prs.parse_object(indent)?;
         self.index = prs.index;
         let end_idx = *self.index();
+        let (nam, deriving) = (obj.name.clone(), obj.deriving.clone());
         self.add_object(obj, &mut book, ini_idx..end_idx, builtin)?;
+        self.add_derived_defs(&nam, &deriving, &mut book, ini_idx..end_idx, builtin)?;
         indent = nxt_indent;
         last_rule = None;
         continue;
@@ -133,9 +154,10 @@ impl<'a> TermParser<'a> {
       // Imp function definition
       if self.try_parse_keyword("def") {
         let mut prs = PyParser { input: self.input, index: *self.index() };
-        let (def, nxt_indent) = prs.parse_def(indent)?;
+        let (mut def, nxt_indent) = prs.parse_def(indent)?;
         self.index = prs.index;
         let end_idx = *self.index();
+        def.inline = std::mem::take(&mut inline);
         self.add_imp_def(def, &mut book, ini_idx..end_idx, builtin)?;
         indent = nxt_indent;
         last_rule = None;
@@ -148,6 +170,10 @@ impl<'a> TermParser<'a> {
         let rewind_index = self.index;
 
         let _ = self.labelled(|p| p.parse_top_level_name(), "datatype name")?;
+        let _ = self.parse_type_params()?;
+        self.skip_trivia();
+        let _ = self.parse_deriving()?;
+        self.skip_trivia();
 
         // Imp type definition
         if self.starts_with(":") {
@@ -155,18 +181,19 @@ impl<'a> TermParser<'a> {
           let (r#enum, nxt_indent) = prs.parse_type(indent)?;
           self.index = prs.index;
           let end_idx = *self.index();
+          let (nam, deriving) = (r#enum.name.clone(), r#enum.deriving.clone());
           self.add_imp_type(r#enum, &mut book, ini_idx..end_idx, builtin)?;
+          self.add_derived_defs(&nam, &deriving, &mut book, ini_idx..end_idx, builtin)?;
           indent = nxt_indent;
           last_rule = None;
           continue;
         // Fun type definition
         } else {
           self.index = rewind_index;
-          let (nam, ctrs) = self.parse_datatype()?;
+          let (nam, adt, deriving) = self.parse_datatype(ini_idx, builtin)?;
           let end_idx = *self.index();
-          let source = if builtin { Source::Builtin } else { Source::Local(ini_idx..end_idx) };
-          let adt = Adt { ctrs, source };
-          self.add_fun_type(&mut book, nam, adt, ini_idx..end_idx)?;
+          self.add_fun_type(&mut book, nam.clone(), adt, ini_idx..end_idx)?;
+          self.add_derived_defs(&nam, &deriving, &mut book, ini_idx..end_idx, builtin)?;
           indent = self.advance_newlines()?;
           last_rule = None;
           continue;
@@ -215,6 +242,9 @@ impl<'a> TermParser<'a> {
       }
 
       self.add_fun_def(&name, rule, builtin, &last_rule, &mut book, ini_idx..end_idx)?;
+      if inline != Inline::Auto {
+        book.fun_defs[&name].inline = std::mem::take(&mut inline);
+      }
       indent = self.advance_newlines()?;
       last_rule = Some(name);
     }
@@ -222,35 +252,50 @@ impl<'a> TermParser<'a> {
     Ok(book)
   }
 
-  fn parse_datatype(&mut self) -> ParseResult<(Name, IndexMap<Name, Vec<CtrField>>)> {
-    // type name = ctr (| ctr)*
+  fn parse_datatype(&mut self, ini_idx: usize, builtin: bool) -> ParseResult<(Name, Adt, Vec<Derive>)> {
+    // type name ("(" param ("," param)* ")")? ("deriving" "(" derive ("," derive)* ")")? = ctr (| ctr)*
     self.skip_trivia();
     let name = self.labelled(|p| p.parse_top_level_name(), "datatype name")?;
+    let params = self.parse_type_params()?;
+    self.skip_trivia();
+    let deriving = self.parse_deriving()?;
     self.consume("=")?;
-    let mut ctrs = vec![self.parse_datatype_ctr(&name)?];
+    let mut ctrs = vec![self.parse_datatype_ctr(&name, &params)?];
     while self.try_consume("|") {
-      ctrs.push(self.parse_datatype_ctr(&name)?);
+      ctrs.push(self.parse_datatype_ctr(&name, &params)?);
     }
     let ctrs = ctrs.into_iter().collect();
-    Ok((name, ctrs))
+    let end_idx = *self.index();
+    let source = if builtin { Source::Builtin } else { Source::Local(ini_idx..end_idx) };
+    Ok((name, Adt { params, ctrs, source }, deriving))
   }
 
-  fn parse_datatype_ctr(&mut self, typ_name: &Name) -> ParseResult<(Name, Vec<CtrField>)> {
-    // (name  ('~'? field)*)
+  fn parse_datatype_ctr(&mut self, typ_name: &Name, params: &[Name]) -> ParseResult<(Name, Vec<CtrField>)> {
+    // (name  ('~'? field | "(" '~'? field ":" type ")")*)
     // name
     if self.try_consume("(") {
       self.skip_trivia();
       let ctr_name = self.parse_top_level_name()?;
       let ctr_name = Name::new(format!("{typ_name}/{ctr_name}"));
 
-      fn parse_field(p: &mut TermParser) -> ParseResult<CtrField> {
+      fn parse_field(p: &mut TermParser, params: &[Name]) -> ParseResult<CtrField> {
+        // Annotated field
+        if p.try_consume_exactly("(") {
+          let rec = p.try_consume("~");
+          p.skip_trivia();
+          let nam = p.labelled(|p| p.parse_bend_name(), "datatype constructor field")?;
+          p.consume(":")?;
+          let typ = p.parse_type_expr(params)?;
+          p.consume(")")?;
+          return Ok(CtrField { nam, rec, typ: Some(typ) });
+        }
         let rec = p.try_consume("~");
         p.skip_trivia();
         let nam = p.labelled(|p| p.parse_bend_name(), "datatype constructor field")?;
-        Ok(CtrField { nam, rec })
+        Ok(CtrField { nam, rec, typ: None })
       }
 
-      let fields = self.list_like(parse_field, "", ")", "", false, 0)?;
+      let fields = self.list_like(|p| parse_field(p, params), "", ")", "", false, 0)?;
       if let Some(field) = fields.find_repeated_names().into_iter().next() {
         return Err(format!("Found a repeated field '{field}' in constructor {ctr_name}."));
       }
@@ -263,6 +308,21 @@ impl<'a> TermParser<'a> {
     }
   }
 
+  fn parse_inline_annotation(&mut self) -> ParseResult<Inline> {
+    // "@" ("inline" | "noinline")
+    let ini_idx = *self.index();
+    self.consume_exactly("@")?;
+    let name = self.parse_bend_name()?;
+    match name.as_ref() {
+      "inline" => Ok(Inline::Always),
+      "noinline" => Ok(Inline::Never),
+      _ => {
+        let end_idx = *self.index();
+        self.expected_spanned("'@inline' or '@noinline'", ini_idx..end_idx)
+      }
+    }
+  }
+
   fn parse_hvm(&mut self, builtin: bool) -> ParseResult<HvmDefinition> {
     self.skip_trivia_inline()?;
     let name = self.parse_bend_name()?;
@@ -276,7 +336,7 @@ impl<'a> TermParser<'a> {
     *self.index() = ini_idx + *p.index();
     let end_idx = *self.index();
     let source = if builtin { Source::Builtin } else { Source::Local(ini_idx..end_idx) };
-    let def = HvmDefinition { name: name.clone(), body, source };
+    let def = HvmDefinition { name: name.clone(), body, source, safe: false };
     Ok(def)
   }
 
@@ -376,6 +436,18 @@ impl<'a> TermParser<'a> {
           return Ok(Pattern::Fan(FanKind::Tup, tag.unwrap_or(Tag::Static), els));
         }
 
+        // Or
+        if self.starts_with("|") && !simple {
+          unexpected_tag(self)?;
+          let mut alts = vec![head];
+          while self.try_consume_exactly("|") {
+            alts.push(self.parse_pattern(simple)?);
+            self.skip_trivia();
+          }
+          self.consume(")")?;
+          return Ok(Pattern::Or(alts));
+        }
+
         // Ctr
         unexpected_tag(self)?;
         let Pattern::Var(Some(name)) = head else {
@@ -405,18 +477,10 @@ impl<'a> TermParser<'a> {
         return Ok(Pattern::Str(STRINGS.get(str)));
       }
 
-      // Char
-      if self.starts_with("'") {
-        unexpected_tag(self)?;
-        let char = self.parse_quoted_char()?;
-        return Ok(Pattern::Num(char as u32));
-      }
-
-      // Number
-      if self.peek_one().map_or(false, |c| c.is_ascii_digit()) {
+      // Number, Char or Range
+      if self.starts_with("'") || self.peek_one().is_some_and(|c| c.is_ascii_digit()) {
         unexpected_tag(self)?;
-        let num = self.parse_u32()?;
-        return Ok(Pattern::Num(num));
+        return self.parse_num_pattern();
       }
 
       // Channel
@@ -716,39 +780,39 @@ impl<'a> TermParser<'a> {
       }
 
       // Switch
+      let ini_idx = *self.index();
       if self.try_parse_keyword("switch") {
         unexpected_tag(self)?;
         let (bnd, arg) = self.parse_match_arg()?;
         let (with_bnd, with_arg) = self.parse_with_clause()?;
 
-        self.try_consume("|");
-        self.consume("0")?;
-        self.consume(":")?;
-        let zero = self.parse_term()?;
-        self.try_consume(";");
-
-        let mut arms = vec![zero];
-        let mut expected_num = 1;
+        let mut arms = vec![];
         loop {
           self.try_consume("|");
           // case _
           if self.try_consume("_") {
             self.consume(":")?;
-            arms.push(self.parse_term()?);
+            arms.push((Pattern::Var(None), self.parse_term()?));
             self.try_consume(";");
             self.consume("}")?;
             break;
           }
-          // case num
-          let val = self.parse_u32()?;
-          if val != expected_num {
-            return self.expected(&format!("'{}'", &expected_num.to_string()));
-          }
-          expected_num += 1;
+          // case num, char or range
+          self.skip_trivia();
+          let pat = self.parse_switch_pattern()?;
           self.consume(":")?;
-          arms.push(self.parse_term()?);
+          arms.push((pat, self.parse_term()?));
           self.try_consume(";");
         }
+
+        // Switches that don't have consecutive cases starting from 0 are compiled like pattern matching rules.
+        if !is_native_switch(arms.iter().map(|(pat, _)| pat)) {
+          let arms = arms.into_iter().map(|(pat, bod)| (pat, None, bod)).collect::<Vec<_>>();
+          let end_idx = *self.index();
+          self.with_ctx(Term::check_switch_pred(&bnd, &arms), ini_idx..end_idx)?;
+          return Ok(Term::pattern_match(arg, bnd, with_bnd, with_arg, arms));
+        }
+        let arms = arms.into_iter().map(|(_, bod)| bod).collect::<Vec<_>>();
         let pred = Some(Name::new(format!("{}-{}", bnd.as_ref().unwrap(), arms.len() - 1)));
         return Ok(Term::Swt { arg: Box::new(arg), bnd, with_bnd, with_arg, pred, arms });
       }
@@ -914,9 +978,11 @@ impl<'a> TermParser<'a> {
     self.try_consume("|");
     self.skip_trivia();
     let nam = self.parse_name_or_era()?;
+    self.skip_trivia();
+    let guard = if self.try_parse_keyword("if") { Some(self.parse_term()?) } else { None };
     self.consume(":")?;
     let bod = self.parse_term()?;
-    Ok((nam, vec![], bod))
+    Ok((nam, vec![], bod, guard))
   }
 
   fn add_fun_def(
@@ -964,8 +1030,31 @@ impl<'a> TermParser<'a> {
     builtin: bool,
   ) -> ParseResult<()> {
     self.check_top_level_redefinition(&def.name, book, span.clone())?;
-    let source = if builtin { Source::Builtin } else { Source::Local(span) };
-    def.source = source;
+    let source = if builtin { Source::Builtin } else { Source::Local(span.clone()) };
+    def.source = source.clone();
+
+    // Default values are generated by their own definitions, so that they
+    // can be referenced from the calls in any other module.
+    for (param, default) in def.defaults.iter_mut() {
+      if matches!(default, Expr::Num { .. }) {
+        continue;
+      }
+      let name = Name::new(format!("{}__default_{param}", def.name));
+      self.check_top_level_redefinition(&name, book, span.clone())?;
+      let val = std::mem::replace(default, Expr::Var { nam: name.clone() });
+      let body = Stmt::Return { term: Box::new(val) };
+      let default_def = ImpDefinition {
+        name: name.clone(),
+        params: vec![],
+        defaults: IndexMap::new(),
+        kw_only: 0,
+        body,
+        source: source.clone(),
+        inline: Inline::Auto,
+      };
+      book.imp_defs.insert(name, default_def);
+    }
+
     book.imp_defs.insert(def.name.clone(), def);
     Ok(())
   }
@@ -985,7 +1074,7 @@ impl<'a> TermParser<'a> {
   ) -> ParseResult<()> {
     self.check_type_redefinition(&enum_.name, book, span.clone())?;
     let source = if builtin { Source::Builtin } else { Source::Local(span.clone()) };
-    let mut adt = Adt { ctrs: Default::default(), source };
+    let mut adt = Adt { params: enum_.params, ctrs: Default::default(), source };
     for variant in enum_.variants {
       self.check_top_level_redefinition(&enum_.name, book, span.clone())?;
       book.ctrs.insert(variant.name.clone(), enum_.name.clone());
@@ -1025,7 +1114,7 @@ impl<'a> TermParser<'a> {
 
   fn add_object(
     &mut self,
-    obj: Variant,
+    obj: Enum,
     book: &mut ParseBook,
     span: Range<usize>,
     builtin: bool,
@@ -1033,13 +1122,33 @@ impl<'a> TermParser<'a> {
     self.check_type_redefinition(&obj.name, book, span.clone())?;
     self.check_top_level_redefinition(&obj.name, book, span.clone())?;
     let source = if builtin { Source::Builtin } else { Source::Local(span) };
-    let mut adt = Adt { ctrs: Default::default(), source };
-    book.ctrs.insert(obj.name.clone(), obj.name.clone());
-    adt.ctrs.insert(obj.name.clone(), obj.fields);
+    let mut adt = Adt { params: obj.params, ctrs: Default::default(), source };
+    for variant in obj.variants {
+      book.ctrs.insert(variant.name.clone(), obj.name.clone());
+      adt.ctrs.insert(variant.name, variant.fields);
+    }
     book.adts.insert(obj.name, adt);
     Ok(())
   }
 
+  /// Adds the functions generated by the `deriving` clause of a datatype.
+  fn add_derived_defs(
+    &mut self,
+    typ: &Name,
+    deriving: &[Derive],
+    book: &mut ParseBook,
+    span: Range<usize>,
+    builtin: bool,
+  ) -> ParseResult<()> {
+    for derive in deriving {
+      let def = derive.gen_def(typ, &book.adts[typ], builtin);
+      let def = self.with_ctx(def, span.clone())?;
+      self.check_top_level_redefinition(&def.name, book, span.clone())?;
+      book.fun_defs.insert(def.name.clone(), def);
+    }
+    Ok(())
+  }
+
   fn check_top_level_redefinition(
     &mut self,
     name: &Name,
@@ -1153,6 +1262,14 @@ pub fn is_num_char(c: char) -> bool {
   "0123456789+-".contains(c)
 }
 
+/// Whether the cases of a switch are the consecutive numbers starting from 0 followed by a default case,
+/// which can be compiled directly to a native switch.
+pub fn is_native_switch<'a>(pats: impl IntoIterator<Item = &'a Pattern>) -> bool {
+  let pats = pats.into_iter().collect::<Vec<_>>();
+  let Some((Pattern::Var(None), nums)) = pats.split_last() else { return false };
+  !nums.is_empty() && nums.iter().enumerate().all(|(i, pat)| **pat == Pattern::Num(i as u32))
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Indent {
   Val(isize),
@@ -1226,6 +1343,81 @@ pub trait ParserCommons<'a>: Parser<'a> {
     }
   }
 
+  /// Parses the optional list of type parameters of a datatype, like `(A, B)` in `type Pair(A, B)`.
+  fn parse_type_params(&mut self) -> ParseResult<Vec<Name>> {
+    if !self.starts_with("(") {
+      return Ok(vec![]);
+    }
+    let ini_idx = *self.index();
+    let params = self.list_like(|p| p.parse_bend_name(), "(", ")", ",", true, 0)?;
+    let end_idx = *self.index();
+    if let Some(param) = params.iter().duplicates().next() {
+      let msg = format!("Found a repeated type parameter '{param}'.");
+      return self.with_ctx(Err(msg), ini_idx..end_idx);
+    }
+    Ok(params)
+  }
+
+  /// Parses the optional `deriving` clause of a datatype, like `deriving (equal, show)`.
+  fn parse_deriving(&mut self) -> ParseResult<Vec<Derive>> {
+    if !self.try_parse_keyword("deriving") {
+      return Ok(vec![]);
+    }
+    self.skip_trivia_inline()?;
+    let ini_idx = *self.index();
+    let names = self.list_like(|p| p.parse_bend_name(), "(", ")", ",", true, 1)?;
+    let end_idx = *self.index();
+    if let Some(name) = names.iter().duplicates().next() {
+      let msg = format!("Found a repeated derived function '{name}'.");
+      return self.with_ctx(Err(msg), ini_idx..end_idx);
+    }
+    let mut deriving = vec![];
+    for name in names {
+      let Some(derive) = Derive::from_name(&name) else {
+        let msg = format!(
+          "Can't derive '{name}'. The derivable functions are 'equal', 'compare', 'show' and 'hash'."
+        );
+        return self.with_ctx(Err(msg), ini_idx..end_idx);
+      };
+      deriving.push(derive);
+    }
+    Ok(deriving)
+  }
+
+  /// Parses a type annotation of a constructor field.
+  ///
+  /// <Type> ::= <TypeAtom> ("->" <Type>)?
+  /// <TypeAtom> ::= "(" <Type> ")" | <Name> ("(" <Type> ("," <Type>)* ")")?
+  ///
+  /// Names that are one of the given type parameters are parsed as type variables.
+  fn parse_type_expr(&mut self, params: &[Name]) -> ParseResult<Type> {
+    maybe_grow(|| {
+      self.skip_trivia();
+      let typ = if self.try_consume_exactly("(") {
+        let typ = self.parse_type_expr(params)?;
+        self.consume(")")?;
+        typ
+      } else {
+        let name = self.labelled(|p| p.parse_top_level_name(), "type")?;
+        if self.starts_with("(") {
+          let args = self.list_like(|p| p.parse_type_expr(params), "(", ")", ",", true, 1)?;
+          Type::Ctr(name, args)
+        } else if params.contains(&name) {
+          Type::Var(name)
+        } else {
+          Type::Ctr(name, vec![])
+        }
+      };
+      self.skip_trivia();
+      if self.try_consume_exactly("->") {
+        let ret = self.parse_type_expr(params)?;
+        Ok(Type::Arr(Box::new(typ), Box::new(ret)))
+      } else {
+        Ok(typ)
+      }
+    })
+  }
+
   fn parse_import_name(&mut self, label: &str) -> Result<(Name, Option<Name>, bool), String> {
     let (import, alias) = self.parse_name_maybe_alias(label)?;
     let relative = import.starts_with("./") | import.starts_with("../");
@@ -1359,13 +1551,16 @@ pub trait ParserCommons<'a>: Parser<'a> {
     }
   }
 
-  fn try_parse_keyword(&mut self, keyword: &str) -> bool {
+  fn starts_with_keyword(&mut self, keyword: &str) -> bool {
     if !self.starts_with(keyword) {
       return false;
     }
     let input = &self.input()[*self.index() + keyword.len()..];
-    let next_is_name = input.chars().next().map_or(false, is_name_char);
-    if !next_is_name {
+    !input.chars().next().map_or(false, is_name_char)
+  }
+
+  fn try_parse_keyword(&mut self, keyword: &str) -> bool {
+    if self.starts_with_keyword(keyword) {
       self.consume_exactly(keyword).unwrap();
       true
     } else {
@@ -1537,6 +1732,52 @@ pub trait ParserCommons<'a>: Parser<'a> {
     }
   }
 
+  /// Parses a number or a character, optionally followed by ".." and the inclusive end of a range.
+  /// Ranges whose end is smaller than their start would never match, so they are rejected.
+  fn parse_num_pattern(&mut self) -> ParseResult<Pattern> {
+    let ini_idx = *self.index();
+    let lo = self.parse_num_pattern_bound()?;
+    if self.try_consume_exactly("..") {
+      let hi = self.parse_num_pattern_bound()?;
+      if lo > hi {
+        let end_idx = *self.index();
+        let msg = format!("Range '{lo}..{hi}' is empty, its start must not be greater than its end.");
+        return self.with_ctx(Err(msg), ini_idx..end_idx);
+      }
+      Ok(Pattern::Range(lo, hi))
+    } else {
+      Ok(Pattern::Num(lo))
+    }
+  }
+
+  /// Parses the pattern of a switch case, which can have alternatives separated by "|".
+  fn parse_switch_pattern(&mut self) -> ParseResult<Pattern> {
+    let mut alts = vec![];
+    loop {
+      self.skip_trivia();
+      if !(self.starts_with("'") || self.peek_one().is_some_and(|c| c.is_ascii_digit())) {
+        return self.expected("number, range or '_'");
+      }
+      alts.push(self.parse_num_pattern()?);
+      if !self.try_consume("|") {
+        break;
+      }
+    }
+    if alts.len() == 1 {
+      Ok(alts.pop().unwrap())
+    } else {
+      Ok(Pattern::Or(alts))
+    }
+  }
+
+  fn parse_num_pattern_bound(&mut self) -> ParseResult<u32> {
+    if self.starts_with("'") {
+      Ok(self.parse_quoted_char()? as u32 & 0x00ff_ffff)
+    } else {
+      self.parse_u32()
+    }
+  }
+
   fn u32_with_radix(&mut self, radix: Radix) -> ParseResult<u32> {
     let num_str = self.take_while(move |c| c.is_digit(radix as u32) || c == '_');
     let num_str = num_str.chars().filter(|c| *c != '_').collect::<String>();
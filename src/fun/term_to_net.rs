@@ -4,6 +4,7 @@ use crate::{
   hvm::{net_trees, tree_children},
   maybe_grow,
   net::CtrKind::{self, *},
+  utils::par_map,
 };
 use hvm::ast::{Net, Tree};
 use loaned::LoanedMut;
@@ -15,7 +16,11 @@ use std::{
 #[derive(Debug, Clone)]
 pub struct ViciousCycleErr;
 
-pub fn book_to_hvm(book: &Book, diags: &mut Diagnostics) -> Result<(hvm::ast::Book, Labels), Diagnostics> {
+pub fn book_to_hvm(
+  book: &Book,
+  diags: &mut Diagnostics,
+  jobs: usize,
+) -> Result<(hvm::ast::Book, Labels), Diagnostics> {
   diags.start_pass();
 
   let mut hvm_book = hvm::ast::Book { defs: Default::default() };
@@ -23,22 +28,27 @@ pub fn book_to_hvm(book: &Book, diags: &mut Diagnostics) -> Result<(hvm::ast::Bo
 
   let main = book.entrypoint.as_ref();
 
-  for def in book.defs.values() {
-    for rule in def.rules.iter() {
-      let net = term_to_hvm(&rule.body, &mut labels);
+  let rules = book.defs.values().flat_map(|def| def.rules.iter().map(move |rule| (def, rule)));
+  // Each net is compiled with its own labels, which are then merged in the order of the definitions.
+  let nets = par_map(rules, jobs, |(def, rule)| {
+    let mut def_labels = Labels::default();
+    let net = term_to_hvm(&rule.body, &mut def_labels);
 
-      let name = if main.is_some_and(|m| &def.name == m) {
-        book.hvm_entrypoint().to_string()
-      } else {
-        def.name.0.to_string()
-      };
-
-      match net {
-        Ok(net) => {
-          hvm_book.defs.insert(name, net);
-        }
-        Err(err) => diags.add_inet_error(err, name),
+    let name = if main.is_some_and(|m| &def.name == m) {
+      book.hvm_entrypoint().to_string()
+    } else {
+      def.name.0.to_string()
+    };
+    (name, net, def_labels)
+  });
+
+  for (name, net, def_labels) in nets {
+    labels.merge(def_labels);
+    match net {
+      Ok(net) => {
+        hvm_book.defs.insert(name, net);
       }
+      Err(err) => diags.add_inet_error(err, name),
     }
   }
 
@@ -273,7 +283,14 @@ impl<'t, 'l> EncodeTermState<'t, 'l> {
         let kind = self.fan_kind(fan, tag);
         self.make_node_list(kind, up, els.iter().map(|el| |slf: &mut Self, up| slf.encode_pat(el, up)));
       }
-      Pattern::Ctr(_, _) | Pattern::Num(_) | Pattern::Lst(_) | Pattern::Str(_) => unreachable!(),
+      Pattern::Ctr(_, _)
+      | Pattern::Num(_)
+      | Pattern::Range(..)
+      | Pattern::Lst(_)
+      | Pattern::Str(_)
+      | Pattern::Or(_) => {
+        unreachable!()
+      }
     })
   }
 
@@ -409,6 +426,14 @@ impl IndexMut<FanKind> for Labels {
   }
 }
 
+impl Labels {
+  fn merge(&mut self, other: Labels) {
+    self.con.merge(other.con);
+    self.dup.merge(other.dup);
+    self.tup.merge(other.tup);
+  }
+}
+
 impl LabelGenerator {
   // If some tag and new generate a new label, otherwise return the generated label.
   // If none use the implicit label counter.
@@ -449,6 +474,12 @@ impl LabelGenerator {
     }
   }
 
+  fn merge(&mut self, other: LabelGenerator) {
+    self.next = self.next.max(other.next);
+    self.name_to_label.extend(other.name_to_label);
+    self.label_to_name.extend(other.label_to_name);
+  }
+
   fn finish(&mut self) {
     self.next = u16::MAX;
     self.name_to_label.clear();
@@ -456,7 +487,7 @@ impl LabelGenerator {
 }
 
 impl Op {
-  fn to_native_tag(self) -> hvm::hvm::Tag {
+  pub fn to_native_tag(self) -> hvm::hvm::Tag {
     match self {
       Op::ADD => hvm::hvm::OP_ADD,
       Op::SUB => hvm::hvm::OP_SUB,
@@ -0,0 +1,264 @@
+use crate::{
+  fun::{Book, Constructors, Name, Pattern, Tag, Term},
+  maybe_grow,
+};
+use indexmap::IndexMap;
+use std::{
+  collections::hash_map::DefaultHasher,
+  hash::{Hash, Hasher},
+};
+
+impl Book {
+  /// Shares the subterms that are computed more than once, binding them to a `let`
+  /// so that they're computed once and their result is duplicated instead.
+  ///
+  /// ```bend
+  /// (Foo (+ (f x) 1) (* (+ (f x) 1) 2))
+  /// // Becomes
+  /// let %cse0 = (+ (f x) 1); (Foo %cse0 (* %cse0 2))
+  ///
+  /// (* (f x) (f x))
+  /// // Becomes
+  /// let %cse0 = (f x); (* %cse0 %cse0)
+  /// ```
+  ///
+  /// Only numeric operations, and applications whose results are used as numbers, are shared,
+  /// and only when all their copies are evaluated together, so nothing is taken out of a lambda
+  /// or a `switch` arm, which could make it be computed when it otherwise wouldn't.
+  /// The `let` is placed at the innermost term that contains all the copies.
+  ///
+  /// Since `linearize_vars` duplicates the shared value once for each extra use, a subterm is only shared
+  /// if that saves more than the added duplication, counting the duplications of its own free variables,
+  /// or if it calls a definition, since sharing it avoids doing the call more than once.
+  ///
+  /// Nothing is shared in a definition that doesn't duplicate any value if it would start doing it,
+  /// since the runtime can only copy references to definitions without duplications.
+  ///
+  /// Precondition: Variables must have unique names within each definition,
+  /// so that identical subterms always refer to the same values.
+  ///
+  /// Must be run after `encode_matches` and before `linearize_vars`.
+  pub fn eliminate_common_subterms(&mut self) {
+    for def in self.defs.values_mut() {
+      let body = &mut def.rule_mut().body;
+      let original = body.clone();
+      let mut fresh = 0;
+      if body.eliminate_common_subterms(&self.ctrs, &mut fresh) && body.has_dups() && !original.has_dups() {
+        *body = original;
+      }
+    }
+  }
+}
+
+/// Where a subterm is, relative to the region whose common subterms are being shared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Position {
+  /// Evaluated whenever the region is.
+  Region,
+  /// Evaluated whenever the region is, and used as a number.
+  Number,
+  /// Inside a lambda or a `switch` arm.
+  Outside,
+}
+
+impl Term {
+  /// Shares the common subterms of the part of the term evaluated together with its root,
+  /// and then of each lambda body and `switch` arm inside it.
+  /// Returns whether anything was shared.
+  fn eliminate_common_subterms(&mut self, ctrs: &Constructors, fresh: &mut usize) -> bool {
+    maybe_grow(|| {
+      let mut shared = false;
+      while let Some((subterm, hash, copies)) = self.most_common_subterm(ctrs) {
+        let nam = Name::new(format!("%cse{fresh}"));
+        *fresh += 1;
+        self.share_subterm(&nam, &subterm, hash, copies, Position::Region, &mut Some(subterm.clone()));
+        shared = true;
+      }
+
+      let mut inner = vec![];
+      self.inner_regions(&mut inner);
+      for region in inner {
+        shared |= region.eliminate_common_subterms(ctrs, fresh);
+      }
+      shared
+    })
+  }
+
+  /// Finds the largest subterm worth sharing that is repeated in the same region,
+  /// returning it with its hash and number of copies.
+  fn most_common_subterm(&self, ctrs: &Constructors) -> Option<(Term, u64, usize)> {
+    fn go<'a>(term: &'a Term, pos: Position, found: &mut IndexMap<u64, Vec<&'a Term>>) -> u64 {
+      maybe_grow(|| {
+        let hash = term.hash_children(|i, child| go(child, term.child_position(i, pos), found));
+        if term.is_shareable_at(pos) {
+          found.entry(hash).or_default().push(term);
+        }
+        hash
+      })
+    }
+
+    let mut found = IndexMap::new();
+    go(self, Position::Region, &mut found);
+    let mut best: Option<(&Term, u64, usize, usize)> = None;
+    for (hash, copies) in found {
+      let term = copies[0];
+      // Different subterms with the same hash are never shared.
+      if copies.len() < 2 || copies.iter().any(|copy| *copy != term) {
+        continue;
+      }
+      if term.has_unscoped_vars() || !term.worth_sharing(ctrs) {
+        continue;
+      }
+      let size = term.size();
+      if best.map_or(true, |(_, _, _, best_size)| size > best_size) {
+        best = Some((term, hash, copies.len(), size));
+      }
+    }
+    best.map(|(term, hash, copies, _)| (term.clone(), hash, copies))
+  }
+
+  /// Hashes a term from the hashes of its children, given by `hash_child`,
+  /// so that all the subterms of a term are hashed in a single pass.
+  fn hash_children<'a>(&'a self, mut hash_child: impl FnMut(usize, &'a Term) -> u64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    self.hash_node(&mut hasher);
+    for (i, child) in self.children().enumerate() {
+      hash_child(i, child).hash(&mut hasher);
+    }
+    hasher.finish()
+  }
+
+  /// Hashes the contents of this term that aren't its children.
+  fn hash_node(&self, hasher: &mut impl Hasher) {
+    std::mem::discriminant(self).hash(hasher);
+    match self {
+      Term::Lam { tag, pat, .. } => (tag, pat).hash(hasher),
+      Term::Let { pat, .. } => pat.hash(hasher),
+      Term::App { tag, .. } => tag.hash(hasher),
+      Term::Fan { fan, tag, .. } => (fan, tag).hash(hasher),
+      Term::Oper { opr, .. } => opr.hash(hasher),
+      Term::Swt { bnd, with_bnd, pred, .. } => (bnd, with_bnd, pred).hash(hasher),
+      Term::Mat { bnd, with_bnd, arms, .. } => {
+        (bnd, with_bnd).hash(hasher);
+        arms.iter().for_each(|(ctr, fields, ..)| (ctr, fields).hash(hasher));
+      }
+      Term::Var { nam } | Term::Link { nam } | Term::Ref { nam } => nam.hash(hasher),
+      Term::Num { val } => val.hash(hasher),
+      Term::Nat { val } => val.hash(hasher),
+      Term::Str { val } => val.hash(hasher),
+      // The other terms are only made of their children, or don't exist anymore at this point.
+      _ => {}
+    }
+  }
+
+  /// Replaces the copies of `subterm` in the region by a variable,
+  /// bound by a `let` right above all of them, at their innermost common ancestor.
+  ///
+  /// Returns the hash this term had before the replacement and the number of copies found in it.
+  fn share_subterm(
+    &mut self,
+    nam: &Name,
+    subterm: &Term,
+    hash: u64,
+    copies: usize,
+    pos: Position,
+    val: &mut Option<Term>,
+  ) -> (u64, usize) {
+    maybe_grow(|| {
+      let positions = (0..self.children().count()).map(|i| self.child_position(i, pos)).collect::<Vec<_>>();
+      let mut found = 0;
+      let mut hashes = vec![];
+      for (child, pos) in self.children_mut().zip(positions) {
+        let (child_hash, child_found) = child.share_subterm(nam, subterm, hash, copies, pos, val);
+        hashes.push(child_hash);
+        found += child_found;
+      }
+      let own_hash = self.hash_children(|i, _| hashes[i]);
+
+      if self.is_shareable_at(pos) && own_hash == hash && self == subterm {
+        *self = Term::Var { nam: nam.clone() };
+        return (own_hash, 1);
+      }
+      if found == copies {
+        if let Some(val) = val.take() {
+          let nxt = std::mem::take(self);
+          *self = Term::Let {
+            pat: Box::new(Pattern::Var(Some(nam.clone()))),
+            val: Box::new(val),
+            nxt: Box::new(nxt),
+          };
+        }
+      }
+      (own_hash, found)
+    })
+  }
+
+  /// Collects the terms that start a new region inside this one.
+  fn inner_regions<'a>(&'a mut self, regions: &mut Vec<&'a mut Term>) {
+    maybe_grow(|| {
+      let same_region = (0..self.children().count()).map(|i| self.is_same_region(i)).collect::<Vec<_>>();
+      for (child, same_region) in self.children_mut().zip(same_region) {
+        if same_region {
+          child.inner_regions(regions);
+        } else {
+          regions.push(child);
+        }
+      }
+    })
+  }
+
+  /// Whether the `i`-th child of this term is evaluated whenever the term itself is.
+  fn is_same_region(&self, i: usize) -> bool {
+    match self {
+      Term::App { .. } | Term::Oper { .. } | Term::Let { .. } | Term::Fan { .. } | Term::List { .. } => true,
+      Term::Mat { with_arg, .. } | Term::Swt { with_arg, .. } => i <= with_arg.len(),
+      _ => false,
+    }
+  }
+
+  fn child_position(&self, i: usize, pos: Position) -> Position {
+    match self {
+      _ if pos == Position::Outside || !self.is_same_region(i) => Position::Outside,
+      Term::Oper { .. } => Position::Number,
+      Term::Swt { .. } if i == 0 => Position::Number,
+      _ => Position::Region,
+    }
+  }
+
+  /// Operations always result in numbers, but applications can result in functions,
+  /// which the runtime can't always duplicate correctly if they duplicate values themselves,
+  /// so they're only shared if they're used as numbers.
+  fn is_shareable_at(&self, pos: Position) -> bool {
+    match self {
+      Term::Oper { .. } => pos != Position::Outside,
+      Term::App { .. } => pos == Position::Number,
+      _ => false,
+    }
+  }
+
+  /// Whether sharing this term saves more than the duplication it adds.
+  /// Terms that only build constructors are never shared, since they don't compute anything.
+  fn worth_sharing(&self, ctrs: &Constructors) -> bool {
+    fn var_uses(term: &Term) -> usize {
+      maybe_grow(|| matches!(term, Term::Var { .. }) as usize + term.children().map(var_uses).sum::<usize>())
+    }
+    fn computes(term: &Term, ctrs: &Constructors) -> bool {
+      maybe_grow(|| match term {
+        Term::Oper { .. } => true,
+        Term::App { fun, .. } if !matches!(fun.as_ref(), Term::App { .. }) => {
+          !matches!(fun.as_ref(), Term::Ref { nam } if ctrs.contains_key(nam))
+        }
+        _ => false,
+      } || term.children().any(|child| computes(child, ctrs)))
+    }
+    fn calls_def(term: &Term, ctrs: &Constructors) -> bool {
+      maybe_grow(|| {
+        let is_call = matches!(term, Term::App { tag: Tag::Static, fun, .. }
+          if matches!(fun.as_ref(), Term::Ref { nam } if !ctrs.contains_key(nam)));
+        is_call || term.children().any(|child| calls_def(child, ctrs))
+      })
+    }
+    // Each removed copy saves its own nodes and the duplications of its variables, but adds one duplication.
+    computes(self, ctrs) && (self.size() + var_uses(self) > 1 || calls_def(self, ctrs))
+  }
+}
@@ -0,0 +1,261 @@
+use crate::{
+  fun::{Book, Constructors, Name, Num, Op, Pattern, Tag, Term},
+  hvm::pre_reduce::operate,
+  maybe_grow,
+};
+use hvm::ast::Numb;
+use std::{
+  collections::HashMap,
+  mem::{discriminant, Discriminant},
+};
+
+type Constants = HashMap<Name, Num>;
+
+impl Book {
+  /// Simplifies the terms whose result is already known at compile time.
+  ///
+  /// * Numeric operations between two numbers are computed, with the same result as in the runtime.
+  ///   Operations whose result could be different at runtime, like divisions by zero, are kept.
+  /// * Operations with a neutral element, like `(+ x 0)` or `(* x 1.0)`, are replaced by the other operand.
+  ///   Only done when the other operand is known to be a number of the same type as the literal.
+  /// * Switches on a number and matches on a constructor select the corresponding arm.
+  /// * `let`s of numbers or of variables used only once are substituted in their body,
+  ///   and so are the arguments of lambdas applied to them in the same cases.
+  /// * References to definitions whose body is a number are replaced by that number.
+  ///
+  /// User-defined constants are only inlined if `prune` is set,
+  /// since otherwise their definitions would be reported as unused.
+  ///
+  /// Must be run after `linearize_match_with` and before `encode_matches`.
+  pub fn fold_constants(&mut self, prune: bool) {
+    let mut consts = Constants::new();
+    // Folding a definition can turn it into a constant that can be inlined in the others.
+    loop {
+      let mut new_consts = false;
+      for def in self.defs.values_mut() {
+        def.rule_mut().body.fold_constants(&consts, &self.ctrs);
+
+        let is_entrypoint = self.entrypoint.as_ref() == Some(&def.name);
+        // Constructor tags are kept as references, since the readback uses them to recognize constructors.
+        let is_ctr_tag =
+          def.name.strip_suffix("/tag").is_some_and(|ctr| self.ctrs.contains_key(&Name::new(ctr)));
+        if let Term::Num { val } = def.rule().body {
+          if (prune || def.is_builtin()) && !is_entrypoint && !is_ctr_tag && !consts.contains_key(&def.name) {
+            consts.insert(def.name.clone(), val);
+            new_consts = true;
+          }
+        }
+      }
+      if !new_consts {
+        break;
+      }
+    }
+  }
+}
+
+impl Term {
+  fn fold_constants(&mut self, consts: &Constants, ctrs: &Constructors) {
+    maybe_grow(|| {
+      // The bound value of a `let` is substituted before folding its body, so the body can use it.
+      if let Term::Let { pat, val, nxt } = self {
+        if let Pattern::Var(nam) = pat.as_ref() {
+          val.fold_constants(consts, ctrs);
+          if can_substitute(nam, val, nxt) {
+            if let Some(nam) = nam {
+              nxt.subst(nam, val);
+            }
+            *self = std::mem::take(nxt.as_mut());
+            self.fold_constants(consts, ctrs);
+          } else {
+            nxt.fold_constants(consts, ctrs);
+          }
+          return;
+        }
+      }
+
+      for child in self.children_mut() {
+        child.fold_constants(consts, ctrs);
+      }
+
+      match self {
+        Term::Ref { nam } => {
+          if let Some(val) = consts.get(nam) {
+            *self = Term::Num { val: *val };
+          }
+        }
+        Term::Oper { opr, fst, snd } => {
+          if let (Term::Num { val: fst }, Term::Num { val: snd }) = (fst.as_ref(), snd.as_ref()) {
+            if let Some(val) = fold_oper(*opr, fst, snd) {
+              *self = Term::Num { val };
+            }
+          } else if let Term::Num { val } = snd.as_ref() {
+            if is_right_neutral(*opr, val) && num_type(fst) == Some(discriminant(val)) {
+              *self = std::mem::take(fst.as_mut());
+            }
+          } else if let Term::Num { val } = fst.as_ref() {
+            if is_left_neutral(*opr, val) && num_type(snd) == Some(discriminant(val)) {
+              *self = std::mem::take(snd.as_mut());
+            }
+          }
+        }
+        Term::Swt { arg, bnd: _, with_bnd, with_arg: _, pred, arms } if with_bnd.is_empty() => {
+          // Switches read the number as an u24, so only non-negative integers select the same arm.
+          let num = match arg.as_ref() {
+            Term::Num { val: Num::U24(num) } => *num,
+            Term::Num { val: Num::I24(num) } if *num >= 0 => *num as u32,
+            _ => return,
+          };
+          let n_nums = arms.len() as u32 - 1;
+          let selected = (num as usize).min(n_nums as usize);
+          if arms.iter().enumerate().any(|(i, arm)| i != selected && arm.has_unscoped_vars()) {
+            return;
+          }
+          if num < n_nums {
+            *self = std::mem::take(&mut arms[num as usize]);
+          } else {
+            let pred = Pattern::Var(pred.take());
+            let val = Term::Num { val: Num::U24(num - n_nums) };
+            let arm = std::mem::take(arms.last_mut().unwrap());
+            *self = Term::Let { pat: Box::new(pred), val: Box::new(val), nxt: Box::new(arm) };
+            self.fold_constants(consts, ctrs);
+          }
+        }
+        Term::Mat { arg, bnd: _, with_bnd, with_arg: _, arms } if with_bnd.is_empty() => {
+          let Some((ctr, args)) = ctr_call(arg, ctrs) else { return };
+          let Some(arm) =
+            arms.iter().position(|(nam, fields, ..)| nam.as_ref() == Some(ctr) && fields.len() == args.len())
+          else {
+            return;
+          };
+          if arms.iter().enumerate().any(|(i, (_, _, body, _))| i != arm && body.has_unscoped_vars()) {
+            return;
+          }
+          let args = args.into_iter().cloned().collect::<Vec<_>>();
+          let (_, fields, body, _) = std::mem::take(&mut arms[arm]);
+          *self = fields.into_iter().zip(args).rfold(body, |nxt, (field, val)| Term::Let {
+            pat: Box::new(Pattern::Var(field)),
+            val: Box::new(val),
+            nxt: Box::new(nxt),
+          });
+          self.fold_constants(consts, ctrs);
+        }
+        Term::App { tag: Tag::Static, fun, arg } => {
+          if let Term::Lam { tag: Tag::Static, pat, bod } = fun.as_mut() {
+            // Only done when the argument will be substituted, since a `let` would copy
+            // values like closed lambdas that `float_combinators` could otherwise extract.
+            let Pattern::Var(nam) = pat.as_mut() else { return };
+            if can_substitute(nam, arg, bod) {
+              *self = Term::Let {
+                pat: Box::new(Pattern::Var(nam.take())),
+                val: std::mem::take(arg),
+                nxt: std::mem::take(bod),
+              };
+              self.fold_constants(consts, ctrs);
+            }
+          }
+        }
+        _ => {}
+      }
+    })
+  }
+}
+
+/// Checks if the value bound to `nam` can be substituted in `nxt` without duplicating any work.
+fn can_substitute(nam: &Option<Name>, val: &Term, nxt: &Term) -> bool {
+  if matches!(val, Term::Num { .. } | Term::Var { .. } | Term::Era) {
+    return true;
+  }
+  let uses = nam.as_ref().map_or(0, |nam| count_uses(nxt, nam));
+  uses == 1 || uses == 0 && can_erase(val)
+}
+
+/// Counts the free occurrences of `nam` in the term,
+/// adding up the ones in different branches of a match.
+fn count_uses(term: &Term, nam: &Name) -> usize {
+  maybe_grow(|| match term {
+    Term::Var { nam: var } => (var == nam) as usize,
+    _ => term
+      .children_with_binds()
+      .map(
+        |(child, mut binds)| {
+          if binds.any(|bind| bind.as_ref() == Some(nam)) {
+            0
+          } else {
+            count_uses(child, nam)
+          }
+        },
+      )
+      .sum(),
+  })
+}
+
+/// Checks if removing the term can't change the result of the program.
+/// Terms with unscoped variables are kept, since those may be bound or used elsewhere,
+/// and so are applications, which could call a lambda that binds an unscoped variable.
+fn can_erase(term: &Term) -> bool {
+  fn has_app(term: &Term) -> bool {
+    maybe_grow(|| matches!(term, Term::App { .. }) || term.children().any(has_app))
+  }
+  !term.has_unscoped_vars() && !has_app(term)
+}
+
+/// Computes a numeric operation like the compiled program would,
+/// with the first operand partially applied to the operator.
+fn fold_oper(opr: Op, fst: &Num, snd: &Num) -> Option<Num> {
+  let partial = Numb((fst.to_bits() & !0x1F) | opr.to_native_tag() as u32);
+  let mut res = operate(&partial, &Numb(snd.to_bits()))?;
+  if matches!(opr, Op::LE | Op::GE) {
+    res = operate(&Numb(Op::EQ.to_native_tag() as u32), &res)?;
+  }
+  Some(Num::from_bits(res.0))
+}
+
+/// Returns the type of the number the term evaluates to, if it's known at compile time.
+/// An operation has the type of its second operand, except comparisons, which return an `u24`.
+fn num_type(term: &Term) -> Option<Discriminant<Num>> {
+  maybe_grow(|| match term {
+    Term::Num { val } => Some(discriminant(val)),
+    Term::Oper { opr: Op::EQ | Op::NEQ | Op::LT | Op::GT | Op::LE | Op::GE, .. } => {
+      Some(discriminant(&Num::U24(0)))
+    }
+    Term::Oper { snd, .. } => num_type(snd),
+    _ => None,
+  })
+}
+
+/// Checks if `(opr x val)` is always `x`, when `x` is a number of the same type as `val`.
+fn is_right_neutral(opr: Op, val: &Num) -> bool {
+  match val {
+    Num::U24(0) | Num::I24(0) => matches!(opr, Op::ADD | Op::SUB | Op::OR | Op::XOR),
+    Num::U24(1) | Num::I24(1) => matches!(opr, Op::MUL | Op::DIV),
+    // `-0.0 + 0.0` is `0.0`, so only subtraction keeps the sign of zero.
+    Num::F24(val) if *val == 0.0 && val.is_sign_positive() => opr == Op::SUB,
+    Num::F24(val) if *val == 1.0 => matches!(opr, Op::MUL | Op::DIV),
+    _ => false,
+  }
+}
+
+/// Checks if `(opr val x)` is always `x`, when `x` is a number of the same type as `val`.
+fn is_left_neutral(opr: Op, val: &Num) -> bool {
+  match val {
+    Num::U24(0) | Num::I24(0) => matches!(opr, Op::ADD | Op::OR | Op::XOR),
+    Num::U24(1) | Num::I24(1) => opr == Op::MUL,
+    Num::F24(val) if *val == 1.0 => opr == Op::MUL,
+    _ => false,
+  }
+}
+
+/// If the term is a constructor applied to all of its fields, returns the constructor and its arguments.
+fn ctr_call<'a>(term: &'a Term, ctrs: &Constructors) -> Option<(&'a Name, Vec<&'a Term>)> {
+  let mut args = vec![];
+  let mut term = term;
+  while let Term::App { tag: Tag::Static, fun, arg } = term {
+    args.push(arg.as_ref());
+    term = fun;
+  }
+  args.reverse();
+  match term {
+    Term::Ref { nam } if ctrs.contains_key(nam) => Some((nam, args)),
+    _ => None,
+  }
+}
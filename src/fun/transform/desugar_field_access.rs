@@ -0,0 +1,146 @@
+use crate::{
+  diagnostics::Diagnostics,
+  fun::{Adts, Ctx, Name, Term},
+  maybe_grow,
+};
+use std::collections::HashMap;
+
+/// A field of the constructor of a type with a single constructor.
+struct RecordField {
+  typ: Name,
+  ctr: Name,
+  idx: usize,
+  arity: usize,
+}
+
+type RecordFields = HashMap<Name, Vec<RecordField>>;
+
+impl Ctx<'_> {
+  /// Desugars record field accesses into pattern matches.
+  ///
+  /// A free variable like `p.x.y`, where `p` is bound, is the projection of the field `x`
+  /// of `p`, followed by the projection of the field `y` of the result.
+  /// Since the values are not typed, the fields are resolved by name among the
+  /// types that have a single constructor.
+  ///
+  /// Example:
+  /// ```hvm
+  /// type Point = (Point x y)
+  /// (Main) = λp (+ p.x p.y)
+  /// // Becomes
+  /// (Main) = λp (+ match p { Point %proj.x *: %proj.x } match p { Point * %proj.y: %proj.y })
+  /// ```
+  pub fn desugar_field_access(&mut self) -> Result<(), Diagnostics> {
+    self.info.start_pass();
+
+    let fields = record_fields(&self.book.adts);
+    for def in self.book.defs.values_mut() {
+      for rule in def.rules.iter_mut() {
+        let mut scope = HashMap::new();
+        for nam in rule.pats.iter().flat_map(|pat| pat.binds()).flatten() {
+          *scope.entry(nam.clone()).or_default() += 1;
+        }
+        if let Err(err) = rule.body.desugar_field_access(&fields, &mut scope) {
+          self.info.add_rule_error(err, def.name.clone());
+        }
+      }
+    }
+
+    self.info.fatal(())
+  }
+}
+
+impl Term {
+  fn desugar_field_access(
+    &mut self,
+    fields: &RecordFields,
+    scope: &mut HashMap<Name, u64>,
+  ) -> Result<(), String> {
+    maybe_grow(|| {
+      if let Term::Var { nam } = self {
+        if !scope.contains_key(nam) {
+          if let Some(proj) = project_var(nam, fields, scope)? {
+            *self = proj;
+          }
+        }
+        return Ok(());
+      }
+      for (child, binds) in self.children_mut_with_binds() {
+        let binds = binds.flatten().cloned().collect::<Vec<_>>();
+        for bind in binds.iter() {
+          *scope.entry(bind.clone()).or_default() += 1;
+        }
+        child.desugar_field_access(fields, scope)?;
+        for bind in binds {
+          let count = scope.get_mut(&bind).unwrap();
+          *count -= 1;
+          if *count == 0 {
+            scope.remove(&bind);
+          }
+        }
+      }
+      Ok(())
+    })
+  }
+}
+
+fn record_fields(adts: &Adts) -> RecordFields {
+  let mut fields = RecordFields::new();
+  for (typ, adt) in adts.iter() {
+    if let [(ctr, ctr_fields)] = adt.ctrs.iter().collect::<Vec<_>>()[..] {
+      for (idx, field) in ctr_fields.iter().enumerate() {
+        let arity = ctr_fields.len();
+        let field_info = RecordField { typ: typ.clone(), ctr: ctr.clone(), idx, arity };
+        fields.entry(field.nam.clone()).or_default().push(field_info);
+      }
+    }
+  }
+  fields
+}
+
+/// Converts an unbound variable `a.b.c` into field projections of the longest bound prefix.
+/// Returns `None` if no prefix of the name is bound.
+fn project_var(
+  var: &Name,
+  fields: &RecordFields,
+  scope: &HashMap<Name, u64>,
+) -> Result<Option<Term>, String> {
+  let mut end = var.len();
+  let base = loop {
+    let Some(idx) = var[..end].rfind('.') else { return Ok(None) };
+    let base = Name::new(&var[..idx]);
+    if scope.contains_key(&base) {
+      break base;
+    }
+    end = idx;
+  };
+
+  // Projections of expressions are bound to generated variables, which shouldn't show up in the errors.
+  let access = if base.starts_with('%') { String::new() } else { format!(" in '{var}'") };
+  let mut term = Term::Var { nam: base.clone() };
+  for field in var[base.len() + 1..].split('.') {
+    let Some(candidates) = fields.get(&Name::new(field)) else {
+      return Err(format!(
+        "Unknown field '{field}'{access}. No type with a single constructor has a field named '{field}'."
+      ));
+    };
+    let fst = &candidates[0];
+    if let Some(other) = candidates.iter().find(|c| (c.idx, c.arity) != (fst.idx, fst.arity)) {
+      return Err(format!(
+        "Ambiguous field '{field}'{access}. The types '{}' and '{}' both have a field named '{field}'. Use 'open' or 'match' to access it instead.",
+        fst.typ, other.typ
+      ));
+    }
+    // Constructors with the same layout are matched the same way, so any of them can be used.
+    let field_var = Name::new(format!("%proj.{field}"));
+    let binds = (0..fst.arity).map(|i| (i == fst.idx).then(|| field_var.clone())).collect();
+    term = Term::Mat {
+      arg: Box::new(term),
+      bnd: None,
+      with_bnd: vec![],
+      with_arg: vec![],
+      arms: vec![(Some(fst.ctr.clone()), binds, Term::Var { nam: field_var }, None)],
+    };
+  }
+  Ok(Some(term))
+}
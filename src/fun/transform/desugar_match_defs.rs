@@ -1,29 +1,37 @@
 use crate::{
   diagnostics::{Diagnostics, WarningType},
-  fun::{builtins, Adts, Constructors, Ctx, Definition, FanKind, Name, Num, Pattern, Rule, Tag, Term},
+  fun::{builtins, Adts, Constructors, Ctx, Definition, FanKind, Name, Num, Op, Pattern, Rule, Tag, Term},
   maybe_grow,
+  utils::par_map,
 };
 use std::collections::{BTreeSet, HashSet};
 
+const U24_MAX: u32 = 0x00ff_ffff;
+
 pub enum DesugarMatchDefErr {
   AdtNotExhaustive { adt: Name, ctr: Name },
   NumMissingDefault,
   TypeMismatch { expected: Type, found: Type, pat: Pattern },
   RepeatedBind { bind: Name },
+  OrPatternBinds { pat: Pattern },
 }
 
 impl Ctx<'_> {
   /// Converts equational-style pattern matching function definitions into trees of match terms.
-  pub fn desugar_match_defs(&mut self) -> Result<(), Diagnostics> {
+  pub fn desugar_match_defs(&mut self, jobs: usize) -> Result<(), Diagnostics> {
     self.info.start_pass();
 
-    for (def_name, def) in self.book.defs.iter_mut() {
-      let errs = def.desugar_match_def(&self.book.ctrs, &self.book.adts);
+    let (ctrs, adts) = (&self.book.ctrs, &self.book.adts);
+    let errs = par_map(self.book.defs.iter_mut(), jobs, |(def_name, def)| {
+      (def_name.clone(), def.desugar_match_def(ctrs, adts))
+    });
+    for (def_name, errs) in errs {
       for err in errs {
         match err {
           DesugarMatchDefErr::AdtNotExhaustive { .. }
           | DesugarMatchDefErr::NumMissingDefault
-          | DesugarMatchDefErr::TypeMismatch { .. } => self.info.add_rule_error(err, def_name.clone()),
+          | DesugarMatchDefErr::TypeMismatch { .. }
+          | DesugarMatchDefErr::OrPatternBinds { .. } => self.info.add_rule_error(err, def_name.clone()),
           DesugarMatchDefErr::RepeatedBind { .. } => {
             self.info.add_rule_warning(err, WarningType::RepeatedBind, def_name.clone())
           }
@@ -41,6 +49,7 @@ impl Definition {
     for rule in self.rules.iter_mut() {
       desugar_inner_match_defs(&mut rule.body, ctrs, adts, &mut errs);
     }
+    let shared_bodies = expand_or_patterns(&mut self.rules, &mut errs);
     let repeated_bind_errs = fix_repeated_binds(&mut self.rules);
     errs.extend(repeated_bind_errs);
 
@@ -48,6 +57,7 @@ impl Definition {
     let rules = std::mem::take(&mut self.rules);
     match simplify_rule_match(args.clone(), rules, vec![], ctrs, adts) {
       Ok(body) => {
+        let body = shared_bodies.into_iter().rfold(body, |nxt, def| Term::Def { def, nxt: Box::new(nxt) });
         let body = Term::rfold_lams(body, args.into_iter().map(Some));
         self.rules = vec![Rule { pats: vec![], body }];
       }
@@ -57,6 +67,98 @@ impl Definition {
   }
 }
 
+impl Term {
+  /// Creates a term that matches `arg` against the given arms by compiling them into local pattern
+  /// matching definitions, so that they go through the same pattern matrix compilation as function rules.
+  ///
+  /// Guarded arms can't be expressed as rules, so the arms are split after each guarded arm
+  /// and a failed guard falls through to the definition with the remaining arms.
+  /// The last arm must not have a guard.
+  pub fn pattern_match(
+    arg: Term,
+    bnd: Option<Name>,
+    with_bnd: Vec<Option<Name>>,
+    with_arg: Vec<Term>,
+    arms: Vec<(Pattern, Option<Term>, Term)>,
+  ) -> Term {
+    let matched = Name::new("%matched");
+    let segment_name = |i: usize| Name::new(format!("match__{i}"));
+
+    let mut segments = vec![vec![]];
+    for (pat, guard, body) in arms {
+      if guard.is_some() {
+        // The guard is checked for each alternative that matches, so they must be separate arms.
+        for pat in pat.expand_alternatives() {
+          segments.last_mut().unwrap().push((pat, guard.clone(), body.clone()));
+          segments.push(vec![]);
+        }
+      } else {
+        segments.last_mut().unwrap().push((pat, guard, body));
+      }
+    }
+    debug_assert!(!segments.last().unwrap().is_empty(), "The last arm of a pattern match can't have a guard");
+
+    let mut term = Term::call(Term::Var { nam: segment_name(0) }, [Term::Var { nam: matched.clone() }]);
+    for (i, segment) in segments.into_iter().enumerate() {
+      let fallthrough =
+        Term::call(Term::Var { nam: segment_name(i + 1) }, [Term::Var { nam: matched.clone() }]);
+      let mut rules = vec![];
+      for (pat, guard, body) in segment {
+        if let Some(guard) = guard {
+          let body = Term::Swt {
+            arg: Box::new(guard),
+            bnd: Some(Name::new("%pred")),
+            with_bnd: vec![],
+            with_arg: vec![],
+            pred: Some(Name::new("%pred-1")),
+            arms: vec![fallthrough.clone(), body],
+          };
+          rules.push(Rule { pats: vec![pat], body });
+          rules.push(Rule { pats: vec![Pattern::Var(None)], body: fallthrough.clone() });
+        } else {
+          rules.push(Rule { pats: vec![pat], body });
+        }
+      }
+      let def = Definition::new_gen(segment_name(i), rules, false);
+      term = Term::Def { def, nxt: Box::new(term) };
+    }
+
+    let (bnd, arg) = match bnd {
+      Some(bnd) => {
+        let nxt = Box::new(term);
+        term = Term::Use { nam: Some(matched), val: Box::new(Term::Var { nam: bnd.clone() }), nxt };
+        (bnd, arg)
+      }
+      None => (matched, arg),
+    };
+    for (bnd, arg) in with_bnd.into_iter().zip(with_arg).rev() {
+      term = Term::Let { pat: Box::new(Pattern::Var(bnd)), val: Box::new(arg), nxt: Box::new(term) };
+    }
+    Term::Let { pat: Box::new(Pattern::Var(Some(bnd))), val: Box::new(arg), nxt: Box::new(term) }
+  }
+
+  /// Checks that the default case of a pattern match on numbers doesn't use the predecessor of the
+  /// matched value, like `x-2`, which is only bound by switches whose cases are the numbers from 0 to n.
+  pub fn check_switch_pred(bnd: &Option<Name>, arms: &[(Pattern, Option<Term>, Term)]) -> Result<(), String> {
+    let (Some(bnd), Some((Pattern::Var(None), _, body))) = (bnd, arms.last()) else { return Ok(()) };
+    if !arms.iter().any(|(pat, ..)| matches!(pat, Pattern::Num(_) | Pattern::Range(..))) {
+      return Ok(());
+    }
+    let prefix = format!("{bnd}-");
+    let is_pred = |var: &Name| {
+      var
+        .strip_prefix(prefix.as_str())
+        .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+    };
+    match body.free_vars().into_keys().find(is_pred) {
+      Some(var) => Err(format!(
+        "The predecessor '{var}' is only bound in the default case of switches whose cases are the numbers from 0 to n. Use '{bnd}' instead."
+      )),
+      None => Ok(()),
+    }
+  }
+}
+
 fn desugar_inner_match_defs(
   term: &mut Term,
   ctrs: &Constructors,
@@ -76,6 +178,102 @@ fn desugar_inner_match_defs(
   })
 }
 
+/// Expands the or-patterns of each rule into one rule for each combination of alternatives.
+///
+/// ```hvm
+/// (Foo (Bar/A | Bar/B) (Bar/A | Bar/C)) = 1
+/// // After this transformation, becomes:
+/// (Foo Bar/A Bar/A) = 1
+/// (Foo Bar/A Bar/C) = 1
+/// (Foo Bar/B Bar/A) = 1
+/// (Foo Bar/B Bar/C) = 1
+/// ```
+///
+/// Since the number of rules grows with the product of the number of alternatives,
+/// bodies that are not trivial to copy are not repeated in each rule.
+/// Instead, they are moved to a local definition that receives the variables of the patterns,
+/// which is returned to be defined around the compiled match, and the rules call it.
+///
+/// All alternatives of an or-pattern must bind the same variables, otherwise an error is returned.
+fn expand_or_patterns(rules: &mut Vec<Rule>, errs: &mut Vec<DesugarMatchDefErr>) -> Vec<Definition> {
+  let mut shared_bodies = vec![];
+  if !rules.iter().flat_map(|r| &r.pats).flat_map(Pattern::iter).any(|p| matches!(p, Pattern::Or(_))) {
+    return shared_bodies;
+  }
+
+  for rule in std::mem::take(rules) {
+    for pat in rule.pats.iter() {
+      check_or_binds(pat, errs);
+    }
+    let alts = rule.pats.into_iter().map(Pattern::expand_alternatives).collect::<Vec<_>>();
+    let mut body = rule.body;
+    let is_trivial = matches!(body, Term::Num { .. } | Term::Var { .. } | Term::Ref { .. } | Term::Era);
+    // Unscoped variables can't be moved to another definition.
+    if alts.iter().any(|alts| alts.len() > 1) && !is_trivial && !body.has_unscoped_vars() {
+      let nam = Name::new(format!("or__{}", shared_bodies.len()));
+      let vars = alts.iter().flat_map(|alts| alts[0].binds().flatten().cloned()).collect::<Vec<_>>();
+      let shared = Term::rfold_lams(body, vars.iter().cloned().map(Some));
+      shared_bodies.push(Definition::new_gen(nam.clone(), vec![Rule { pats: vec![], body: shared }], false));
+      body = Term::call(Term::Var { nam }, vars.into_iter().map(|nam| Term::Var { nam }));
+    }
+    for pats in cartesian_product(alts) {
+      rules.push(Rule { pats, body: body.clone() });
+    }
+  }
+  shared_bodies
+}
+
+fn check_or_binds(pat: &Pattern, errs: &mut Vec<DesugarMatchDefErr>) {
+  for pat in pat.iter() {
+    if let Pattern::Or(alts) = pat {
+      let binds = |alt: &Pattern| alt.binds().flatten().cloned().collect::<BTreeSet<_>>();
+      let fst_binds = binds(&alts[0]);
+      if alts[1..].iter().any(|alt| binds(alt) != fst_binds) {
+        errs.push(DesugarMatchDefErr::OrPatternBinds { pat: pat.clone() });
+      }
+    }
+  }
+}
+
+fn cartesian_product(sets: Vec<Vec<Pattern>>) -> Vec<Vec<Pattern>> {
+  sets.into_iter().fold(vec![vec![]], |acc, set| {
+    acc
+      .into_iter()
+      .flat_map(|prefix| {
+        set.iter().map(move |pat| {
+          let mut pats = prefix.clone();
+          pats.push(pat.clone());
+          pats
+        })
+      })
+      .collect()
+  })
+}
+
+impl Pattern {
+  /// Returns the list of patterns without or-patterns that together match the same values as `self`.
+  pub fn expand_alternatives(self) -> Vec<Pattern> {
+    maybe_grow(|| match self {
+      Pattern::Or(alts) => alts.into_iter().flat_map(Pattern::expand_alternatives).collect(),
+      Pattern::Ctr(nam, els) => {
+        let els = els.into_iter().map(Pattern::expand_alternatives).collect();
+        cartesian_product(els).into_iter().map(|els| Pattern::Ctr(nam.clone(), els)).collect()
+      }
+      Pattern::Fan(fan, tag, els) => {
+        let els = els.into_iter().map(Pattern::expand_alternatives).collect();
+        cartesian_product(els).into_iter().map(|els| Pattern::Fan(fan, tag.clone(), els)).collect()
+      }
+      Pattern::Lst(els) => {
+        let els = els.into_iter().map(Pattern::expand_alternatives).collect();
+        cartesian_product(els).into_iter().map(Pattern::Lst).collect()
+      }
+      Pattern::Var(_) | Pattern::Chn(_) | Pattern::Num(_) | Pattern::Range(..) | Pattern::Str(_) => {
+        vec![self]
+      }
+    })
+  }
+}
+
 /// When a rule has repeated bind, the only one that is actually useful is the last one.
 ///
 /// Example: In `(Foo x x x x) = x`, the function should return the fourth argument.
@@ -282,6 +480,10 @@ fn num_rule(
     return Err(DesugarMatchDefErr::NumMissingDefault);
   }
 
+  if rules.iter().any(|r| matches!(r.pats[0], Pattern::Range(..))) {
+    return range_rule(args, rules, with, ctrs, adts);
+  }
+
   let arg = args[0].clone();
   let args = args.split_off(1);
 
@@ -374,6 +576,132 @@ fn num_rule(
   Ok(term)
 }
 
+/// The indices of some rules and the intervals of numbers matched by exactly those rules.
+type RangeGroup = (Vec<usize>, Vec<(u32, u32)>);
+
+/// Number rule with ranges.
+///
+/// The bounds of the ranges split the numbers into disjoint intervals, each one matched by the same rules.
+/// The intervals that are matched by the same rules are tested together in a native switch on the
+/// argument, and these switches are chained until the default case.
+/// ```hvm
+/// case x0 ... xN {
+///   48..57 p1 ... pN: (Body0 p1 ... pN)
+///   var p1 ... pN: (Body1 var p1 ... pN)
+/// }
+/// ```
+/// becomes
+/// ```hvm
+/// switch (& (>= x0 48) (<= x0 57)) {
+///   0: case x1 ... xN { p1 ... pN: use var = x0; (Body1 var p1 ... pN) }
+///   _: case x1 ... xN { p1 ... pN: (Body0 p1 ... pN) }
+/// }
+/// ```
+fn range_rule(
+  mut args: Vec<Name>,
+  rules: Vec<Rule>,
+  with: Vec<Name>,
+  ctrs: &Constructors,
+  adts: &Adts,
+) -> Result<Term, DesugarMatchDefErr> {
+  let arg = args[0].clone();
+  let args = args.split_off(1);
+
+  let bounds = |pat: &Pattern| match pat {
+    Pattern::Num(n) => Some((*n, *n)),
+    Pattern::Range(lo, hi) => Some((*lo, *hi)),
+    _ => None,
+  };
+
+  let mut points = BTreeSet::new();
+  for rule in rules.iter() {
+    if let Some((lo, hi)) = bounds(&rule.pats[0]) {
+      if lo <= hi {
+        points.insert(lo);
+        points.insert(hi.min(U24_MAX) + 1);
+      }
+    }
+  }
+  let points = points.into_iter().collect::<Vec<_>>();
+
+  // Group the intervals by the rules that match them.
+  // Intervals that are only matched by variables go to the default case.
+  let mut groups: Vec<RangeGroup> = vec![];
+  for bound in points.windows(2) {
+    let (lo, hi) = (bound[0], bound[1] - 1);
+    let matching = (0..rules.len())
+      .filter(|&i| bounds(&rules[i].pats[0]).map_or(true, |(l, h)| l <= lo && hi <= h))
+      .collect::<Vec<_>>();
+    if matching.iter().all(|&i| rules[i].pats[0].is_wildcard()) {
+      continue;
+    }
+    match groups.iter_mut().find(|(group, _)| *group == matching) {
+      Some((_, intervals)) => match intervals.last_mut() {
+        Some((_, last_hi)) if *last_hi + 1 == lo => *last_hi = hi,
+        _ => intervals.push((lo, hi)),
+      },
+      None => groups.push((matching, vec![(lo, hi)])),
+    }
+  }
+
+  let mut body_with = with.clone();
+  body_with.push(arg.clone());
+  let rules_body = |rules: Vec<&Rule>| {
+    let new_rules = rules
+      .into_iter()
+      .map(|rule| {
+        let mut body = rule.body.clone();
+        if let Pattern::Var(Some(var)) = &rule.pats[0] {
+          let val = Box::new(Term::Var { nam: arg.clone() });
+          body = Term::Use { nam: Some(var.clone()), val, nxt: Box::new(body) };
+        }
+        Rule { pats: rule.pats[1..].to_vec(), body }
+      })
+      .collect();
+    simplify_rule_match(args.clone(), new_rules, body_with.clone(), ctrs, adts)
+  };
+
+  let mut cases = vec![];
+  for (matching, intervals) in groups {
+    let body = rules_body(matching.into_iter().map(|i| &rules[i]).collect())?;
+    cases.push((range_cond(&arg, &intervals), body));
+  }
+  let default_body = rules_body(rules.iter().filter(|r| matches!(r.pats[0], Pattern::Var(_))).collect())?;
+
+  // Linearize previously matched vars, current args and the matched number.
+  let with = with.into_iter().chain(args.iter().cloned()).chain([arg.clone()]).collect::<Vec<_>>();
+  let with_bnd = with.iter().cloned().map(Some).collect::<Vec<_>>();
+  let with_arg = with.iter().cloned().map(|nam| Term::Var { nam }).collect::<Vec<_>>();
+
+  let term = cases.into_iter().rfold(default_body, |term, (cond, body)| Term::Swt {
+    arg: Box::new(cond),
+    bnd: Some(Name::new("%cond")),
+    with_bnd: with_bnd.clone(),
+    with_arg: with_arg.clone(),
+    pred: Some(Name::new("%cond-1")),
+    arms: vec![term, body],
+  });
+
+  Ok(term)
+}
+
+/// Returns a term that is 1 if `arg` is in any of the given inclusive intervals and 0 otherwise.
+fn range_cond(arg: &Name, intervals: &[(u32, u32)]) -> Term {
+  let op = |opr, fst, snd| Term::Oper { opr, fst: Box::new(fst), snd: Box::new(snd) };
+  let var = || Term::Var { nam: arg.clone() };
+  let num = |val| Term::Num { val: Num::U24(val) };
+  intervals
+    .iter()
+    .map(|&(lo, hi)| match (lo, hi) {
+      (lo, hi) if lo == hi => op(Op::EQ, var(), num(lo)),
+      (0, hi) => op(Op::LE, var(), num(hi)),
+      (lo, hi) if hi >= U24_MAX => op(Op::GE, var(), num(lo)),
+      (lo, hi) => op(Op::AND, op(Op::GE, var(), num(lo)), op(Op::LE, var(), num(hi))),
+    })
+    .reduce(|acc, cond| op(Op::OR, acc, cond))
+    .unwrap()
+}
+
 /// Replaces `body` to `pred_var` if the term is a operation that subtracts the given var by the current
 /// switch number.
 fn fast_pred_access(body: &mut Term, cur_num: u32, var: &Name, pred_var: &Name) {
@@ -500,7 +828,7 @@ fn switch_rule(
     }
 
     let body = simplify_rule_match(args, new_rules, with.clone(), ctrs, adts)?;
-    new_arms.push((Some(ctr.clone()), new_args.map(Some).collect(), body));
+    new_arms.push((Some(ctr.clone()), new_args.map(Some).collect(), body, None));
   }
 
   // Linearize previously matched vars and current args.
@@ -565,9 +893,10 @@ impl Pattern {
         Type::Adt(adt_nam.clone())
       }
       Pattern::Fan(is_tup, tag, args) => Type::Fan(*is_tup, tag.clone(), args.len()),
-      Pattern::Num(_) => Type::Num,
+      Pattern::Num(_) | Pattern::Range(..) => Type::Num,
       Pattern::Lst(..) => Type::Adt(Name::new(builtins::LIST)),
       Pattern::Str(..) => Type::Adt(Name::new(builtins::STRING)),
+      Pattern::Or(..) => unreachable!("Or-patterns should have been expanded"),
     }
   }
 }
@@ -603,6 +932,9 @@ impl std::fmt::Display for DesugarMatchDefErr {
       DesugarMatchDefErr::RepeatedBind { bind } => {
         write!(f, "Repeated bind in pattern matching rule: '{bind}'.")
       }
+      DesugarMatchDefErr::OrPatternBinds { pat } => {
+        write!(f, "All alternatives of the or-pattern '{pat}' must bind the same variables.")
+      }
     }
   }
 }
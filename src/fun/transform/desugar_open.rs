@@ -34,7 +34,7 @@ impl Term {
                 bnd: Some(std::mem::take(var)),
                 with_bnd: vec![],
                 with_arg: vec![],
-                arms: vec![(Some(ctr.clone()), vec![], std::mem::take(bod))],
+                arms: vec![(Some(ctr.clone()), vec![], std::mem::take(bod), None)],
               }
             } else {
               return Err(format!("Type '{typ}' of an 'open' has more than one constructor"));
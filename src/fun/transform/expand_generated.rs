@@ -23,7 +23,7 @@ impl Term {
   }
 }
 
-type DepGraph = HashMap<Name, HashSet<Name>>;
+pub(crate) type DepGraph = HashMap<Name, HashSet<Name>>;
 type Cycles = Vec<Vec<Name>>;
 type RecursiveDefs = BTreeSet<Name>;
 
@@ -83,18 +83,20 @@ fn find_cycles(deps: &DepGraph, nam: &Name, visited: &mut HashSet<Name>, cycles:
   }
 }
 
-fn book_def_deps(book: &Book) -> DepGraph {
+pub(crate) fn book_def_deps(book: &Book) -> DepGraph {
   book.defs.iter().map(|(nam, def)| (nam.clone(), def_deps(def))).collect()
 }
 
 fn def_deps(def: &crate::fun::Definition) -> HashSet<Name> {
   fn collect_refs(term: &Term, set: &mut HashSet<Name>) {
-    if let Term::Ref { nam } = term {
-      set.insert(nam.clone());
-    }
-    for children in term.children() {
-      collect_refs(children, set);
-    }
+    maybe_grow(|| {
+      if let Term::Ref { nam } = term {
+        set.insert(nam.clone());
+      }
+      for children in term.children() {
+        collect_refs(children, set);
+      }
+    })
   }
 
   let mut set = HashSet::new();
@@ -8,6 +8,7 @@ use std::collections::HashMap;
 enum FixMatchErr {
   AdtMismatch { expected: Name, found: Name, ctr: Name },
   NonExhaustiveMatch { typ: Name, missing: Name },
+  NonExhaustiveGuards { typ: Option<Name>, missing: Option<Name> },
   IrrefutableMatch { var: Option<Name> },
   UnreachableMatchArms { var: Option<Name> },
   RedundantArm { ctr: Name },
@@ -19,6 +20,7 @@ impl Ctx<'_> {
   /// * For switches, resolve the succ case ("_") and create the name of the pred variable.
   /// * If the match arg is not a variable, it is separated into a let expression and bound to "%matched"
   /// * Check for redundant arms and non-exhaustive matches.
+  /// * Convert arms with guards into `if` chains that fall through to the next arms of the same constructor.
   ///
   /// Example:
   /// For the program
@@ -50,9 +52,9 @@ impl Ctx<'_> {
 
         for err in errs {
           match err {
-            FixMatchErr::AdtMismatch { .. } | FixMatchErr::NonExhaustiveMatch { .. } => {
-              self.info.add_rule_error(err, def.name.clone())
-            }
+            FixMatchErr::AdtMismatch { .. }
+            | FixMatchErr::NonExhaustiveMatch { .. }
+            | FixMatchErr::NonExhaustiveGuards { .. } => self.info.add_rule_error(err, def.name.clone()),
             FixMatchErr::IrrefutableMatch { .. } => {
               self.info.add_rule_warning(err, WarningType::IrrefutableMatch, def.name.clone())
             }
@@ -94,7 +96,7 @@ impl Term {
         // Add a use term to each arm rebuilding the matched variable
         Term::Mat { arg: _, bnd, with_bnd: _, with_arg: _, arms }
         | Term::Fold { bnd, arg: _, with_bnd: _, with_arg: _, arms } => {
-          for (ctr, fields, body) in arms {
+          for (ctr, fields, body, _) in arms {
             if let Some(ctr) = ctr {
               *body = Term::Use {
                 nam: bnd.clone(),
@@ -140,7 +142,10 @@ impl Term {
     let bnd = bnd.clone().unwrap();
 
     // Normalize arms, making one arm for each constructor of the matched adt.
-    if let Some(ctr_nam) = &arms[0].0 {
+    // Leading variable arms with guards don't determine the type, so we skip them.
+    let fst_arm =
+      arms.iter().find(|arm| arm.3.is_none() || arm.0.as_ref().is_some_and(|c| ctrs.contains_key(c)));
+    if let Some(ctr_nam) = fst_arm.and_then(|arm| arm.0.as_ref()) {
       if let Some(adt_nam) = ctrs.get(ctr_nam) {
         // First arm matches a constructor as expected, so we can normalize the arms.
         let adt_ctrs = &adts[adt_nam].ctrs;
@@ -158,7 +163,7 @@ impl Term {
             errs.push(FixMatchErr::NonExhaustiveMatch { typ: adt_nam.clone(), missing: ctr.clone() });
             Term::Err
           };
-          new_rules.push((Some(ctr.clone()), fields, body));
+          new_rules.push((Some(ctr.clone()), fields, body, None));
         }
         *arms = new_rules;
         return;
@@ -166,14 +171,35 @@ impl Term {
     }
 
     // First arm was not matching a constructor, irrefutable match, convert into a use term.
-    errs.push(FixMatchErr::IrrefutableMatch { var: arms[0].0.clone() });
-    let match_var = arms[0].0.take();
+    // The variable arms with guards before it become an `if` chain that falls through to it.
+    let Some(fst_idx) = arms.iter().position(|arm| arm.3.is_none()) else {
+      errs.push(FixMatchErr::NonExhaustiveGuards { typ: None, missing: None });
+      *self = Term::Err;
+      return;
+    };
+    errs.push(FixMatchErr::IrrefutableMatch { var: arms[fst_idx].0.clone() });
+    let has_guards = fst_idx != 0;
+    let uses_bnd = has_guards || arms[fst_idx].0.is_some();
     let arg = std::mem::take(arg);
     let with_bnd = std::mem::take(with_bnd);
     let with_arg = std::mem::take(with_arg);
 
     // Replaces `self` by its irrefutable arm
-    *self = std::mem::take(&mut arms[0].2);
+    let use_var = |var: &Option<Name>, term: Term| match var {
+      Some(var) => Term::Use {
+        nam: Some(var.clone()),
+        val: Box::new(Term::Var { nam: bnd.clone() }),
+        nxt: Box::new(term),
+      },
+      None => term,
+    };
+    let fst_var = arms[fst_idx].0.clone();
+    let fallback = use_var(&fst_var, std::mem::take(&mut arms[fst_idx].2));
+    *self = arms[..fst_idx].iter_mut().rfold(fallback, |els, (var, _, body, guard)| {
+      let guard = use_var(var, guard.take().unwrap());
+      let body = use_var(var, std::mem::take(body));
+      guarded_arm(guard, body, els)
+    });
 
     // `with` clause desugaring
     // Performs the same as `Term::linearize_match_with`.
@@ -181,16 +207,8 @@ impl Term {
     *self = Term::rfold_lams(std::mem::take(self), with_bnd.into_iter());
     *self = Term::call(std::mem::take(self), with_arg);
 
-    if let Some(var) = match_var {
-      *self = Term::Use {
-        nam: Some(bnd.clone()),
-        val: arg,
-        nxt: Box::new(Term::Use {
-          nam: Some(var),
-          val: Box::new(Term::Var { nam: bnd }),
-          nxt: Box::new(std::mem::take(self)),
-        }),
-      }
+    if uses_bnd {
+      *self = Term::Use { nam: Some(bnd), val: arg, nxt: Box::new(std::mem::take(self)) };
     }
   }
 }
@@ -210,15 +228,26 @@ fn fixed_match_arms<'a>(
   errs: &mut Vec<FixMatchErr>,
 ) -> HashMap<&'a Name, Option<Term>> {
   let mut bodies = HashMap::<&Name, Option<Term>>::from_iter(adt_ctrs.map(|ctr| (ctr, None)));
+  // The guarded arms of each constructor that are waiting for an arm to fall through to.
+  let mut guarded = HashMap::<&Name, Vec<(Term, Term)>>::new();
   for rule_idx in 0..rules.len() {
+    let guard = rules[rule_idx].3.take();
     // If Ctr arm, use the body of this rule for this constructor.
     if let Some(ctr_nam) = &rules[rule_idx].0 {
       if let Some(found_adt) = ctrs.get(ctr_nam) {
         if found_adt == adt_nam {
+          let ctr = *bodies.get_key_value(ctr_nam).unwrap().0;
           let body = bodies.get_mut(ctr_nam).unwrap();
           if body.is_none() {
-            // Use this rule for this constructor
-            *body = Some(rules[rule_idx].2.clone());
+            let new_body = rules[rule_idx].2.clone();
+            if let Some(guard) = guard {
+              // Use this rule only if the guard holds, otherwise fall through.
+              guarded.entry(ctr).or_default().push((guard, new_body));
+            } else {
+              // Use this rule for this constructor
+              let guarded = guarded.remove(ctr).unwrap_or_default();
+              *body = Some(fold_guarded_arms(guarded, new_body));
+            }
           } else {
             errs.push(FixMatchErr::RedundantArm { ctr: ctr_nam.clone() });
           }
@@ -235,17 +264,26 @@ fn fixed_match_arms<'a>(
     // Otherwise, Var arm, use the body of this rule for all non-covered constructors.
     for (ctr, body) in bodies.iter_mut() {
       if body.is_none() {
-        let mut new_body = rules[rule_idx].2.clone();
-        if let Some(var) = &rules[rule_idx].0 {
-          new_body = Term::Use {
+        let use_var = |term: Term| match &rules[rule_idx].0 {
+          Some(var) => Term::Use {
             nam: Some(var.clone()),
             val: Box::new(rebuild_ctr(bnd, ctr, &adts[adt_nam].ctrs[&**ctr])),
-            nxt: Box::new(new_body),
-          };
+            nxt: Box::new(term),
+          },
+          None => term,
+        };
+        let new_body = use_var(rules[rule_idx].2.clone());
+        if let Some(guard) = &guard {
+          guarded.entry(*ctr).or_default().push((use_var(guard.clone()), new_body));
+        } else {
+          let guarded = guarded.remove(*ctr).unwrap_or_default();
+          *body = Some(fold_guarded_arms(guarded, new_body));
         }
-        *body = Some(new_body);
       }
     }
+    if guard.is_some() {
+      continue;
+    }
     if rule_idx != rules.len() - 1 {
       errs.push(FixMatchErr::UnreachableMatchArms { var: rules[rule_idx].0.clone() });
       rules.truncate(rule_idx + 1);
@@ -253,9 +291,32 @@ fn fixed_match_arms<'a>(
     break;
   }
 
+  // Constructors that are only matched by arms with guards have nothing to fall through to.
+  for ctr in guarded.into_keys() {
+    errs.push(FixMatchErr::NonExhaustiveGuards { typ: Some(adt_nam.clone()), missing: Some(ctr.clone()) });
+    bodies.insert(ctr, Some(Term::Err));
+  }
+
   bodies
 }
 
+/// Chains the guarded arms of a constructor, falling through to `fallback` if none of the guards hold.
+fn fold_guarded_arms(guarded: Vec<(Term, Term)>, fallback: Term) -> Term {
+  guarded.into_iter().rfold(fallback, |els, (guard, body)| guarded_arm(guard, body, els))
+}
+
+/// Builds `if guard { body } else { els }` as an already fixed switch term.
+fn guarded_arm(guard: Term, body: Term, els: Term) -> Term {
+  Term::Swt {
+    arg: Box::new(guard),
+    bnd: None,
+    with_bnd: vec![],
+    with_arg: vec![],
+    pred: Some(Name::new("%guard-1")),
+    arms: vec![els, body],
+  }
+}
+
 fn match_field(arg: &Name, field: &Name) -> Name {
   Name::new(format!("{arg}.{field}"))
 }
@@ -276,6 +337,13 @@ impl std::fmt::Display for FixMatchErr {
       FixMatchErr::NonExhaustiveMatch { typ, missing } => {
         write!(f, "Non-exhaustive 'match' expression of type '{typ}'. Case '{missing}' not covered.")
       }
+      FixMatchErr::NonExhaustiveGuards { typ: Some(typ), missing: Some(missing) } => write!(
+        f,
+        "Non-exhaustive 'match' expression of type '{typ}'. Case '{missing}' is only covered by arms with guards."
+      ),
+      FixMatchErr::NonExhaustiveGuards { .. } => {
+        write!(f, "Non-exhaustive 'match' expression. All of its arms have guards.")
+      }
       FixMatchErr::IrrefutableMatch { var } => {
         writeln!(
           f,
@@ -1,10 +1,11 @@
 use crate::{
-  fun::{Book, Definition, Name, Pattern, Rule, Term},
+  fun::{Book, Definition, FanKind, Name, Pattern, Rule, Term},
   maybe_grow, multi_iterator,
 };
 use std::collections::{BTreeMap, HashSet};
 
 pub const NAME_SEP: &str = "__C";
+pub const COPY_SEP: &str = "__L";
 
 impl Book {
   /// Extracts combinator terms into new definitions.
@@ -30,24 +31,22 @@ impl Book {
   ///
   /// See [`Term::size`] for the measurement of size.
   /// It should more or less correspond to the compiled inet size.
+  ///
+  /// Lambdas passed as arguments that duplicate variables are always
+  /// extracted, see [`Term::float_copyable`].
   pub fn float_combinators(&mut self, max_size: usize) {
     let book = self.clone();
     let mut ctx = FloatCombinatorsCtx::new(&book, max_size);
 
     for (def_name, def) in self.defs.iter_mut() {
-      // Don't float combinators in the main entrypoint.
-      // This avoids making programs unexpectedly too lazy,
-      // returning just a reference without executing anything.
-      if let Some(main) = self.entrypoint.as_ref() {
-        if def_name == main {
-          continue;
-        }
-      }
-
       let builtin = def.is_builtin();
       let body = &mut def.rule_mut().body;
       ctx.reset();
       ctx.def_size = body.size();
+      // In the main entrypoint, only the lambdas that must be copyable are floated.
+      // Floating other combinators would make programs unexpectedly too lazy,
+      // returning just a reference without executing anything.
+      ctx.only_copyable = self.entrypoint.as_ref() == Some(def_name);
       body.float_combinators(&mut ctx, def_name, builtin);
     }
 
@@ -55,6 +54,14 @@ impl Book {
   }
 }
 
+impl Book {
+  /// Checks if a reference to the definition `nam` is safe, see [`Term::is_safe`].
+  pub fn is_safe_def(&self, nam: &Name) -> bool {
+    let mut ctx = FloatCombinatorsCtx::new(self, 0);
+    Term::Ref { nam: nam.clone() }.is_safe(&mut ctx)
+  }
+}
+
 struct FloatCombinatorsCtx<'b> {
   pub combinators: BTreeMap<Name, (bool, Definition)>,
   pub name_gen: usize,
@@ -62,6 +69,8 @@ struct FloatCombinatorsCtx<'b> {
   pub book: &'b Book,
   pub max_size: usize,
   pub def_size: usize,
+  /// Only float the lambdas that must be copyable.
+  pub only_copyable: bool,
 }
 
 impl<'b> FloatCombinatorsCtx<'b> {
@@ -73,6 +82,7 @@ impl<'b> FloatCombinatorsCtx<'b> {
       book,
       max_size,
       def_size: 0,
+      only_copyable: false,
     }
   }
 
@@ -91,11 +101,38 @@ impl Term {
         child.float_combinators(ctx, def_name, builtin);
       }
 
-      let mut size = self.size();
-      let is_combinator = self.is_combinator();
+      // In `main` only the copyable lambdas are floated, so the sizes are not needed.
+      let (mut size, is_combinator) =
+        if ctx.only_copyable { (0, false) } else { (self.size(), self.is_combinator()) };
+      // The children of a lambda are the ones of its body, see `float_children_mut`.
+      let mut bod = &*self;
+      while let Term::Lam { bod: inner, .. } = bod {
+        bod = inner;
+      }
+      // Encoded matches apply the matched variable to the arms, which are not copied by constructors.
+      let mut head = bod;
+      while let Term::App { fun, .. } = head {
+        head = fun;
+      }
+      let is_app = matches!(bod, Term::App { .. }) && !matches!(head, Term::Var { .. });
 
       // Float unsafe children and children that make the term too big.
-      for child in self.float_children_mut() {
+      let mut children = self.float_children_mut().peekable();
+      while let Some(child) = children.next() {
+        // The last child of an application is its head, the others are the arguments.
+        let is_arg = is_app && children.peek().is_some();
+        if is_arg && child.must_be_copyable(ctx) {
+          if !ctx.only_copyable {
+            ctx.def_size -= child.size();
+            size -= child.size();
+          }
+          child.float_copyable(ctx, def_name, builtin);
+          continue;
+        }
+        if ctx.only_copyable {
+          continue;
+        }
+
         let child_is_safe = child.is_safe(ctx);
         let child_size = child.size();
 
@@ -122,6 +159,42 @@ impl Term {
     let rule = Definition::new_gen(comb_name.clone(), rules, builtin);
     ctx.combinators.insert(comb_name, (is_safe, rule));
   }
+
+  /// Floats a lambda passed as an argument, which may end up being copied.
+  ///
+  /// HVM refuses to copy references to definitions that contain duplications,
+  /// and copying the lambda inline would mix up its duplications with the ones of the copies.
+  /// So the lambda is extracted into a definition, and replaced by a reference to a wrapper
+  /// that only calls it, which is copied instead.
+  fn float_copyable(&mut self, ctx: &mut FloatCombinatorsCtx, def_name: &Name, builtin: bool) {
+    self.float(ctx, def_name, builtin, false);
+
+    let wrapper_name = Name::new(format!("{}{}{}", def_name, COPY_SEP, ctx.name_gen));
+    ctx.name_gen += 1;
+
+    let arg = Name::new("x");
+    let lambda = std::mem::replace(self, Term::Ref { nam: wrapper_name.clone() });
+    let body = Term::lam(Pattern::Var(Some(arg.clone())), Term::arg_call(lambda, arg));
+
+    let rules = vec![Rule { body, pats: Vec::new() }];
+    let rule = Definition::new_gen(wrapper_name.clone(), rules, builtin);
+    ctx.combinators.insert(wrapper_name, (true, rule));
+  }
+
+  /// Whether the term is a lambda that can be floated and may not be copied correctly inline.
+  fn must_be_copyable(&self, ctx: &mut FloatCombinatorsCtx) -> bool {
+    matches!(self, Term::Lam { .. }) && self.is_combinator() && self.has_dup(ctx)
+  }
+
+  /// Whether the term may have duplication nodes when reduced,
+  /// either its own or from the unsafe definitions it references.
+  fn has_dup(&self, ctx: &mut FloatCombinatorsCtx) -> bool {
+    maybe_grow(|| match self {
+      Term::Fan { fan: FanKind::Dup, .. } => true,
+      Term::Ref { .. } => !self.is_safe(ctx),
+      _ => self.pattern().is_some_and(Pattern::has_dup) || self.children().any(|child| child.has_dup(ctx)),
+    })
+  }
 }
 
 impl Term {
@@ -160,6 +233,8 @@ impl Term {
           ref_safe
         } else if let Some((safe, _)) = ctx.combinators.get(nam) {
           *safe
+        } else if let Some(def) = ctx.book.hvm_defs.get(nam) {
+          def.safe
         } else {
           false
         };
@@ -195,7 +270,7 @@ impl Term {
     declared.difference(&used).count() != 0 || used.difference(&declared).count() != 0
   }
 
-  fn is_combinator(&self) -> bool {
+  pub fn is_combinator(&self) -> bool {
     self.free_vars().is_empty() && !self.has_unscoped_diff() && !matches!(self, Term::Ref { .. })
   }
 
@@ -227,7 +302,7 @@ impl Term {
     }
   }
 
-  fn size(&self) -> usize {
+  pub fn size(&self) -> usize {
     maybe_grow(|| {
       let children_size: usize = self.children().map(|c| c.size()).sum();
       self.base_size() + children_size
@@ -281,13 +356,24 @@ impl Term {
 }
 
 impl Pattern {
+  fn has_dup(&self) -> bool {
+    matches!(self, Pattern::Fan(FanKind::Dup, ..)) || self.children().any(Pattern::has_dup)
+  }
+
   fn size(&self) -> usize {
     match self {
       Pattern::Var(_) => 0,
       Pattern::Chn(_) => 0,
       Pattern::Fan(_, _, pats) => pats.len() - 1 + pats.iter().map(|p| p.size()).sum::<usize>(),
 
-      Pattern::Num(_) | Pattern::Lst(_) | Pattern::Str(_) | Pattern::Ctr(_, _) => unreachable!(),
+      Pattern::Num(_)
+      | Pattern::Range(..)
+      | Pattern::Lst(_)
+      | Pattern::Str(_)
+      | Pattern::Ctr(_, _)
+      | Pattern::Or(_) => {
+        unreachable!()
+      }
     }
   }
 }
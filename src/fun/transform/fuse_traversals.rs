@@ -0,0 +1,347 @@
+use crate::{
+  fun::{
+    transform::desugar_bend::RECURSIVE_KW, Adt, Adts, Book, Constructors, MatchRule, Name, Num, Op, Pattern,
+    Source, Tag, Term,
+  },
+  maybe_grow,
+};
+use std::collections::HashSet;
+
+const MAP: &str = "List/map";
+const FILTER: &str = "List/filter";
+const FOLD_LEFT: &str = "List/fold_left";
+const FOLD_RIGHT: &str = "List/fold_right";
+const SUM: &str = "List/sum";
+
+impl Book {
+  /// Fuses pairs of traversals where one consumes the structure produced by the other,
+  /// so that the intermediate structure is never built.
+  ///
+  /// A `fold` over the result of a `bend` becomes a single `bend` that, instead of
+  /// creating each constructor, applies the `fold` arm of that constructor to its fields.
+  /// This is only done when every value returned by the `bend` is either a constructor of
+  /// the folded type or a `fork` call, and `fork` isn't used anywhere else,
+  /// since the recursive calls of the fused `bend` already return folded values.
+  /// ```bend
+  /// fold t = (bend x = 0 { when (< x 3): (Tree/Node (fork (+ x 1)) (fork (+ x 1))); else: (Tree/Leaf x) }) {
+  ///   Tree/Node: (+ t.left t.right)
+  ///   Tree/Leaf: t.value
+  /// }
+  /// // Becomes
+  /// bend x = 0 {
+  ///   when (< x 3): let t.left = (fork (+ x 1)); let t.right = (fork (+ x 1)); (+ t.left t.right)
+  ///   else: let t.value = x; t.value
+  /// }
+  /// ```
+  ///
+  /// The builtin list functions are fused when applied to the result of `List/map`:
+  /// ```bend
+  /// (List/map (List/map xs g) f)          => (List/map xs λx (f (g x)))
+  /// (List/fold_left (List/map xs g) a f)  => (List/fold_left xs a λacc λx (f acc (g x)))
+  /// (List/fold_right (List/map xs g) a f) => (List/fold_right xs a λx (f (g x)))
+  /// (List/sum (List/map xs g))            => (List/fold_right xs 0 λx λacc (+ (g x) acc))
+  /// ```
+  ///
+  /// The fused functions use each element only once, since the runtime can't copy a reference
+  /// to a definition that duplicates values, and the list functions copy the function they receive.
+  ///
+  /// `List/map` applied to the result of `List/filter` needs each element twice, so it becomes a
+  /// `fold` instead, which is later turned into a definition of its own:
+  /// ```bend
+  /// (List/map (List/filter xs p) f)
+  /// // Becomes
+  /// let p = p; let f = f; fold xs {
+  ///   List/Nil: List/Nil
+  ///   List/Cons: if (p xs.head) { (List/Cons (f xs.head) xs.tail) } else { xs.tail }
+  /// }
+  /// ```
+  ///
+  /// Terms with unscoped variables are never fused, since that could change which values they bind.
+  ///
+  /// Must be run after `fix_match_terms` and before `desugar_bend` and `desugar_fold`.
+  pub fn fuse_traversals(&mut self) {
+    // Only the list functions that weren't redefined by the user keep their known behaviour.
+    let list_fns = [MAP, FILTER, FOLD_LEFT, FOLD_RIGHT, SUM]
+      .into_iter()
+      .map(Name::new)
+      .filter(|nam| {
+        let source = self.defs.get(nam).map(|def| &def.source);
+        // They may have been taken from the compilation cache.
+        let source = source.or_else(|| self.hvm_defs.get(nam).map(|def| &def.source));
+        source.is_some_and(Source::is_builtin)
+      })
+      .collect::<HashSet<_>>();
+
+    for def in self.defs.values_mut() {
+      for rule in def.rules.iter_mut() {
+        rule.body.fuse_traversals(&self.ctrs, &self.adts, &list_fns);
+      }
+    }
+  }
+}
+
+impl Term {
+  fn fuse_traversals(&mut self, ctrs: &Constructors, adts: &Adts, list_fns: &HashSet<Name>) {
+    maybe_grow(|| {
+      for child in self.children_mut() {
+        child.fuse_traversals(ctrs, adts, list_fns);
+      }
+
+      if let Some(fused) = self.fuse_fold_of_bend(ctrs, adts) {
+        *self = fused;
+      }
+      while let Some(fused) = self.fuse_list_calls(list_fns) {
+        *self = fused;
+      }
+    })
+  }
+
+  /// Fuses `fold (bend ...) { ... }` and `let x = bend ...; fold x { ... }`.
+  fn fuse_fold_of_bend(&self, ctrs: &Constructors, adts: &Adts) -> Option<Term> {
+    let (bend, fold) = match self {
+      Term::Fold { arg, .. } => (arg.as_ref(), self),
+      Term::Let { pat, val, nxt } => {
+        let (Pattern::Var(Some(nam)), Term::Fold { arg, with_arg, arms, .. }) = (pat.as_ref(), nxt.as_ref())
+        else {
+          return None;
+        };
+        let is_var = matches!(arg.as_ref(), Term::Var { nam: arg } if arg == nam);
+        let used_again =
+          with_arg.iter().chain(arms.iter().map(|arm| &arm.2)).any(|t| t.free_vars().contains_key(nam));
+        if !is_var || used_again {
+          return None;
+        }
+        (val.as_ref(), nxt.as_ref())
+      }
+      _ => return None,
+    };
+    let Term::Bend { bnd, arg, cond, step, base } = bend else { return None };
+    let Term::Fold { with_bnd, with_arg, arms, .. } = fold else { return None };
+
+    let adt = &adts[ctrs.get(arms.first()?.0.as_ref()?)?];
+    if bend.has_unscoped_vars() || arms.iter().any(|arm| arm.2.has_unscoped_vars()) {
+      return None;
+    }
+
+    // The arms are moved inside the bend, so none of its binds can capture their free variables.
+    let fork = Name::new(RECURSIVE_KW);
+    let mut bend_binds = bnd.iter().flatten().cloned().collect::<HashSet<_>>();
+    for term in [cond, step, base] {
+      term.collect_binds(&mut bend_binds);
+    }
+    if bend_binds.contains(&fork) {
+      return None;
+    }
+    bend_binds.insert(fork.clone());
+    for (_, fields, body, _) in arms {
+      let mut free_vars = body.free_vars();
+      for nam in fields.iter().chain(with_bnd.iter()).flatten() {
+        free_vars.shift_remove(nam);
+      }
+      if free_vars.keys().any(|nam| bend_binds.contains(nam)) {
+        return None;
+      }
+    }
+
+    let mut fuser = BendFuser { adt, arms, with_bnd, fork: &fork, forks: 0 };
+    let mut step = step.as_ref().clone();
+    let mut base = base.as_ref().clone();
+    fuser.fuse(&mut step)?;
+    fuser.fuse(&mut base)?;
+
+    // Since the recursive calls now return folded values, `fork` can't be used anywhere else.
+    let forks = [cond, &step, &base].iter().map(|term| term.var_uses(&fork)).sum();
+    if fuser.forks != forks {
+      return None;
+    }
+
+    let bend = Term::Bend {
+      bnd: bnd.clone(),
+      arg: arg.clone(),
+      cond: cond.clone(),
+      step: Box::new(step),
+      base: Box::new(base),
+    };
+    Some(Term::call(bend, with_arg.iter().cloned()))
+  }
+
+  /// Fuses a call to a builtin list function applied to the result of another one.
+  fn fuse_list_calls(&self, list_fns: &HashSet<Name>) -> Option<Term> {
+    let x = || Term::Var { nam: Name::new("%x") };
+    let acc = || Term::Var { nam: Name::new("%acc") };
+    let lam = |nam: &str, bod: Term| Term::lam(Pattern::Var(Some(Name::new(nam))), bod);
+    let list_call = |fun: &str, args: Vec<Term>| Term::call(Term::r#ref(fun), args);
+    let no_unscoped = |funs: &[&Term]| funs.iter().all(|fun| !fun.has_unscoped_vars());
+
+    if let Some([list, f]) = list_fn_call(self, MAP, list_fns) {
+      if let Some([xs, p]) = list_fn_call(&list, FILTER, list_fns) {
+        no_unscoped(&[&f, &p]).then_some(())?;
+        return Some(map_filter_fold(xs, p, f));
+      }
+      let [xs, g] = list_fn_call(&list, MAP, list_fns)?;
+      no_unscoped(&[&f, &g]).then_some(())?;
+      let fun = lam("%x", Term::app(f, Term::app(g, x())));
+      Some(list_call(MAP, vec![xs, fun]))
+    } else if let Some([list, init, f]) = list_fn_call(self, FOLD_LEFT, list_fns) {
+      let [xs, g] = list_fn_call(&list, MAP, list_fns)?;
+      no_unscoped(&[&f, &g]).then_some(())?;
+      let fun = lam("%acc", lam("%x", Term::call(f, [acc(), Term::app(g, x())])));
+      Some(list_call(FOLD_LEFT, vec![xs, init, fun]))
+    } else if let Some([list, init, f]) = list_fn_call(self, FOLD_RIGHT, list_fns) {
+      let [xs, g] = list_fn_call(&list, MAP, list_fns)?;
+      no_unscoped(&[&f, &g]).then_some(())?;
+      let fun = lam("%x", Term::app(f, Term::app(g, x())));
+      Some(list_call(FOLD_RIGHT, vec![xs, init, fun]))
+    } else if let Some([list]) = list_fn_call(self, SUM, list_fns) {
+      let [xs, g] = list_fn_call(&list, MAP, list_fns)?;
+      (no_unscoped(&[&g]) && list_fns.contains(&Name::new(FOLD_RIGHT))).then_some(())?;
+      let add = Term::Oper { opr: Op::ADD, fst: Box::new(Term::app(g, x())), snd: Box::new(acc()) };
+      let fun = lam("%x", lam("%acc", add));
+      Some(list_call(FOLD_RIGHT, vec![xs, Term::Num { val: Num::U24(0) }, fun]))
+    } else {
+      None
+    }
+  }
+
+  /// Splits a chain of static applications into the applied term and its arguments.
+  fn uncall(self) -> (Term, Vec<Term>) {
+    let mut args = vec![];
+    let mut term = self;
+    while let Term::App { tag: Tag::Static, fun, arg } = &mut term {
+      args.push(std::mem::take(arg.as_mut()));
+      term = std::mem::take(fun.as_mut());
+    }
+    args.reverse();
+    (term, args)
+  }
+
+  /// Counts the occurrences of a variable, including the shadowed ones.
+  fn var_uses(&self, nam: &Name) -> u64 {
+    maybe_grow(|| {
+      let uses = matches!(self, Term::Var { nam: var } if var == nam) as u64;
+      uses + self.children().map(|child| child.var_uses(nam)).sum::<u64>()
+    })
+  }
+
+  /// Collects the names of all the variables bound inside this term.
+  fn collect_binds(&self, binds: &mut HashSet<Name>) {
+    maybe_grow(|| {
+      for (child, child_binds) in self.children_with_binds() {
+        binds.extend(child_binds.flatten().cloned());
+        child.collect_binds(binds);
+      }
+    })
+  }
+}
+
+/// Builds the `fold` that maps `f` over the elements of `xs` that satisfy `p`.
+fn map_filter_fold(xs: Term, p: Term, f: Term) -> Term {
+  let var = |nam: &str| Term::Var { nam: Name::new(nam) };
+  let cons = |head: Term, tail: Term| Term::call(Term::r#ref("List/Cons"), [head, tail]);
+  let bind = |nam: &str, val: Term, nxt: Term| Term::Let {
+    pat: Box::new(Pattern::Var(Some(Name::new(nam)))),
+    val: Box::new(val),
+    nxt: Box::new(nxt),
+  };
+
+  let cons_arm = Term::Swt {
+    bnd: Some(Name::new("%cond")),
+    arg: Box::new(Term::app(var("%p"), var("%xs.head"))),
+    with_bnd: vec![],
+    with_arg: vec![],
+    pred: Some(Name::new("%cond-1")),
+    arms: vec![var("%xs.tail"), cons(Term::app(var("%f"), var("%xs.head")), var("%xs.tail"))],
+  };
+  let fold = Term::Fold {
+    bnd: Some(Name::new("%xs")),
+    arg: Box::new(xs),
+    with_bnd: vec![],
+    with_arg: vec![],
+    arms: vec![
+      (Some(Name::new("List/Nil")), vec![], Term::r#ref("List/Nil"), None),
+      (
+        Some(Name::new("List/Cons")),
+        vec![Some(Name::new("%xs.head")), Some(Name::new("%xs.tail"))],
+        cons_arm,
+        None,
+      ),
+    ],
+  };
+  // The function arguments are bound outside the fold, so that they're passed along instead of recomputed.
+  bind("%p", p, bind("%f", f, fold))
+}
+
+/// Returns the arguments of `term` if it's a call to the builtin list function `fun` with exactly `N` arguments.
+fn list_fn_call<const N: usize>(term: &Term, fun: &str, list_fns: &HashSet<Name>) -> Option<[Term; N]> {
+  let mut args = vec![];
+  let mut head = term;
+  while let Term::App { tag: Tag::Static, fun, arg } = head {
+    args.push(arg.as_ref());
+    head = fun;
+  }
+  match head {
+    Term::Ref { nam } if nam == fun && list_fns.contains(nam) && args.len() == N => {
+      args.into_iter().rev().cloned().collect::<Vec<_>>().try_into().ok()
+    }
+    _ => None,
+  }
+}
+
+/// Replaces the constructors returned by a `bend` with the arms of the `fold` that consumes it.
+struct BendFuser<'a> {
+  adt: &'a Adt,
+  arms: &'a [MatchRule],
+  with_bnd: &'a [Option<Name>],
+  fork: &'a Name,
+  /// The number of `fork` calls found in return positions.
+  forks: u64,
+}
+
+impl BendFuser<'_> {
+  /// Fuses the values that `term` can return, failing if any of them isn't a constructor or `fork` call.
+  fn fuse(&mut self, term: &mut Term) -> Option<()> {
+    maybe_grow(|| match term {
+      Term::Let { nxt, .. } | Term::Use { nxt, .. } => self.fuse(nxt),
+      Term::Mat { arms, .. } => arms.iter_mut().try_for_each(|arm| self.fuse(&mut arm.2)),
+      Term::Swt { arms, .. } => arms.iter_mut().try_for_each(|arm| self.fuse(arm)),
+      _ => {
+        let (head, mut args) = std::mem::take(term).uncall();
+        match &head {
+          Term::Var { nam } if nam == self.fork => {
+            self.forks += 1;
+            *term = Term::call(head, args);
+            Some(())
+          }
+          Term::Ref { nam } if self.adt.ctrs.contains_key(nam) => {
+            let fields = &self.adt.ctrs[nam];
+            let (_, binds, body, _) = self.arms.iter().find(|arm| arm.0.as_ref() == Some(nam))?;
+            if args.len() != fields.len() {
+              return None;
+            }
+            // The fields are bound one after the other, so they can't capture the variables of the next ones.
+            let captures = args.iter().any(|arg| {
+              let free_vars = arg.free_vars();
+              binds.iter().flatten().any(|bind| free_vars.contains_key(bind))
+            });
+            if captures {
+              return None;
+            }
+            for (arg, field) in args.iter_mut().zip(fields) {
+              if field.rec {
+                self.fuse(arg)?;
+              }
+            }
+            let body = Term::rfold_lams(body.clone(), self.with_bnd.iter().cloned());
+            *term = binds.iter().zip(args).rfold(body, |nxt, (bind, val)| Term::Let {
+              pat: Box::new(Pattern::Var(bind.clone())),
+              val: Box::new(val),
+              nxt: Box::new(nxt),
+            });
+            Some(())
+          }
+          _ => None,
+        }
+      }
+    })
+  }
+}
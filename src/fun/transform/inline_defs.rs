@@ -0,0 +1,262 @@
+use crate::{
+  diagnostics::Diagnostics,
+  fun::{Book, Ctx, Definition, FanKind, Inline, Name, Pattern, Source, Tag, Term},
+  maybe_grow,
+};
+use indexmap::{IndexMap, IndexSet};
+use std::collections::{HashMap, HashSet};
+
+/// The maximum size, as measured by [`Term::size`], of the definitions that are inlined automatically.
+pub const MAX_INLINE_SIZE: usize = 8;
+
+type Deps = IndexMap<Name, IndexSet<Name>>;
+
+impl Ctx<'_> {
+  /// Replaces the calls to small non-recursive definitions by their bodies.
+  ///
+  /// A call is a reference applied to at least one argument. Since the runtime
+  /// expands these references as soon as they're reached, inlining them saves
+  /// that expansion without making the program any more eager.
+  /// The lambdas of the inlined body that receive an argument become `let`s.
+  ///
+  /// Definitions annotated with `@inline` are inlined regardless of their size,
+  /// and the ones annotated with `@noinline` are never inlined.
+  /// Recursive definitions, constructors and definitions with unscoped variables are not inlined.
+  ///
+  /// Nothing is inlined in a definition that doesn't duplicate any value if it would start doing it,
+  /// since the runtime can only copy references to definitions without duplications.
+  ///
+  /// User-defined functions are only inlined if `prune` is set,
+  /// since otherwise their definitions would be reported as unused.
+  ///
+  /// Nothing is inlined in the entrypoint, since it's not affected by `float_combinators`,
+  /// which keeps the inlined terms as lazy as they were in their own definitions.
+  ///
+  /// Must be run after `linearize_match_with` and before `encode_matches`.
+  pub fn inline_defs(&mut self, prune: bool) -> Result<(), Diagnostics> {
+    self.info.start_pass();
+
+    let deps =
+      self.book.defs.values().map(|def| (def.name.clone(), def.rule().body.refs(self.book))).collect();
+    let recursive = recursive_defs(&deps);
+
+    for def in self.book.defs.values() {
+      if def.inline == Inline::Always {
+        if recursive.contains(&def.name) {
+          self
+            .info
+            .add_rule_error("Definition is annotated with '@inline', but it's recursive.", def.name.clone());
+        } else if def.rule().body.has_unscoped_vars() {
+          let msg = "Definition is annotated with '@inline', but it has unscoped variables.";
+          self.info.add_rule_error(msg, def.name.clone());
+        }
+      }
+    }
+
+    // Inline the definitions in dependency order, so that the inlined bodies already have their own calls inlined.
+    let mut order = vec![];
+    let mut visited = HashSet::new();
+    for nam in deps.keys() {
+      visit_deps(nam, &deps, &recursive, &mut visited, &mut order);
+    }
+
+    let mut inlinable = HashMap::new();
+    for nam in order {
+      let is_entrypoint = self.book.entrypoint.as_ref() == Some(&nam);
+      let def = self.book.defs.get_mut(&nam).unwrap();
+      let body = &mut def.rule_mut().body;
+      let original = body.clone();
+      if !is_entrypoint && body.inline_calls(&inlinable) {
+        body.make_var_names_unique();
+        body.reduce_inlined_calls();
+        if body.has_dups() && !original.has_dups() {
+          *body = original;
+        }
+      }
+
+      let def = &self.book.defs[&nam];
+      if !is_entrypoint && !recursive.contains(&nam) && self.book.can_inline(def, prune) {
+        inlinable.insert(nam, def.rule().body.clone());
+      }
+    }
+
+    self.info.fatal(())
+  }
+}
+
+impl Book {
+  fn can_inline(&self, def: &Definition, prune: bool) -> bool {
+    let body = &def.rule().body;
+    let wont_be_unused =
+      prune || def.is_builtin() || def.source == Source::Generated || def.name.is_generated();
+    let small = def.inline == Inline::Always || def.inline == Inline::Auto && body.size() <= MAX_INLINE_SIZE;
+    small && wont_be_unused && !self.ctrs.contains_key(&def.name) && !body.has_unscoped_vars()
+  }
+}
+
+impl Term {
+  /// Replaces the heads of the applications that reference an inlinable definition by its body.
+  /// Returns whether anything was inlined.
+  fn inline_calls(&mut self, inlinable: &HashMap<Name, Term>) -> bool {
+    maybe_grow(|| {
+      let mut inlined = false;
+      for child in self.children_mut() {
+        inlined |= child.inline_calls(inlinable);
+      }
+      if let Term::App { tag: Tag::Static, fun, .. } = self {
+        if let Term::Ref { nam } = fun.as_ref() {
+          if let Some(body) = inlinable.get(nam) {
+            **fun = body.clone();
+            inlined = true;
+          }
+        }
+      }
+      inlined
+    })
+  }
+
+  /// Turns the lambdas applied to an argument into `let`s.
+  ///
+  /// Precondition: Variables must have unique names, so that moving
+  /// the arguments inside the `let`s doesn't capture any of them.
+  fn reduce_inlined_calls(&mut self) {
+    maybe_grow(|| {
+      for child in self.children_mut() {
+        child.reduce_inlined_calls();
+      }
+      self.reduce_app();
+    })
+  }
+
+  fn reduce_app(&mut self) {
+    let Term::App { tag: Tag::Static, fun, arg } = self else { return };
+    match fun.as_mut() {
+      Term::Lam { tag: Tag::Static, pat, bod } => {
+        if let Pattern::Var(nam) = pat.as_mut() {
+          let pat = Box::new(Pattern::Var(nam.take()));
+          *self = Term::Let { pat, val: std::mem::take(arg), nxt: std::mem::take(bod) };
+        }
+      }
+      // `(let x = v; f) a` is `let x = v; (f a)`
+      Term::Let { .. } => {
+        let arg = std::mem::take(arg);
+        let Term::Let { nxt, .. } = fun.as_mut() else { unreachable!() };
+        let applied = std::mem::take(nxt.as_mut());
+        **nxt = Term::App { tag: Tag::Static, fun: Box::new(applied), arg };
+        nxt.reduce_app();
+        *self = std::mem::take(fun.as_mut());
+      }
+      _ => {}
+    }
+  }
+
+  /// Checks if the compiled term would duplicate any value.
+  pub fn has_dups(&self) -> bool {
+    fn go(term: &Term, uses: &mut HashSet<Name>) -> bool {
+      maybe_grow(|| {
+        let is_dup = match term {
+          Term::Var { nam } => !uses.insert(nam.clone()),
+          Term::Fan { fan: FanKind::Dup, .. } => true,
+          _ => term.pattern().is_some_and(|pat| matches!(pat, Pattern::Fan(FanKind::Dup, ..))),
+        };
+        is_dup || term.children().any(|child| go(child, uses))
+      })
+    }
+    let mut term = self.clone();
+    term.make_var_names_unique();
+    go(&term, &mut HashSet::new())
+  }
+
+  /// The definitions referenced by this term.
+  fn refs(&self, book: &Book) -> IndexSet<Name> {
+    fn go(term: &Term, book: &Book, refs: &mut IndexSet<Name>) {
+      maybe_grow(|| {
+        if let Term::Ref { nam } = term {
+          if book.defs.contains_key(nam) {
+            refs.insert(nam.clone());
+          }
+        }
+        for child in term.children() {
+          go(child, book, refs);
+        }
+      })
+    }
+    let mut refs = IndexSet::new();
+    go(self, book, &mut refs);
+    refs
+  }
+}
+
+/// Orders the definitions so that each one comes after the non-recursive definitions it references.
+fn visit_deps(
+  nam: &Name,
+  deps: &Deps,
+  recursive: &HashSet<Name>,
+  visited: &mut HashSet<Name>,
+  order: &mut Vec<Name>,
+) {
+  maybe_grow(|| {
+    if !visited.insert(nam.clone()) {
+      return;
+    }
+    for dep in &deps[nam] {
+      if !recursive.contains(dep) {
+        visit_deps(dep, deps, recursive, visited, order);
+      }
+    }
+    order.push(nam.clone());
+  })
+}
+
+/// Finds the definitions that are part of a reference cycle, using Tarjan's strongly connected components algorithm.
+fn recursive_defs(deps: &Deps) -> HashSet<Name> {
+  #[derive(Default)]
+  struct State {
+    index: HashMap<Name, usize>,
+    low_link: HashMap<Name, usize>,
+    stack: Vec<Name>,
+    on_stack: HashSet<Name>,
+    recursive: HashSet<Name>,
+  }
+
+  fn visit(nam: &Name, deps: &Deps, state: &mut State) {
+    maybe_grow(|| {
+      let index = state.index.len();
+      state.index.insert(nam.clone(), index);
+      state.low_link.insert(nam.clone(), index);
+      state.stack.push(nam.clone());
+      state.on_stack.insert(nam.clone());
+
+      for dep in &deps[nam] {
+        if !state.index.contains_key(dep) {
+          visit(dep, deps, state);
+          let low_link = state.low_link[nam].min(state.low_link[dep]);
+          state.low_link.insert(nam.clone(), low_link);
+        } else if state.on_stack.contains(dep) {
+          let low_link = state.low_link[nam].min(state.index[dep]);
+          state.low_link.insert(nam.clone(), low_link);
+        }
+      }
+
+      // `nam` is the root of a strongly connected component.
+      if state.low_link[nam] == index {
+        let start = state.stack.iter().rposition(|n| n == nam).unwrap();
+        let component = state.stack.split_off(start);
+        for n in &component {
+          state.on_stack.remove(n);
+        }
+        if component.len() > 1 || deps[nam].contains(nam) {
+          state.recursive.extend(component);
+        }
+      }
+    })
+  }
+
+  let mut state = State::default();
+  for nam in deps.keys() {
+    if !state.index.contains_key(nam) {
+      visit(nam, deps, &mut state);
+    }
+  }
+  state.recursive
+}
@@ -0,0 +1,309 @@
+use super::{expand_generated::book_def_deps, linearize_matches::lift_match_vars};
+use crate::{
+  fun::{Book, Name, Pattern, Term},
+  maybe_grow,
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+impl Book {
+  /// Rewrites recursive references in strict positions so that the
+  /// `float_combinators` pass can turn them into lazy references.
+  ///
+  /// A reference is recursive when the definition it points to also reaches the current one.
+  /// It's strict when it's expanded as soon as the definition is,
+  /// like when it's called (`(Foo x)`) or bound to a variable that's not used linearly
+  /// (`let x = Foo; (x x)`).
+  ///
+  /// `let x = Foo; nxt` becomes `nxt[x := Foo]`, since references can be freely duplicated.
+  /// A reference has no free variables, so the substitution can't capture any.
+  ///
+  /// The strict recursive references that can't be extracted into a lazy
+  /// definition are recorded in `lazy_recursion_notes` with the reason why,
+  /// to explain the recursion cycles found after compiling to HVM.
+  /// `linearize_matches` tells if the variables used by match arms will be linearized,
+  /// turning the arms into combinators.
+  ///
+  /// Precondition: Variables still have the names given by the user, so that the notes show them.
+  pub fn make_recursion_lazy(&mut self, linearize_matches: bool) {
+    let peers = recursive_peers(self);
+
+    for (def_name, def) in self.defs.iter_mut() {
+      // The main entrypoint is not floated, so it's never made lazy.
+      if self.entrypoint.as_ref() == Some(def_name) || def.is_builtin() {
+        continue;
+      }
+      let Some(peers) = peers.get(def_name).filter(|peers| !peers.is_empty()) else { continue };
+
+      let mut notes = Vec::new();
+      for rule in def.rules.iter_mut() {
+        rule.body.make_recursion_lazy(peers);
+        rule.body.explain_strict_recursion(def_name, peers, linearize_matches, true, None, &mut notes);
+      }
+      if !notes.is_empty() {
+        self.lazy_recursion_notes.insert(def_name.clone(), notes);
+      }
+    }
+  }
+
+  /// Linearizes the variables used by the arms of the matches that contain a recursive reference,
+  /// so that `float_combinators` can extract the arms and make the references lazy.
+  ///
+  /// Used with `linearize_match_binds`, which doesn't linearize the arms by itself.
+  ///
+  /// Precondition: Variables have unique names.
+  pub fn linearize_recursive_matches(&mut self) {
+    let peers = recursive_peers(self);
+
+    for (def_name, def) in self.defs.iter_mut() {
+      let Some(peers) = peers.get(def_name).filter(|peers| !peers.is_empty()) else { continue };
+      for rule in def.rules.iter_mut() {
+        rule.body.linearize_recursive_matches(peers);
+      }
+    }
+  }
+}
+
+impl Term {
+  fn make_recursion_lazy(&mut self, peers: &BTreeSet<Name>) {
+    maybe_grow(|| {
+      for child in self.children_mut() {
+        child.make_recursion_lazy(peers);
+      }
+
+      match self {
+        Term::Let { pat, val, nxt } if matches!(pat.as_ref(), Pattern::Var(_)) && val.is_ref_to(peers) => {
+          let Pattern::Var(bnd) = pat.as_ref() else { unreachable!() };
+          let mut nxt = std::mem::take(nxt.as_mut());
+          if let Some(bnd) = bnd {
+            nxt.subst(bnd, val);
+          }
+          *self = nxt;
+        }
+        Term::Use { nam, val, nxt } if val.is_ref_to(peers) => {
+          let mut nxt = std::mem::take(nxt.as_mut());
+          if let Some(nam) = nam {
+            nxt.subst(nam, val);
+          }
+          *self = nxt;
+        }
+        _ => {}
+      }
+    })
+  }
+
+  fn linearize_recursive_matches(&mut self, peers: &BTreeSet<Name>) {
+    maybe_grow(|| {
+      for child in self.children_mut() {
+        child.linearize_recursive_matches(peers);
+      }
+
+      let recursive_arms = match self {
+        Term::Mat { arms, .. } => arms.iter().any(|arm| arm.2.has_ref_to(peers)),
+        Term::Swt { arms, .. } => arms.iter().any(|arm| arm.has_ref_to(peers)),
+        _ => false,
+      };
+      if recursive_arms {
+        lift_match_vars(self);
+      }
+    })
+  }
+
+  /// Records why each strict recursive reference in the term won't be made lazy by `float_combinators`.
+  ///
+  /// A reference is left alone when it's inside a term that will be floated into its own
+  /// definition (a combinator that the pass visits) and that term ends up in a lazy position.
+  /// Otherwise, the reason is given by the innermost lambda, match arm or argument around the reference.
+  fn explain_strict_recursion(
+    &self,
+    def_name: &Name,
+    peers: &BTreeSet<Name>,
+    linearize_matches: bool,
+    strict: bool,
+    enclosing: Option<&Enclosing>,
+    notes: &mut Vec<String>,
+  ) {
+    maybe_grow(|| {
+      if let Term::Ref { nam } = self {
+        if strict && peers.contains(nam) {
+          let note = explain(nam, def_name, enclosing);
+          if !notes.contains(&note) {
+            notes.push(note);
+          }
+        }
+        return;
+      }
+
+      for child in self.strictness_children(strict) {
+        let (term, strict) = (child.term, child.strict);
+        // The variables that a match arm uses from outside of it, unless they will be linearized.
+        let arm_captured = match &child.arm_binds {
+          Some(binds) if !linearize_matches => {
+            term.free_vars().into_keys().filter(|var| !binds.contains(var)).collect()
+          }
+          _ => vec![],
+        };
+        if child.float_candidate && !strict {
+          // Only the free variables of match arms that are not linearized and unscoped variables stop them from being floated.
+          let is_combinator = if child.arm_binds.is_some() {
+            arm_captured.is_empty() && !term.has_unscoped_diff()
+          } else {
+            term.is_combinator()
+          };
+          if is_combinator {
+            continue;
+          }
+        }
+
+        // Lambdas and match arms give more useful explanations, so they take precedence over arguments.
+        let enclosing_child = if child.arm_binds.is_some() {
+          Some(Enclosing { kind: "match arm", captured: arm_captured, unscoped: term.has_unscoped_diff() })
+        } else if child.float_candidate && matches!(term, Term::Lam { .. }) {
+          let captured = term.free_vars().into_keys().collect();
+          Some(Enclosing { kind: "lambda", captured, unscoped: term.has_unscoped_diff() })
+        } else if child.float_candidate && !strict && enclosing.map_or(true, |e| e.kind == "argument") {
+          let captured = term.free_vars().into_keys().collect();
+          Some(Enclosing { kind: "argument", captured, unscoped: term.has_unscoped_diff() })
+        } else {
+          None
+        };
+        let enclosing = enclosing_child.as_ref().or(enclosing);
+        term.explain_strict_recursion(def_name, peers, linearize_matches, strict, enclosing, notes);
+      }
+    })
+  }
+
+  /// The children of a term, whether they're in a strict position
+  /// and whether `float_combinators` would try to extract them.
+  ///
+  /// Follows how terms are compiled to HVM: function heads, operands and
+  /// values destructured by a `let` become redexes, so they are always strict,
+  /// while function arguments are lazy. Other children inherit the strictness of their parent.
+  fn strictness_children(&self, strict: bool) -> Vec<StrictnessChild<'_>> {
+    let child =
+      |term, strict, float_candidate| StrictnessChild { term, strict, float_candidate, arm_binds: None };
+    let arm =
+      |term, binds| StrictnessChild { term, strict: false, float_candidate: true, arm_binds: Some(binds) };
+    match self {
+      Term::App { .. } => {
+        // Application spines are flattened, the arguments and the head are the candidates.
+        let mut children = vec![];
+        let mut cur = self;
+        while let Term::App { fun, arg, .. } = cur {
+          children.push(child(arg.as_ref(), false, true));
+          cur = fun;
+        }
+        children.push(child(cur, true, true));
+        children
+      }
+      Term::Mat { arg, with_arg, arms, .. } => std::iter::once(child(arg.as_ref(), true, true))
+        .chain(with_arg.iter().map(|arg| child(arg, false, true)))
+        .chain(arms.iter().map(|arm_| arm(&arm_.2, arm_.1.iter().flatten().cloned().collect())))
+        .collect(),
+      Term::Swt { arg, with_arg, pred, arms, .. } => {
+        let (succ, nums) = arms.split_last().unwrap();
+        std::iter::once(child(arg.as_ref(), true, true))
+          .chain(with_arg.iter().map(|arg| child(arg, false, true)))
+          .chain(nums.iter().map(|num| arm(num, vec![])))
+          .chain(std::iter::once(arm(succ, pred.iter().cloned().collect())))
+          .collect()
+      }
+      // The value of a variable is only lazy if it's used once as an argument, which we don't track.
+      Term::Let { val, nxt, .. } => vec![child(val, true, true), child(nxt, strict, true)],
+      Term::Use { val, nxt, .. } => vec![child(val, strict, true), child(nxt, strict, true)],
+      Term::Oper { fst, snd, .. } => vec![child(fst, true, true), child(snd, true, true)],
+      Term::Fan { els, .. } => els.iter().map(|el| child(el, strict, true)).collect(),
+      Term::List { els } => els.iter().map(|el| child(el, false, true)).collect(),
+      // Lambda bodies are not extracted on their own, but their children are.
+      Term::Lam { .. } => {
+        let mut bod = self;
+        while let Term::Lam { bod: inner, .. } = bod {
+          bod = inner;
+        }
+        match bod {
+          Term::Ref { .. } => vec![child(bod, strict, false)],
+          bod => bod.strictness_children(strict),
+        }
+      }
+      _ => self.children().map(|term| child(term, strict, false)).collect(),
+    }
+  }
+
+  fn is_ref_to(&self, peers: &BTreeSet<Name>) -> bool {
+    matches!(self, Term::Ref { nam } if peers.contains(nam))
+  }
+
+  fn has_ref_to(&self, peers: &BTreeSet<Name>) -> bool {
+    maybe_grow(|| self.is_ref_to(peers) || self.children().any(|child| child.has_ref_to(peers)))
+  }
+}
+
+struct StrictnessChild<'t> {
+  term: &'t Term,
+  strict: bool,
+  float_candidate: bool,
+  /// The variables bound by the match arm, if the child is one.
+  arm_binds: Option<Vec<Name>>,
+}
+
+/// The innermost lambda, match arm or argument around a recursive reference.
+struct Enclosing {
+  kind: &'static str,
+  captured: Vec<Name>,
+  unscoped: bool,
+}
+
+fn explain(nam: &Name, def_name: &Name, enclosing: Option<&Enclosing>) -> String {
+  match enclosing {
+    None => format!(
+      "'{nam}' is used strictly in the body of '{def_name}', outside of any lambda or match arm that could be made lazy."
+    ),
+    Some(Enclosing { kind: "argument", captured, .. }) if !captured.is_empty() => {
+      let vars = captured.iter().map(|var| format!("'{var}'")).collect::<Vec<_>>().join(", ");
+      format!(
+        "'{nam}' is used inside an argument in '{def_name}' that depends on {vars}, so it can't be extracted into a lazy definition. Moving it into the arm of a 'match' or 'switch' avoids this."
+      )
+    }
+    Some(Enclosing { kind, captured, .. }) if !captured.is_empty() => {
+      let vars = captured.iter().map(|var| format!("'{var}'")).collect::<Vec<_>>().join(", ");
+      let args = if captured.len() == 1 { "an argument" } else { "arguments" };
+      format!(
+        "'{nam}' is used inside a {kind} in '{def_name}' that captures {vars} from outside of it. Pass {vars} to the {kind} as {args} instead."
+      )
+    }
+    Some(Enclosing { kind, unscoped: true, .. }) => {
+      let article = if kind.starts_with('a') { "an" } else { "a" };
+      format!("'{nam}' is used inside {article} {kind} in '{def_name}' that uses unscoped variables bound outside of it.")
+    }
+    Some(Enclosing { kind, .. }) => format!(
+      "'{nam}' is used inside a {kind} in '{def_name}' that is itself used strictly, so extracting it wouldn't make it lazy."
+    ),
+  }
+}
+
+/// The definitions that are in a recursive cycle with each definition.
+#[allow(clippy::mutable_key_type)] // Safe to allow, we know how `Name` works.
+fn recursive_peers(book: &Book) -> BTreeMap<Name, BTreeSet<Name>> {
+  let deps = book_def_deps(book);
+  let reachable = deps.keys().map(|nam| (nam, reachable_defs(nam, &deps))).collect::<HashMap<_, _>>();
+
+  let mut peers = BTreeMap::new();
+  for (nam, reach) in reachable.iter() {
+    let cycle = reach.iter().filter(|dep| reachable.get(dep).is_some_and(|reach| reach.contains(*nam)));
+    peers.insert((*nam).clone(), cycle.cloned().collect());
+  }
+  peers
+}
+
+#[allow(clippy::mutable_key_type)]
+fn reachable_defs(nam: &Name, deps: &HashMap<Name, HashSet<Name>>) -> HashSet<Name> {
+  let mut seen = HashSet::new();
+  let mut to_visit = vec![nam];
+  while let Some(nam) = to_visit.pop() {
+    for dep in deps.get(nam).into_iter().flatten() {
+      if seen.insert(dep.clone()) {
+        to_visit.push(dep);
+      }
+    }
+  }
+  seen
+}
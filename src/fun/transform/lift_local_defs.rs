@@ -12,8 +12,9 @@ impl Book {
     let mut defs = IndexMap::new();
     for (name, def) in self.defs.iter_mut() {
       let mut gen = 0;
+      let builtin = def.is_builtin();
       for rule in def.rules.iter_mut() {
-        rule.body.lift_local_defs(name, &mut defs, &mut gen);
+        rule.body.lift_local_defs(name, builtin, &mut defs, &mut gen);
       }
     }
     self.defs.extend(defs);
@@ -27,14 +28,20 @@ impl Rule {
 }
 
 impl Term {
-  pub fn lift_local_defs(&mut self, parent: &Name, defs: &mut IndexMap<Name, Definition>, gen: &mut usize) {
+  pub fn lift_local_defs(
+    &mut self,
+    parent: &Name,
+    builtin: bool,
+    defs: &mut IndexMap<Name, Definition>,
+    gen: &mut usize,
+  ) {
     maybe_grow(|| match self {
       Term::Def { def, nxt } => {
         let local_name = Name::new(format!("{}__local_{}_{}", parent, gen, def.name));
         for rule in def.rules.iter_mut() {
-          rule.body.lift_local_defs(&local_name, defs, gen);
+          rule.body.lift_local_defs(&local_name, builtin, defs, gen);
         }
-        nxt.lift_local_defs(parent, defs, gen);
+        nxt.lift_local_defs(parent, builtin, defs, gen);
         *gen += 1;
 
         let inner_defs =
@@ -45,12 +52,12 @@ impl Term {
 
         apply_closure(&mut rules, &fvs);
 
-        let new_def = Definition::new_gen(local_name.clone(), rules, false);
+        let new_def = Definition::new_gen(local_name.clone(), rules, builtin);
         defs.insert(local_name.clone(), new_def);
       }
       _ => {
         for child in self.children_mut() {
-          child.lift_local_defs(parent, defs, gen);
+          child.lift_local_defs(parent, builtin, defs, gen);
         }
       }
     })
@@ -66,14 +73,13 @@ fn gen_use(
 ) -> (Term, BTreeSet<Name>, Vec<Rule>) {
   let mut fvs = BTreeSet::<Name>::new();
   for rule in rules.iter() {
-    fvs.extend(rule.body.free_vars().into_keys().collect::<BTreeSet<_>>());
-  }
-  fvs.retain(|fv| !inner_defs.contains(fv));
-  for rule in rules.iter() {
+    let mut rule_fvs = rule.body.free_vars().into_keys().collect::<BTreeSet<_>>();
     for bind in rule.binds().flatten() {
-      fvs.remove(bind);
+      rule_fvs.remove(bind);
     }
+    fvs.extend(rule_fvs);
   }
+  fvs.retain(|fv| !inner_defs.contains(fv));
   fvs.remove(nam);
 
   let call = Term::call(
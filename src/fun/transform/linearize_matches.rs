@@ -395,7 +395,7 @@ pub fn lift_match_vars(match_term: &mut Term) -> &mut Term {
   let (with_bnd, with_arg, arms) = match match_term {
     Term::Mat { arg: _, bnd: _, with_bnd, with_arg, arms: rules } => {
       let args =
-        rules.iter().map(|(_, binds, body)| (binds.iter().flatten().cloned().collect(), body)).collect();
+        rules.iter().map(|(_, binds, body, _)| (binds.iter().flatten().cloned().collect(), body)).collect();
       (with_bnd.clone(), with_arg.clone(), args)
     }
     Term::Swt { arg: _, bnd: _, with_bnd, with_arg, pred, arms } => {
@@ -1,6 +1,7 @@
 use crate::{
   fun::{Book, FanKind, Name, Pattern, Tag, Term},
   maybe_grow, multi_iterator,
+  utils::par_map,
 };
 use std::collections::HashMap;
 
@@ -17,10 +18,8 @@ use std::collections::HashMap;
 ///   If they're use more times: add dups for all the uses, put the let body at the root dup.
 /// Precondition: All variables are bound and have unique names within each definition.
 impl Book {
-  pub fn linearize_vars(&mut self) {
-    for def in self.defs.values_mut() {
-      def.rule_mut().body.linearize_vars();
-    }
+  pub fn linearize_vars(&mut self, jobs: usize) {
+    par_map(self.defs.values_mut(), jobs, |def| def.rule_mut().body.linearize_vars());
   }
 }
 
@@ -1,7 +1,10 @@
 pub mod apply_args;
+pub mod common_subterms;
+pub mod constant_folding;
 pub mod definition_merge;
 pub mod definition_pruning;
 pub mod desugar_bend;
+pub mod desugar_field_access;
 pub mod desugar_fold;
 pub mod desugar_match_defs;
 pub mod desugar_open;
@@ -14,6 +17,9 @@ pub mod expand_main;
 pub mod fix_match_defs;
 pub mod fix_match_terms;
 pub mod float_combinators;
+pub mod fuse_traversals;
+pub mod inline_defs;
+pub mod lazy_recursion;
 pub mod lift_local_defs;
 pub mod linearize_matches;
 pub mod linearize_vars;
@@ -1,5 +1,5 @@
 use super::tree_children;
-use crate::{diagnostics::Diagnostics, fun::Name, CompilerTarget};
+use crate::{diagnostics::Diagnostics, fun::Name, utils::par_map, CompilerTarget};
 use hvm::ast::{Book, Net, Tree};
 
 pub const MAX_NET_SIZE_C: usize = 4095;
@@ -9,6 +9,7 @@ pub fn check_net_sizes(
   book: &Book,
   diagnostics: &mut Diagnostics,
   target: &CompilerTarget,
+  jobs: usize,
 ) -> Result<(), Diagnostics> {
   let (net_size_bound, target_lang) = match target {
     CompilerTarget::Cuda => (MAX_NET_SIZE_CUDA, "Cuda"),
@@ -17,8 +18,8 @@ pub fn check_net_sizes(
 
   diagnostics.start_pass();
 
-  for (name, net) in &book.defs {
-    let nodes = count_nodes(net);
+  let sizes = par_map(&book.defs, jobs, |(name, net)| (name, count_nodes(net)));
+  for (name, nodes) in sizes {
     if nodes > net_size_bound {
       diagnostics.add_rule_error(
         format!("Definition is too large for HVM {target_lang} (size={nodes}, max size={net_size_bound}). Please break it into smaller pieces."),
@@ -6,6 +6,8 @@ pub mod check_net_size;
 pub mod eta_reduce;
 pub mod inline;
 pub mod mutual_recursion;
+pub mod net_cache;
+pub mod pre_reduce;
 pub mod prune;
 
 pub fn tree_children(tree: &Tree) -> impl DoubleEndedIterator<Item = &Tree> + Clone {
@@ -39,20 +41,25 @@ pub fn net_trees_mut(net: &mut Net) -> impl DoubleEndedIterator<Item = &mut Tree
 pub fn hvm_book_show_pretty(book: &hvm::ast::Book) -> String {
   let mut s = String::new();
   for (nam, def) in book.defs.iter() {
-    s.push_str(&format!("@{} = {}\n", nam, def.root.show()));
-    for (pri, a, b) in def.rbag.iter() {
-      s.push_str("  &");
-      if *pri {
-        s.push('!');
-      } else {
-        s.push(' ');
-      }
-      s.push_str(&a.show());
-      s.push_str(" ~ ");
-      s.push_str(&b.show());
-      s.push('\n');
+    s.push_str(&hvm_def_show_pretty(nam, def));
+  }
+  s
+}
+
+pub fn hvm_def_show_pretty(nam: &str, def: &Net) -> String {
+  let mut s = format!("@{} = {}\n", nam, def.root.show());
+  for (pri, a, b) in def.rbag.iter() {
+    s.push_str("  &");
+    if *pri {
+      s.push('!');
+    } else {
+      s.push(' ');
     }
+    s.push_str(&a.show());
+    s.push_str(" ~ ");
+    s.push_str(&b.show());
     s.push('\n');
   }
+  s.push('\n');
   s
 }
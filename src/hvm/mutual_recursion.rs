@@ -1,12 +1,15 @@
 use super::tree_children;
 use crate::{
   diagnostics::{Diagnostics, WarningType, ERR_INDENT_SIZE},
-  fun::transform::definition_merge::MERGE_SEPARATOR,
+  fun::{
+    transform::{definition_merge::MERGE_SEPARATOR, float_combinators::NAME_SEP},
+    Name,
+  },
   maybe_grow,
 };
 use hvm::ast::{Book, Tree};
 use indexmap::{IndexMap, IndexSet};
-use std::fmt::Debug;
+use std::{collections::BTreeMap, fmt::Debug};
 
 type Ref = String;
 type Stack<T> = Vec<T>;
@@ -15,19 +18,121 @@ type RefSet = IndexSet<Ref>;
 #[derive(Default)]
 pub struct Graph(IndexMap<Ref, RefSet>);
 
-pub fn check_cycles(book: &Book, diagnostics: &mut Diagnostics) -> Result<(), Diagnostics> {
+/// Checks for recursive cycles of strict references, which would expand infinitely.
+///
+/// `notes` has the reasons, by definition, why recursive references couldn't be
+/// automatically made lazy, which are shown for the definitions in the cycles.
+#[allow(clippy::mutable_key_type)] // Safe to allow, we know how `Name` works.
+pub fn check_cycles(
+  book: &Book,
+  notes: &BTreeMap<Name, Vec<String>>,
+  diagnostics: &mut Diagnostics,
+) -> Result<(), Diagnostics> {
   diagnostics.start_pass();
 
   let graph = Graph::from(book);
   let cycles = graph.cycles();
 
   if !cycles.is_empty() {
-    let msg = format!(include_str!("mutual_recursion.message"), cycles = show_cycles(cycles));
+    let notes = show_notes(&cycles, notes);
+    let msg = format!(include_str!("mutual_recursion.message"), cycles = show_cycles(cycles), notes = notes);
     diagnostics.add_book_warning(msg.as_str(), WarningType::RecursionCycle);
   }
 
   diagnostics.fatal(())
 }
+
+/// Checks for definitions that expand infinitely as soon as they're called.
+///
+/// Unlike [`check_cycles`], which reports any recursion through strict references,
+/// this only follows the references that are always expanded when the definition is,
+/// because they meet a node in one of its redexes.
+/// A cycle of those never stops unfolding, whatever the evaluation order,
+/// so the priorities given by `add_recursive_priority` can't save it.
+pub fn check_infinite_expansion(book: &Book, diagnostics: &mut Diagnostics) -> Result<(), Diagnostics> {
+  diagnostics.start_pass();
+
+  let mut graph = Graph::new();
+  for (nam, net) in book.defs.iter() {
+    // A reference at the root is connected to the node that called the definition.
+    if let Tree::Ref { nam: root } = &net.root {
+      graph.add(nam.clone(), root.clone());
+    }
+    for (_, a, b) in net.rbag.iter() {
+      let mut called = vec![];
+      called_refs(a, b, &mut called);
+      for r#ref in called {
+        graph.add(nam.clone(), r#ref.clone());
+      }
+    }
+  }
+
+  let cycles = graph.cycles();
+  if !cycles.is_empty() {
+    let msg = format!(
+      "\x1b[1mThe following functions expand infinitely as soon as they're called:\x1b[0m\n{}\n\n\
+       Each of them always calls the next one in an active redex, so running a program that uses them never ends.\n\
+       Make the recursive calls happen only in some of the branches of a 'match' or 'switch'.\n\
+       To disable this check, use the \"-Ainfinite-expansion\" compiler option.",
+      show_cycles(cycles)
+    );
+    diagnostics.add_book_warning(msg.as_str(), WarningType::InfiniteExpansion);
+  }
+
+  diagnostics.fatal(())
+}
+
+/// Collects the references that get called when the redex `a ~ b` is reduced.
+///
+/// A reference is called when it meets a constructor, operation or switch node, either
+/// directly or, after a commutation, a copy of the node on the other side of the redex.
+/// Meeting a duplication copies it, and meeting an eraser, a number or another reference erases it.
+fn called_refs<'t>(a: &'t Tree, b: &'t Tree, called: &mut Vec<&'t Ref>) {
+  maybe_grow(|| match (a, b) {
+    (Tree::Var { .. }, _) | (_, Tree::Var { .. }) => {}
+    (Tree::Ref { nam }, node) | (node, Tree::Ref { nam }) if expands_refs(node) => called.push(nam),
+    // Annihilation: the ports of the two nodes are connected to each other.
+    (Tree::Con { fst: a1, snd: a2 }, Tree::Con { fst: b1, snd: b2 })
+    | (Tree::Dup { fst: a1, snd: a2 }, Tree::Dup { fst: b1, snd: b2 })
+    | (Tree::Opr { fst: a1, snd: a2 }, Tree::Opr { fst: b1, snd: b2 })
+    | (Tree::Swi { fst: a1, snd: a2 }, Tree::Swi { fst: b1, snd: b2 }) => {
+      called_refs(a1, b1, called);
+      called_refs(a2, b2, called);
+    }
+    // Commutation: the ports of each node are connected to copies of the other.
+    (a, b) if is_binary(a) && is_binary(b) => {
+      for (node, other) in [(a, b), (b, a)] {
+        if !expands_refs(other) {
+          continue;
+        }
+        for child in tree_children(node) {
+          if let Tree::Ref { nam } = child {
+            called.push(nam);
+          }
+        }
+      }
+    }
+    // Operations and switches connect their first port to a new node.
+    (Tree::Num { .. }, Tree::Opr { fst, .. } | Tree::Swi { fst, .. })
+    | (Tree::Opr { fst, .. } | Tree::Swi { fst, .. }, Tree::Num { .. }) => {
+      if let Tree::Ref { nam } = fst.as_ref() {
+        called.push(nam);
+      }
+    }
+    // Erasures don't call the references they reach.
+    _ => {}
+  })
+}
+
+fn is_binary(tree: &Tree) -> bool {
+  matches!(tree, Tree::Con { .. } | Tree::Dup { .. } | Tree::Opr { .. } | Tree::Swi { .. })
+}
+
+/// Whether a reference that meets the node is expanded, instead of copied or erased.
+fn expands_refs(tree: &Tree) -> bool {
+  matches!(tree, Tree::Con { .. } | Tree::Opr { .. } | Tree::Swi { .. })
+}
+
 fn show_cycles(mut cycles: Vec<Vec<Ref>>) -> String {
   let tail = if cycles.len() > 5 {
     format!("\n{:ERR_INDENT_SIZE$}and {} other cycles...", "", cycles.len() - 5)
@@ -58,6 +163,31 @@ fn show_cycles(mut cycles: Vec<Vec<Ref>>) -> String {
   cycles
 }
 
+/// Shows why the recursive references of the definitions in the shown cycles weren't made lazy.
+#[allow(clippy::mutable_key_type)]
+fn show_notes(cycles: &[Vec<Ref>], notes: &BTreeMap<Name, Vec<String>>) -> String {
+  let mut shown = Vec::new();
+  for nam in cycles.iter().take(5).flatten().flat_map(|nam| nam.split(MERGE_SEPARATOR)) {
+    // Floated combinators are explained by the definition they came from.
+    let def_notes = notes.get(&Name::new(nam)).or_else(|| {
+      let (def_name, _) = nam.split_once(NAME_SEP)?;
+      notes.get(&Name::new(def_name))
+    });
+    for note in def_notes.into_iter().flatten() {
+      if !shown.contains(&note) {
+        shown.push(note);
+      }
+    }
+  }
+
+  if shown.is_empty() {
+    return String::new();
+  }
+  let notes =
+    shown.iter().map(|note| format!("{:ERR_INDENT_SIZE$}* {note}", "")).collect::<Vec<_>>().join("\n");
+  format!("\n\n\x1b[1mThese recursive references couldn't be made lazy automatically:\x1b[0m\n{notes}")
+}
+
 impl Graph {
   pub fn cycles(&self) -> Vec<Vec<Ref>> {
     let mut cycles = Vec::new();
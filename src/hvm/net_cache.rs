@@ -0,0 +1,318 @@
+//! Content-addressed on-disk cache of compiled definitions.
+//!
+//! It has two kinds of entries:
+//!
+//! * [`DefCache`] stores the nets that each definition of the program compiles to.
+//!   Its key is a hash of the definition's source code, of the source code of all the definitions
+//!   it transitively references, of the data types and of the compilation options.
+//!   Unchanged definitions are loaded as native HVM definitions before desugaring,
+//!   so they skip all the passes over terms and the conversion to nets.
+//!
+//! * [`NetCache`] stores the net of each definition after the passes that only look at a single net,
+//!   like `pre_reduce`. Its key is a hash of the definition's net and of the nets it transitively references.
+//!
+//! The passes over the whole program, like the recursion checks, still run every time.
+
+use super::{net_trees, tree_children};
+use crate::{
+  diagnostics::{DiagnosticOrigin, Diagnostics},
+  fun::{Book, HvmDefinition, Name, Source, Term},
+  maybe_grow,
+};
+use hvm::ast::{CoreParser, Net, Tree};
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  path::{Path, PathBuf},
+};
+
+/// The directory, next to the compiled program, where the cache is stored.
+pub const CACHE_DIR: &str = ".bend-cache";
+
+pub struct DefCache {
+  dir: PathBuf,
+  keys: BTreeMap<Name, u64>,
+  /// The definitions that were taken from the cache.
+  loaded: BTreeSet<Name>,
+}
+
+#[allow(clippy::mutable_key_type)] // Safe to allow, we know how `Name` works.
+impl DefCache {
+  /// Computes the cache keys of the definitions of a book that wasn't desugared yet.
+  ///
+  /// `opts` identifies the options that change how the definitions are compiled.
+  pub fn new(dir: &Path, book: &Book, opts: &str) -> Self {
+    // Every definition depends on the data types, which decide how matches and constructors are compiled,
+    // and on the native definitions, which are never cached.
+    let mut common = format!("{} {opts}", env!("CARGO_PKG_VERSION"));
+    for (nam, adt) in &book.adts {
+      common.push_str(&format!("\n{}", adt.display(nam)));
+    }
+    for def in book.hvm_defs.values() {
+      common.push_str(&format!("\n{} {}", def.name, def.body.show()));
+    }
+
+    let src_hashes = book
+      .defs
+      .values()
+      .map(|def| (def.name.as_ref(), hash_str(&format!("{:?} {def}", def.inline))))
+      .collect::<BTreeMap<_, _>>();
+    let deps = book
+      .defs
+      .values()
+      .map(|def| {
+        let mut refs = BTreeSet::new();
+        for rule in &def.rules {
+          term_refs(&rule.body, book, &mut refs);
+        }
+        (def.name.as_ref(), refs)
+      })
+      .collect::<BTreeMap<_, _>>();
+
+    let mut keys = BTreeMap::new();
+    for nam in book.defs.keys() {
+      let mut key = common.clone();
+      for dep in transitive_deps(nam, &deps) {
+        key.push_str(&format!(" {dep}:{:016x}", src_hashes[dep]));
+      }
+      key.push_str(&format!(" {:016x}", src_hashes[nam.as_ref()]));
+      keys.insert(nam.clone(), hash_str(&key));
+    }
+
+    DefCache { dir: dir.to_path_buf(), keys, loaded: BTreeSet::new() }
+  }
+
+  /// Replaces the definitions that have a valid cache entry by the nets stored in it.
+  ///
+  /// The entrypoint and the definitions that `expand_main` can copy into it are always compiled,
+  /// since the entrypoint needs their terms.
+  ///
+  /// Must run after `set_entrypoint`.
+  pub fn load(&mut self, book: &mut Book) {
+    let mut compiled = BTreeSet::new();
+    if let Some(main) = &book.entrypoint {
+      compiled = main_expansion_deps(book, main);
+      compiled.insert(main.clone());
+    }
+
+    for (nam, key) in &self.keys {
+      if compiled.contains(nam) || !book.defs.contains_key(nam) {
+        continue;
+      }
+      let Some((safe, nets)) = self.read(*key) else { continue };
+      let def = book.defs.shift_remove(nam).unwrap();
+      for (net_nam, body) in nets {
+        let name = Name::new(net_nam);
+        let (source, safe) =
+          if name == *nam { (def.source.clone(), safe) } else { (Source::Generated, false) };
+        book.hvm_defs.insert(name.clone(), HvmDefinition { name, body, source, safe });
+      }
+      self.loaded.insert(nam.clone());
+    }
+  }
+
+  /// Stores the nets of the definitions that were compiled, grouped by the definition they came from.
+  ///
+  /// Definitions with diagnostics are not stored, so that they are reported again in the next compilation.
+  /// Failing to write to the cache is not an error, the entry is just not stored.
+  pub fn store(&self, book: &Book, hvm_book: &hvm::ast::Book, diagnostics: &Diagnostics) {
+    let mut nets = BTreeMap::<&Name, Vec<(&str, &Net)>>::new();
+    for (nam, net) in &hvm_book.defs {
+      if let Some(owner) = self.owner(nam) {
+        nets.entry(owner).or_default().push((nam, net));
+      }
+    }
+
+    let with_diagnostics = diagnostics
+      .diagnostics
+      .keys()
+      .filter_map(|origin| match origin {
+        DiagnosticOrigin::Rule(nam) => self.owner(nam),
+        DiagnosticOrigin::Inet(nam) => self.owner(nam),
+        DiagnosticOrigin::Book | DiagnosticOrigin::Readback => None,
+      })
+      .collect::<BTreeSet<_>>();
+
+    for (nam, nets) in nets {
+      let is_main = book.entrypoint.as_ref() == Some(nam);
+      let has_net = nets.iter().any(|(net_nam, _)| *net_nam == nam.as_ref());
+      if is_main || !has_net || self.loaded.contains(nam) || with_diagnostics.contains(nam) {
+        continue;
+      }
+      let mut code = format!("{}\n", u8::from(book.is_safe_def(nam)));
+      for (net_nam, net) in nets {
+        code.push_str(&format!("@{net_nam} = {}\n", net.show()));
+      }
+      let path = self.entry_path(self.keys[nam]);
+      if !path.exists() && std::fs::create_dir_all(&self.dir).is_ok() {
+        let _ = std::fs::write(path, code);
+      }
+    }
+  }
+
+  /// The definitions that were taken from the cache in this compilation.
+  pub fn loaded(&self) -> &BTreeSet<Name> {
+    &self.loaded
+  }
+
+  /// Returns whether the definition was safe and the nets of the definition stored with `key`.
+  fn read(&self, key: u64) -> Option<(bool, BTreeMap<String, Net>)> {
+    let code = std::fs::read_to_string(self.entry_path(key)).ok()?;
+    let (safe, nets) = code.split_once('\n')?;
+    let nets = CoreParser::new(nets).parse_book().ok()?.defs;
+    Some((safe == "1", nets))
+  }
+
+  fn entry_path(&self, key: u64) -> PathBuf {
+    self.dir.join(format!("{key:016x}.def.hvm"))
+  }
+
+  /// The definition of the program that a compiled definition was generated from,
+  /// which is the longest one that is a prefix of its name up to a `__` separator.
+  fn owner(&self, nam: &str) -> Option<&Name> {
+    if let Some((nam, _)) = self.keys.get_key_value(&Name::new(nam)) {
+      return Some(nam);
+    }
+    let mut seps = nam.match_indices("__").map(|(i, _)| i).collect::<Vec<_>>();
+    seps.reverse();
+    seps.into_iter().find_map(|i| self.keys.get_key_value(&Name::new(&nam[..i])).map(|(nam, _)| nam))
+  }
+}
+
+pub struct NetCache {
+  dir: PathBuf,
+  keys: BTreeMap<String, u64>,
+}
+
+impl NetCache {
+  /// Computes the cache keys of the definitions of a freshly compiled book.
+  ///
+  /// `opts` identifies the options that change how the cached passes transform the nets.
+  pub fn new(dir: &Path, book: &hvm::ast::Book, opts: &str) -> Self {
+    let net_hashes =
+      book.defs.iter().map(|(nam, net)| (nam.as_str(), hash_str(&net.show()))).collect::<BTreeMap<_, _>>();
+    let deps = book.defs.iter().map(|(nam, net)| (nam.as_str(), net_refs(net))).collect::<BTreeMap<_, _>>();
+
+    let mut keys = BTreeMap::new();
+    for nam in book.defs.keys() {
+      let mut key = format!("{} {opts}", env!("CARGO_PKG_VERSION"));
+      for dep in transitive_deps(nam, &deps) {
+        if let Some(hash) = net_hashes.get(dep) {
+          key.push_str(&format!(" {hash:016x}"));
+        }
+      }
+      key.push_str(&format!(" {:016x}", net_hashes[nam.as_str()]));
+      keys.insert(nam.clone(), hash_str(&key));
+    }
+
+    NetCache { dir: dir.to_path_buf(), keys }
+  }
+
+  /// Returns the cached net of a definition, if there's a valid one.
+  pub fn get(&self, nam: &str) -> Option<Net> {
+    let code = std::fs::read_to_string(self.entry_path(nam)?).ok()?;
+    CoreParser::new(&code).parse_net().ok()
+  }
+
+  /// Stores the final net of a definition.
+  /// Failing to write to the cache is not an error, the entry is just not stored.
+  pub fn insert(&self, nam: &str, net: &Net) {
+    let Some(path) = self.entry_path(nam) else { return };
+    if std::fs::create_dir_all(&self.dir).is_ok() {
+      let _ = std::fs::write(path, net.show());
+    }
+  }
+
+  fn entry_path(&self, nam: &str) -> Option<PathBuf> {
+    self.keys.get(nam).map(|key| self.dir.join(format!("{key:016x}.hvm")))
+  }
+}
+
+/// Removes all the cached nets stored in `dir`.
+pub fn clean_cache(dir: &Path) -> std::io::Result<()> {
+  match std::fs::remove_dir_all(dir) {
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+    res => res,
+  }
+}
+
+/// The definitions directly referenced by a term.
+fn term_refs<'a>(term: &'a Term, book: &Book, refs: &mut BTreeSet<&'a str>) {
+  maybe_grow(|| {
+    if let Term::Var { nam } | Term::Ref { nam } = term {
+      if book.defs.contains_key(nam) {
+        refs.insert(nam);
+      }
+    }
+    for child in term.children() {
+      term_refs(child, book, refs);
+    }
+  })
+}
+
+/// The definitions used by the entrypoint whose body `expand_main` can copy into it.
+///
+/// Over-approximates it with all the definitions that are used other than by calling them.
+#[allow(clippy::mutable_key_type)] // Safe to allow, we know how `Name` works.
+fn main_expansion_deps(book: &Book, main: &Name) -> BTreeSet<Name> {
+  fn go(term: &Term, book: &Book, found: &mut BTreeSet<Name>, to_visit: &mut Vec<Name>) {
+    maybe_grow(|| match term {
+      Term::App { fun, arg, .. } => {
+        let is_call =
+          matches!(fun.as_ref(), Term::Var { nam } | Term::Ref { nam } if !book.ctrs.contains_key(nam));
+        if !is_call {
+          go(fun, book, found, to_visit);
+        }
+        go(arg, book, found, to_visit);
+      }
+      Term::Var { nam } | Term::Ref { nam } => {
+        if book.defs.contains_key(nam) && found.insert(nam.clone()) {
+          to_visit.push(nam.clone());
+        }
+      }
+      _ => term.children().for_each(|child| go(child, book, found, to_visit)),
+    })
+  }
+
+  let mut found = BTreeSet::new();
+  let mut to_visit = vec![main.clone()];
+  while let Some(nam) = to_visit.pop() {
+    for rule in &book.defs[&nam].rules {
+      go(&rule.body, book, &mut found, &mut to_visit);
+    }
+  }
+  found
+}
+
+/// The definitions directly referenced by a net.
+fn net_refs(net: &Net) -> BTreeSet<&str> {
+  fn go<'a>(tree: &'a Tree, refs: &mut BTreeSet<&'a str>) {
+    maybe_grow(|| {
+      if let Tree::Ref { nam } = tree {
+        refs.insert(nam);
+      }
+      tree_children(tree).for_each(|child| go(child, refs));
+    })
+  }
+  let mut refs = BTreeSet::new();
+  net_trees(net).for_each(|tree| go(tree, &mut refs));
+  refs
+}
+
+/// All the definitions reachable from `nam`, in a deterministic order.
+fn transitive_deps<'a>(nam: &'a str, deps: &BTreeMap<&'a str, BTreeSet<&'a str>>) -> BTreeSet<&'a str> {
+  let mut seen = BTreeSet::new();
+  let mut to_visit = vec![nam];
+  while let Some(nam) = to_visit.pop() {
+    for &dep in deps.get(nam).into_iter().flatten() {
+      if seen.insert(dep) {
+        to_visit.push(dep);
+      }
+    }
+  }
+  seen
+}
+
+/// A 64-bit FNV-1a hash, which unlike the std hashers is stable between compiler versions.
+fn hash_str(s: &str) -> u64 {
+  s.bytes().fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
@@ -0,0 +1,260 @@
+//! Partially normalizes each net at compile time, reducing the redexes that
+//! are fully known inside of it, so they are not reduced again on every call.
+//!
+//! Only redexes between two nodes of the net are reduced.
+//! Redexes with a `Ref` that would need to be expanded are kept as they are,
+//! since expanding them could unfold a recursive definition forever.
+//!
+//! Since interaction nets are strongly confluent, reducing a redex earlier
+//! doesn't change the result of the program, only the number of interactions.
+//!
+//! The reduction is limited by a budget of interactions, and commutations are
+//! only done while the net is not larger than the original one, so that nets don't blow up.
+//!
+//! Numeric operations that could behave differently between the compiler and
+//! the runtime (divisions by zero, float transcendental functions, float casts)
+//! are also kept for the runtime.
+
+use super::{check_net_size::count_nodes, tree_children};
+use crate::{fun::num_to_name, maybe_grow};
+use hvm::{
+  ast::{Net, Numb, Tree},
+  hvm as rt,
+};
+use std::collections::{HashMap, HashSet};
+
+/// The maximum number of interactions done in a single net.
+const MAX_INTERACTIONS: usize = 1 << 12;
+
+pub fn pre_reduce_hvm_net(net: &mut Net) {
+  let size = count_nodes(net);
+  let mut names = HashSet::new();
+  for (_, a, b) in &net.rbag {
+    collect_names(a, &mut names);
+    collect_names(b, &mut names);
+  }
+  collect_names(&net.root, &mut names);
+
+  let mut state = PreReduceState {
+    size,
+    max_size: size,
+    interactions: 0,
+    subst: HashMap::new(),
+    kept: vec![],
+    names,
+    fresh: 0,
+  };
+
+  let mut redexes = std::mem::take(&mut net.rbag);
+  redexes.reverse();
+  while let Some((pri, a, b)) = redexes.pop() {
+    state.reduce(pri, a, b, &mut redexes);
+  }
+
+  // Connects the results of the reductions to where their variables are used.
+  let mut rbag = std::mem::take(&mut state.kept);
+  state.apply_subst(&mut net.root);
+  for (_, a, b) in &mut rbag {
+    state.apply_subst(a);
+    state.apply_subst(b);
+  }
+  // Variables whose both ends were left in redexes become redexes again.
+  while let Some(nam) = state.subst.keys().next().cloned() {
+    let mut tree = state.subst.remove(&nam).unwrap();
+    state.apply_subst(&mut tree);
+    rbag.push((false, Tree::Var { nam }, tree));
+  }
+  net.rbag = rbag;
+}
+
+struct PreReduceState {
+  /// The current number of nodes of the net.
+  size: usize,
+  max_size: usize,
+  interactions: usize,
+  /// The trees linked to variables whose other end has not been found yet.
+  subst: HashMap<String, Tree>,
+  /// The redexes that can't be reduced at compile time.
+  kept: Vec<(bool, Tree, Tree)>,
+  /// The variable names used in the net, to generate fresh ones.
+  names: HashSet<String>,
+  fresh: u64,
+}
+
+impl PreReduceState {
+  fn reduce(&mut self, pri: bool, a: Tree, b: Tree, redexes: &mut Vec<(bool, Tree, Tree)>) {
+    match (a, b) {
+      // Link: connects the tree to the other end of the variable.
+      (Tree::Var { nam }, t) | (t, Tree::Var { nam }) => {
+        if let Some(other) = self.subst.remove(&nam) {
+          redexes.push((pri, other, t));
+        } else if occurs(&nam, &t) {
+          self.kept.push((pri, Tree::Var { nam }, t));
+        } else {
+          self.subst.insert(nam, t);
+        }
+      }
+      (a, b) if self.interactions >= MAX_INTERACTIONS => self.kept.push((pri, a, b)),
+      // Void: two nullary nodes are just erased, as long as no reference needs to be expanded.
+      (Tree::Era | Tree::Num { .. } | Tree::Ref { .. }, Tree::Era | Tree::Num { .. } | Tree::Ref { .. }) => {
+        self.interactions += 1;
+      }
+      // Call: references are not expanded.
+      (a @ Tree::Ref { .. }, b) | (b, a @ Tree::Ref { .. }) => self.kept.push((pri, a, b)),
+      // Erase: the nullary node is copied to both ports of the other node.
+      (a @ (Tree::Era | Tree::Num { .. }), Tree::Con { fst, snd } | Tree::Dup { fst, snd })
+      | (Tree::Con { fst, snd } | Tree::Dup { fst, snd }, a @ (Tree::Era | Tree::Num { .. }))
+      | (a @ Tree::Era, Tree::Opr { fst, snd } | Tree::Swi { fst, snd })
+      | (Tree::Opr { fst, snd } | Tree::Swi { fst, snd }, a @ Tree::Era) => {
+        self.interactions += 1;
+        self.size -= 1;
+        redexes.push((false, a.clone(), *fst));
+        redexes.push((false, a, *snd));
+      }
+      // Oper: operates the number with the first operand of the operator.
+      (Tree::Num { val }, Tree::Opr { fst, snd }) | (Tree::Opr { fst, snd }, Tree::Num { val }) => match *fst
+      {
+        Tree::Num { val: fst_val } => {
+          let Some(res) = operate(&val, &fst_val) else {
+            let opr = Tree::Opr { fst: Box::new(Tree::Num { val: fst_val }), snd };
+            return self.kept.push((pri, Tree::Num { val }, opr));
+          };
+          self.interactions += 1;
+          self.size -= 1;
+          redexes.push((false, Tree::Num { val: res }, *snd));
+        }
+        fst => {
+          self.interactions += 1;
+          redexes.push((false, fst, Tree::Opr { fst: Box::new(Tree::Num { val }), snd }));
+        }
+      },
+      // Swit: selects the first branch for 0 and the second one for the predecessor of other numbers.
+      (Tree::Num { val }, Tree::Swi { fst, snd }) | (Tree::Swi { fst, snd }, Tree::Num { val }) => {
+        let num = rt::Numb(val.0).get_u24();
+        let branches = if num == 0 {
+          Tree::Con { fst: snd, snd: Box::new(Tree::Era) }
+        } else {
+          if self.size + 1 > self.max_size {
+            return self.kept.push((pri, Tree::Num { val }, Tree::Swi { fst, snd }));
+          }
+          self.size += 1;
+          let pred = Tree::Num { val: Numb(rt::Numb::new_u24(num - 1).0) };
+          let succ = Tree::Con { fst: Box::new(pred), snd };
+          Tree::Con { fst: Box::new(Tree::Era), snd: Box::new(succ) }
+        };
+        self.interactions += 1;
+        redexes.push((false, *fst, branches));
+      }
+      // Anni: two nodes of the same kind connect their ports.
+      (Tree::Con { fst: a1, snd: a2 }, Tree::Con { fst: b1, snd: b2 })
+      | (Tree::Dup { fst: a1, snd: a2 }, Tree::Dup { fst: b1, snd: b2 })
+      | (Tree::Opr { fst: a1, snd: a2 }, Tree::Opr { fst: b1, snd: b2 })
+      | (Tree::Swi { fst: a1, snd: a2 }, Tree::Swi { fst: b1, snd: b2 }) => {
+        self.interactions += 1;
+        self.size -= 2;
+        redexes.push((false, *a1, *b1));
+        redexes.push((false, *a2, *b2));
+      }
+      // Comm: two nodes of different kinds duplicate each other, growing the net.
+      (a, b) => {
+        if self.size + 2 > self.max_size {
+          return self.kept.push((pri, a, b));
+        }
+        self.interactions += 1;
+        self.size += 2;
+        let [x0, x1, x2, x3] = [(); 4].map(|_| self.fresh_var());
+        let (a_node, a1, a2) = split_node(a);
+        let (b_node, b1, b2) = split_node(b);
+        redexes.push((false, a1, b_node(x0.clone(), x1.clone())));
+        redexes.push((false, a2, b_node(x2.clone(), x3.clone())));
+        redexes.push((false, b1, a_node(x0, x2)));
+        redexes.push((false, b2, a_node(x1, x3)));
+      }
+    }
+  }
+
+  /// Replaces the variables linked during the reductions by the trees they were linked to.
+  fn apply_subst(&mut self, tree: &mut Tree) {
+    maybe_grow(|| match tree {
+      Tree::Var { nam } => {
+        if let Some(mut other) = self.subst.remove(nam) {
+          self.apply_subst(&mut other);
+          *tree = other;
+        }
+      }
+      Tree::Con { fst, snd } | Tree::Dup { fst, snd } | Tree::Opr { fst, snd } | Tree::Swi { fst, snd } => {
+        self.apply_subst(fst);
+        self.apply_subst(snd);
+      }
+      Tree::Ref { .. } | Tree::Era | Tree::Num { .. } => {}
+    })
+  }
+
+  fn fresh_var(&mut self) -> Tree {
+    loop {
+      let nam = num_to_name(self.fresh);
+      self.fresh += 1;
+      if self.names.insert(nam.clone()) {
+        return Tree::Var { nam };
+      }
+    }
+  }
+}
+
+/// Splits a binary node into a constructor for a node of the same kind and its two children.
+fn split_node(tree: Tree) -> (fn(Tree, Tree) -> Tree, Tree, Tree) {
+  match tree {
+    Tree::Con { fst, snd } => (|fst, snd| Tree::Con { fst: Box::new(fst), snd: Box::new(snd) }, *fst, *snd),
+    Tree::Dup { fst, snd } => (|fst, snd| Tree::Dup { fst: Box::new(fst), snd: Box::new(snd) }, *fst, *snd),
+    Tree::Opr { fst, snd } => (|fst, snd| Tree::Opr { fst: Box::new(fst), snd: Box::new(snd) }, *fst, *snd),
+    Tree::Swi { fst, snd } => (|fst, snd| Tree::Swi { fst: Box::new(fst), snd: Box::new(snd) }, *fst, *snd),
+    _ => unreachable!(),
+  }
+}
+
+/// Applies a numeric operation like the runtime does,
+/// or returns `None` if the result could be different at runtime.
+pub fn operate(a: &Numb, b: &Numb) -> Option<Numb> {
+  let (a, b) = (rt::Numb(a.0), rt::Numb(b.0));
+  let (at, bt) = (a.get_typ(), b.get_typ());
+  if a.is_cast() || b.is_cast() {
+    // Float casts saturate differently depending on the target.
+    let involves_f24 = [a.get_sym(), b.get_sym(), at, bt].contains(&rt::TY_F24);
+    if involves_f24 || !(a.is_num() || b.is_num()) {
+      return None;
+    }
+  } else if at >= rt::OP_ADD && b.is_num() || bt >= rt::OP_ADD && a.is_num() {
+    let (op, ty) = if at >= rt::OP_ADD { (at, bt) } else { (bt, at) };
+    let is_div = matches!(op, rt::OP_DIV | rt::FP_DIV | rt::OP_REM | rt::FP_REM);
+    let by_zero = a.get_u24() == 0 || b.get_u24() == 0;
+    let unsafe_op = match ty {
+      rt::TY_U24 => is_div && by_zero,
+      rt::TY_I24 => op >= rt::OP_SHL || is_div && by_zero,
+      // The float functions are computed by different libraries in each runtime.
+      _ => op >= rt::OP_AND,
+    };
+    if unsafe_op {
+      return None;
+    }
+  }
+  Some(Numb(rt::Numb::operate(a, b).0))
+}
+
+/// Checks if the variable `nam` appears inside `tree`.
+fn occurs(nam: &str, tree: &Tree) -> bool {
+  maybe_grow(|| match tree {
+    Tree::Var { nam: other } => nam == other,
+    _ => tree_children(tree).any(|child| occurs(nam, child)),
+  })
+}
+
+fn collect_names(tree: &Tree, names: &mut HashSet<String>) {
+  maybe_grow(|| {
+    if let Tree::Var { nam } = tree {
+      names.insert(nam.clone());
+    }
+    for child in tree_children(tree) {
+      collect_names(child, names);
+    }
+  })
+}
@@ -0,0 +1,235 @@
+use super::{AssignPattern, Definition, Expr, FStrPart, MatchArm, Stmt};
+use crate::fun::{Name, Num, Op};
+use indexmap::IndexSet;
+use std::collections::HashMap;
+
+/// The kinds of the variables whose value is known, named like the `:kind` of an interpolated value.
+type Kinds = HashMap<Name, &'static str>;
+
+impl Definition {
+  /// Chooses how to convert the values interpolated in the formatted strings of this definition,
+  /// when the kind is not given explicitly with `:kind`.
+  ///
+  /// The kind of a value is known from its form, like a number or string literal,
+  /// or from the last value assigned to a variable, like in `s = "abc"; f"s={s}"`.
+  /// A variable assigned in a branch or a loop is only known if it's assigned again before its use.
+  pub fn infer_fstr_kinds(&mut self) {
+    self.body.infer_fstr_kinds(Kinds::new());
+  }
+}
+
+#[allow(clippy::mutable_key_type)] // Safe to allow, we know how `Name` works.
+impl Stmt {
+  fn infer_fstr_kinds(&mut self, mut kinds: Kinds) {
+    match self {
+      Stmt::LocalDef { def, nxt } => {
+        def.infer_fstr_kinds();
+        nxt.infer_fstr_kinds(kinds);
+      }
+      Stmt::Assign { pat, val, nxt } => {
+        val.infer_fstr_kinds(&kinds);
+        let kind = kind_of(val, &kinds);
+        forget(&mut kinds, pat_binds(pat));
+        if let (AssignPattern::Var(nam), Some(kind)) = (&*pat, kind) {
+          kinds.insert(nam.clone(), kind);
+        }
+        if let Some(nxt) = nxt {
+          nxt.infer_fstr_kinds(kinds);
+        }
+      }
+      Stmt::Ask { pat, val, nxt } => {
+        val.infer_fstr_kinds(&kinds);
+        forget(&mut kinds, pat_binds(pat));
+        if let Some(nxt) = nxt {
+          nxt.infer_fstr_kinds(kinds);
+        }
+      }
+      Stmt::InPlace { op: _, pat, val, nxt } => {
+        val.infer_fstr_kinds(&kinds);
+        forget(&mut kinds, pat_binds(pat));
+        if let Some(nxt) = nxt {
+          nxt.infer_fstr_kinds(kinds);
+        }
+      }
+      Stmt::If { cond, then, otherwise, nxt } => {
+        cond.infer_fstr_kinds(&kinds);
+        then.infer_fstr_kinds(kinds.clone());
+        otherwise.infer_fstr_kinds(kinds.clone());
+        if let Some(nxt) = nxt {
+          forget(&mut kinds, then.assigned_vars());
+          forget(&mut kinds, otherwise.assigned_vars());
+          nxt.infer_fstr_kinds(kinds);
+        }
+      }
+      Stmt::Match { arg, bnd, with_bnd, with_arg, arms, nxt }
+      | Stmt::Fold { arg, bnd, with_bnd, with_arg, arms, nxt } => {
+        arg.infer_fstr_kinds(&kinds);
+        with_arg.iter_mut().for_each(|arg| arg.infer_fstr_kinds(&kinds));
+        forget(&mut kinds, bnd.iter().chain(with_bnd.iter().flatten()).cloned());
+        for MatchArm { lft: _, guard, rgt } in arms.iter_mut() {
+          if let Some(guard) = guard {
+            guard.infer_fstr_kinds(&kinds);
+          }
+          rgt.infer_fstr_kinds(kinds.clone());
+        }
+        if let Some(nxt) = nxt {
+          arms.iter().for_each(|arm| forget(&mut kinds, arm.rgt.assigned_vars()));
+          nxt.infer_fstr_kinds(kinds);
+        }
+      }
+      Stmt::Switch { arg, bnd, with_bnd, with_arg, arms, nxt } => {
+        arg.infer_fstr_kinds(&kinds);
+        with_arg.iter_mut().for_each(|arg| arg.infer_fstr_kinds(&kinds));
+        forget(&mut kinds, bnd.iter().chain(with_bnd.iter().flatten()).cloned());
+        arms.iter_mut().for_each(|arm| arm.infer_fstr_kinds(kinds.clone()));
+        if let Some(nxt) = nxt {
+          arms.iter().for_each(|arm| forget(&mut kinds, arm.assigned_vars()));
+          nxt.infer_fstr_kinds(kinds);
+        }
+      }
+      Stmt::Bend { bnd, arg, cond, step, base, nxt } => {
+        arg.iter_mut().for_each(|arg| arg.infer_fstr_kinds(&kinds));
+        forget(&mut kinds, bnd.iter().flatten().cloned());
+        cond.infer_fstr_kinds(&kinds);
+        step.infer_fstr_kinds(kinds.clone());
+        base.infer_fstr_kinds(kinds.clone());
+        if let Some(nxt) = nxt {
+          forget(&mut kinds, step.assigned_vars());
+          forget(&mut kinds, base.assigned_vars());
+          nxt.infer_fstr_kinds(kinds);
+        }
+      }
+      // The variables assigned in the body of a loop can have the value of a previous iteration.
+      Stmt::While { cond, bod, state: _, nxt } => {
+        forget(&mut kinds, bod.assigned_vars());
+        cond.infer_fstr_kinds(&kinds);
+        bod.infer_fstr_kinds(kinds.clone());
+        if let Some(nxt) = nxt {
+          nxt.infer_fstr_kinds(kinds);
+        }
+      }
+      Stmt::For { pat, iter, bod, state: _, nxt } => {
+        iter.infer_fstr_kinds(&kinds);
+        forget(&mut kinds, bod.assigned_vars());
+        forget(&mut kinds, pat_binds(pat));
+        bod.infer_fstr_kinds(kinds.clone());
+        if let Some(nxt) = nxt {
+          nxt.infer_fstr_kinds(kinds);
+        }
+      }
+      Stmt::With { typ: _, bod, nxt } => {
+        bod.infer_fstr_kinds(kinds.clone());
+        if let Some(nxt) = nxt {
+          forget(&mut kinds, bod.assigned_vars());
+          nxt.infer_fstr_kinds(kinds);
+        }
+      }
+      Stmt::Return { term } => term.infer_fstr_kinds(&kinds),
+      Stmt::Open { typ: _, var, nxt } => {
+        kinds.remove(var);
+        nxt.infer_fstr_kinds(kinds);
+      }
+      Stmt::Use { nam, val, nxt } => {
+        val.infer_fstr_kinds(&kinds);
+        match kind_of(val, &kinds) {
+          Some(kind) => kinds.insert(nam.clone(), kind),
+          None => kinds.remove(nam),
+        };
+        nxt.infer_fstr_kinds(kinds);
+      }
+      Stmt::Err => {}
+    }
+  }
+}
+
+#[allow(clippy::mutable_key_type)] // Safe to allow, we know how `Name` works.
+impl Expr {
+  fn infer_fstr_kinds(&mut self, kinds: &Kinds) {
+    match self {
+      Expr::FStr { parts } => {
+        for part in parts {
+          if let FStrPart::Expr { val, show } = part {
+            val.infer_fstr_kinds(kinds);
+            if show.is_none() {
+              *show = kind_of(val, kinds).map(Name::new);
+            }
+          }
+        }
+      }
+      Expr::Lam { names, bod } => {
+        let mut kinds = kinds.clone();
+        forget(&mut kinds, names.iter().map(|(nam, _)| nam.clone()));
+        bod.infer_fstr_kinds(&kinds);
+      }
+      Expr::Block { block } => block.infer_fstr_kinds(kinds.clone()),
+      Expr::Call { fun, args, kwargs } => {
+        fun.infer_fstr_kinds(kinds);
+        args.iter_mut().for_each(|arg| arg.infer_fstr_kinds(kinds));
+        kwargs.iter_mut().for_each(|(_, arg)| arg.infer_fstr_kinds(kinds));
+      }
+      Expr::Opr { lhs, rhs, .. } | Expr::TreeNode { left: lhs, right: rhs } => {
+        lhs.infer_fstr_kinds(kinds);
+        rhs.infer_fstr_kinds(kinds);
+      }
+      Expr::Lst { els } | Expr::Tup { els } | Expr::Sup { els } => {
+        els.iter_mut().for_each(|el| el.infer_fstr_kinds(kinds));
+      }
+      Expr::Ctr { args, kwargs, .. } => {
+        args.iter_mut().for_each(|arg| arg.infer_fstr_kinds(kinds));
+        kwargs.iter_mut().for_each(|(_, arg)| arg.infer_fstr_kinds(kinds));
+      }
+      Expr::LstMap { term, bind, iter, cond } => {
+        iter.infer_fstr_kinds(kinds);
+        let mut kinds = kinds.clone();
+        kinds.remove(bind);
+        term.infer_fstr_kinds(&kinds);
+        if let Some(cond) = cond {
+          cond.infer_fstr_kinds(&kinds);
+        }
+      }
+      Expr::Map { entries } => {
+        for (key, val) in entries {
+          key.infer_fstr_kinds(kinds);
+          val.infer_fstr_kinds(kinds);
+        }
+      }
+      Expr::MapGet { key: val, .. } | Expr::TreeLeaf { val } | Expr::Try { val } | Expr::Proj { val, .. } => {
+        val.infer_fstr_kinds(kinds)
+      }
+      Expr::Upd { val, fields, .. } => {
+        val.infer_fstr_kinds(kinds);
+        fields.iter_mut().flat_map(|(_, val)| val).for_each(|val| val.infer_fstr_kinds(kinds));
+      }
+      Expr::Era | Expr::Var { .. } | Expr::Chn { .. } | Expr::Num { .. } | Expr::Str { .. } => {}
+    }
+  }
+}
+
+/// Returns the kind of a value from its form, or `None` if it can't be known.
+/// The result of an operation has the type of its second operand, and comparisons return an `u24`.
+#[allow(clippy::mutable_key_type)] // Safe to allow, we know how `Name` works.
+fn kind_of(val: &Expr, kinds: &Kinds) -> Option<&'static str> {
+  match val {
+    Expr::Str { .. } | Expr::FStr { .. } => Some("String"),
+    Expr::Num { val: Num::U24(_) } => Some("u24"),
+    Expr::Num { val: Num::I24(_) } => Some("i24"),
+    Expr::Num { val: Num::F24(_) } => Some("f24"),
+    Expr::Var { nam } => kinds.get(nam).copied(),
+    Expr::Opr { op: Op::EQ | Op::NEQ | Op::LT | Op::GT | Op::LE | Op::GE, .. } => Some("u24"),
+    Expr::Opr { rhs, .. } => kind_of(rhs, kinds),
+    _ => None,
+  }
+}
+
+fn pat_binds(pat: &AssignPattern) -> IndexSet<Name> {
+  let mut binds = IndexSet::new();
+  pat.binds(&mut binds);
+  binds
+}
+
+#[allow(clippy::mutable_key_type)] // Safe to allow, we know how `Name` works.
+fn forget(kinds: &mut Kinds, vars: impl IntoIterator<Item = Name>) {
+  for var in vars {
+    kinds.remove(&var);
+  }
+}
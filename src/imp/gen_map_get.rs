@@ -1,6 +1,6 @@
 use crate::fun::Name;
 
-use super::{AssignPattern, Definition, Expr, Stmt};
+use super::{AssignPattern, Definition, Expr, FStrPart, Stmt};
 
 impl Definition {
   /// Generates a map from `Stmt` to `Substitutions` for each definition in the program.
@@ -52,7 +52,9 @@ impl Stmt {
           Vec::new()
         };
 
-        nxt.gen_map_get(id);
+        if let Some(nxt) = nxt {
+          nxt.gen_map_get(id);
+        }
 
         let substitutions = val.substitute_map_gets(id);
         if !substitutions.is_empty() {
@@ -119,6 +121,23 @@ impl Stmt {
           *self = gen_get(self, substitutions);
         }
       }
+      // The condition is evaluated again on each iteration, so its map accesses can't be moved before the loop.
+      Stmt::While { cond: _, bod, state: _, nxt } => {
+        bod.gen_map_get(id);
+        if let Some(nxt) = nxt {
+          nxt.gen_map_get(id);
+        }
+      }
+      Stmt::For { pat: _, iter, bod, state: _, nxt } => {
+        bod.gen_map_get(id);
+        if let Some(nxt) = nxt {
+          nxt.gen_map_get(id);
+        }
+        let substitutions = iter.substitute_map_gets(id);
+        if !substitutions.is_empty() {
+          *self = gen_get(self, substitutions);
+        }
+      }
       Stmt::With { typ: _, bod, nxt } => {
         bod.gen_map_get(id);
         if let Some(nxt) = nxt {
@@ -149,6 +168,11 @@ impl Stmt {
 type Substitutions = Vec<(Name, Name, Box<Expr>)>;
 
 impl Expr {
+  /// Checks if the expression contains any map access, like `map[key]`.
+  pub fn has_map_get(&self) -> bool {
+    !self.clone().substitute_map_gets(&mut 0).is_empty()
+  }
+
   fn substitute_map_gets(&mut self, id: &mut usize) -> Substitutions {
     fn go(e: &mut Expr, substitutions: &mut Substitutions, id: &mut usize) {
       match e {
@@ -170,6 +194,7 @@ impl Expr {
         Expr::Lam { bod, .. } => {
           go(bod, substitutions, id);
         }
+        Expr::Block { block } => block.gen_map_get(id),
         Expr::Opr { lhs, rhs, .. } => {
           go(lhs, substitutions, id);
           go(rhs, substitutions, id);
@@ -200,8 +225,21 @@ impl Expr {
           go(left, substitutions, id);
           go(right, substitutions, id);
         }
-        Expr::TreeLeaf { val } => {
+        Expr::TreeLeaf { val } | Expr::Try { val } | Expr::Proj { val, .. } => {
+          go(val, substitutions, id);
+        }
+        Expr::Upd { val, fields, .. } => {
           go(val, substitutions, id);
+          for new_val in fields.iter_mut().filter_map(|(_, new_val)| new_val.as_mut()) {
+            go(new_val, substitutions, id);
+          }
+        }
+        Expr::FStr { parts } => {
+          for part in parts {
+            if let FStrPart::Expr { val, .. } = part {
+              go(val, substitutions, id);
+            }
+          }
         }
         Expr::Era | Expr::Str { .. } | Expr::Var { .. } | Expr::Chn { .. } | Expr::Num { .. } => {}
       }
@@ -0,0 +1,404 @@
+use crate::fun::{
+  self,
+  builtins::{OPTION_NONE, OPTION_SOME, RESULT_ERR, RESULT_OK},
+  parser::ParseBook,
+  Name, Pattern,
+};
+use std::collections::HashSet;
+
+use super::{AssignPattern, Definition, Expr, FStrPart, MatchArm, Stmt};
+
+/// The functions that visibly return an `Option`, since one of their results is an `Option` constructor.
+pub type OptionFns = HashSet<Name>;
+
+struct TryCtx<'a> {
+  id: usize,
+  option_fns: &'a OptionFns,
+}
+
+#[allow(clippy::mutable_key_type)] // Safe to allow, we know how `Name` works.
+impl ParseBook {
+  /// Finds the functions that visibly return an `Option`, on which the `?` operator can't be used.
+  pub fn option_fns(&self) -> OptionFns {
+    let fun_defs =
+      self.fun_defs.iter().filter(|(_, def)| def.rules.iter().any(|rule| returns_option(&rule.body)));
+    let imp_defs = self.imp_defs.iter().filter(|(_, def)| def.body.returns_option());
+    fun_defs.map(|(nam, _)| nam).chain(imp_defs.map(|(nam, _)| nam)).cloned().collect()
+  }
+}
+
+#[allow(clippy::mutable_key_type)] // Safe to allow, we know how `Name` works.
+impl Definition {
+  /// Desugars the `?` operator, matching on the `Result` of the expression it is applied to.
+  /// If it is an error, the function returns it early; otherwise, the rest of the function
+  /// continues with the `Ok` value in place of the expression.
+  pub fn gen_try(&mut self, option_fns: &OptionFns) -> Result<(), String> {
+    self.body.gen_try(true, &mut TryCtx { id: 0, option_fns })
+  }
+}
+
+impl Stmt {
+  /// `tail` indicates whether the statements that follow this one return from the function,
+  /// which is required to be able to return early from it.
+  fn gen_try(&mut self, tail: bool, ctx: &mut TryCtx) -> Result<(), String> {
+    match self {
+      Stmt::LocalDef { def, nxt } => {
+        nxt.gen_try(tail, ctx)?;
+        def.gen_try(ctx.option_fns)?;
+      }
+      Stmt::Assign { pat, val, nxt } => {
+        let mut substitutions =
+          if let AssignPattern::MapSet(_, key) = pat { key.substitute_tries(ctx)? } else { Vec::new() };
+        if let Some(nxt) = nxt {
+          nxt.gen_try(tail, ctx)?;
+        }
+        substitutions.extend(val.substitute_tries(ctx)?);
+        gen_match(self, substitutions, tail)?;
+      }
+      Stmt::InPlace { op: _, pat, val, nxt } => {
+        let mut substitutions = if let AssignPattern::MapSet(_, key) = &mut **pat {
+          key.substitute_tries(ctx)?
+        } else {
+          Vec::new()
+        };
+        if let Some(nxt) = nxt {
+          nxt.gen_try(tail, ctx)?;
+        }
+        substitutions.extend(val.substitute_tries(ctx)?);
+        gen_match(self, substitutions, tail)?;
+      }
+      Stmt::Ask { pat: _, val, nxt } => {
+        if let Some(nxt) = nxt {
+          nxt.gen_try(tail, ctx)?;
+        }
+        let substitutions = val.substitute_tries(ctx)?;
+        gen_match(self, substitutions, tail)?;
+      }
+      Stmt::If { cond, then, otherwise, nxt } => {
+        then.gen_try(tail && nxt.is_none(), ctx)?;
+        otherwise.gen_try(tail && nxt.is_none(), ctx)?;
+        if let Some(nxt) = nxt {
+          nxt.gen_try(tail, ctx)?;
+        }
+        let substitutions = cond.substitute_tries(ctx)?;
+        gen_match(self, substitutions, tail)?;
+      }
+      Stmt::Match { arg, bnd: _, with_bnd: _, with_arg, arms, nxt } => {
+        for arm in arms.iter_mut() {
+          arm.check_guard()?;
+          arm.rgt.gen_try(tail && nxt.is_none(), ctx)?;
+        }
+        if let Some(nxt) = nxt {
+          nxt.gen_try(tail, ctx)?;
+        }
+        let mut substitutions = arg.substitute_tries(ctx)?;
+        for arg in with_arg {
+          substitutions.extend(arg.substitute_tries(ctx)?);
+        }
+        gen_match(self, substitutions, tail)?;
+      }
+      Stmt::Switch { arg, bnd: _, with_bnd: _, with_arg, arms, nxt } => {
+        for arm in arms.iter_mut() {
+          arm.gen_try(tail && nxt.is_none(), ctx)?;
+        }
+        if let Some(nxt) = nxt {
+          nxt.gen_try(tail, ctx)?;
+        }
+        let mut substitutions = arg.substitute_tries(ctx)?;
+        for arg in with_arg {
+          substitutions.extend(arg.substitute_tries(ctx)?);
+        }
+        gen_match(self, substitutions, tail)?;
+      }
+      // The arms of a 'fold' and the branches of a 'bend' return from the recursive step, not from the function.
+      Stmt::Fold { arg, bnd: _, with_bnd: _, with_arg, arms, nxt } => {
+        for arm in arms.iter_mut() {
+          arm.check_guard()?;
+          arm.rgt.gen_try(false, ctx)?;
+        }
+        if let Some(nxt) = nxt {
+          nxt.gen_try(tail, ctx)?;
+        }
+        let mut substitutions = arg.substitute_tries(ctx)?;
+        for arg in with_arg {
+          substitutions.extend(arg.substitute_tries(ctx)?);
+        }
+        gen_match(self, substitutions, tail)?;
+      }
+      Stmt::Bend { bnd: _, arg: init, cond, step, base, nxt } => {
+        if cond.has_try() {
+          return Err("The '?' operator is not allowed in 'bend' conditions.".to_string());
+        }
+        step.gen_try(false, ctx)?;
+        base.gen_try(false, ctx)?;
+        if let Some(nxt) = nxt {
+          nxt.gen_try(tail, ctx)?;
+        }
+        let mut substitutions = Vec::new();
+        for init in init {
+          substitutions.extend(init.substitute_tries(ctx)?);
+        }
+        gen_match(self, substitutions, tail)?;
+      }
+      // Like a 'bend', the body of a loop runs in a recursive step and not directly in the function.
+      Stmt::While { cond, bod, state: _, nxt } => {
+        if cond.has_try() {
+          return Err("The '?' operator is not allowed in 'while' conditions.".to_string());
+        }
+        bod.gen_try(false, ctx)?;
+        if let Some(nxt) = nxt {
+          nxt.gen_try(tail, ctx)?;
+        }
+      }
+      Stmt::For { pat: _, iter, bod, state: _, nxt } => {
+        bod.gen_try(false, ctx)?;
+        if let Some(nxt) = nxt {
+          nxt.gen_try(tail, ctx)?;
+        }
+        let substitutions = iter.substitute_tries(ctx)?;
+        gen_match(self, substitutions, tail)?;
+      }
+      Stmt::With { typ: _, bod, nxt } => {
+        bod.gen_try(tail && nxt.is_none(), ctx)?;
+        if let Some(nxt) = nxt {
+          nxt.gen_try(tail, ctx)?;
+        }
+      }
+      Stmt::Return { term } => {
+        let substitutions = term.substitute_tries(ctx)?;
+        gen_match(self, substitutions, tail)?;
+      }
+      Stmt::Open { typ: _, var: _, nxt } => {
+        nxt.gen_try(tail, ctx)?;
+      }
+      Stmt::Use { nam: _, val, nxt } => {
+        nxt.gen_try(tail, ctx)?;
+        let substitutions = val.substitute_tries(ctx)?;
+        gen_match(self, substitutions, tail)?;
+      }
+      Stmt::Err => {}
+    }
+    Ok(())
+  }
+}
+
+impl MatchArm {
+  fn check_guard(&self) -> Result<(), String> {
+    match &self.guard {
+      Some(guard) if guard.has_try() => Err("The '?' operator is not allowed in 'case' guards.".to_string()),
+      _ => Ok(()),
+    }
+  }
+}
+
+type Substitutions = Vec<(Name, Expr)>;
+
+impl Expr {
+  /// Checks if the expression uses the `?` operator.
+  pub fn has_try(&self) -> bool {
+    let mut ctx = TryCtx { id: 0, option_fns: &OptionFns::new() };
+    !matches!(self.clone().substitute_tries(&mut ctx), Ok(substitutions) if substitutions.is_empty())
+  }
+
+  /// Replaces each `val?` with a variable holding its `Ok` value,
+  /// returning the expressions that must be matched on before this one.
+  fn substitute_tries(&mut self, ctx: &mut TryCtx) -> Result<Substitutions, String> {
+    fn go(e: &mut Expr, substitutions: &mut Substitutions, ctx: &mut TryCtx) -> Result<(), String> {
+      match e {
+        Expr::Try { val } => {
+          if val.is_option(ctx.option_fns) {
+            return Err(
+              "The '?' operator can only be used on a 'Result', but it was used on an 'Option'. Use a 'match' or a 'with Option' block instead."
+                .to_string(),
+            );
+          }
+          go(val, substitutions, ctx)?;
+          let new_var = gen_try_var(&mut ctx.id);
+          substitutions.push((new_var.clone(), std::mem::replace(val.as_mut(), Expr::Era)));
+          *e = Expr::Var { nam: Name::new(format!("{new_var}.val")) };
+        }
+        // The lambdas with a block body return from the block, like a function.
+        // Since they don't have any `?` left, this doesn't add any substitutions.
+        Expr::Lam { bod, .. } => {
+          if !matches!(bod.as_ref(), Expr::Block { .. }) && bod.has_try() {
+            return Err("The '?' operator is not allowed inside a lambda.".to_string());
+          }
+          go(bod, substitutions, ctx)?;
+        }
+        Expr::Block { block } => block.gen_try(true, ctx)?,
+        Expr::LstMap { term, iter, cond, .. } => {
+          if term.has_try() || cond.as_ref().is_some_and(|cond| cond.has_try()) {
+            return Err("The '?' operator is not allowed inside a list comprehension.".to_string());
+          }
+          go(term, substitutions, ctx)?;
+          if let Some(cond) = cond {
+            go(cond, substitutions, ctx)?;
+          }
+          go(iter, substitutions, ctx)?;
+        }
+        Expr::Call { fun, args, kwargs } => {
+          go(fun, substitutions, ctx)?;
+          for arg in args {
+            go(arg, substitutions, ctx)?;
+          }
+          for (_, arg) in kwargs {
+            go(arg, substitutions, ctx)?;
+          }
+        }
+        Expr::Opr { lhs, rhs, .. } => {
+          go(lhs, substitutions, ctx)?;
+          go(rhs, substitutions, ctx)?;
+        }
+        Expr::Lst { els } | Expr::Tup { els } | Expr::Sup { els } => {
+          for el in els {
+            go(el, substitutions, ctx)?;
+          }
+        }
+        Expr::Ctr { args, kwargs, .. } => {
+          for arg in args {
+            go(arg, substitutions, ctx)?;
+          }
+          for (_, arg) in kwargs {
+            go(arg, substitutions, ctx)?;
+          }
+        }
+        Expr::Map { entries } => {
+          for (key, val) in entries {
+            go(key, substitutions, ctx)?;
+            go(val, substitutions, ctx)?;
+          }
+        }
+        Expr::MapGet { key, .. } => {
+          go(key, substitutions, ctx)?;
+        }
+        Expr::TreeNode { left, right } => {
+          go(left, substitutions, ctx)?;
+          go(right, substitutions, ctx)?;
+        }
+        Expr::TreeLeaf { val } | Expr::Proj { val, .. } => {
+          go(val, substitutions, ctx)?;
+        }
+        Expr::Upd { val, fields, .. } => {
+          go(val, substitutions, ctx)?;
+          for new_val in fields.iter_mut().filter_map(|(_, new_val)| new_val.as_mut()) {
+            go(new_val, substitutions, ctx)?;
+          }
+        }
+        Expr::FStr { parts } => {
+          for part in parts {
+            if let FStrPart::Expr { val, .. } = part {
+              go(val, substitutions, ctx)?;
+            }
+          }
+        }
+        Expr::Era | Expr::Str { .. } | Expr::Var { .. } | Expr::Chn { .. } | Expr::Num { .. } => {}
+      }
+      Ok(())
+    }
+    let mut substitutions = Substitutions::new();
+    go(self, &mut substitutions, ctx)?;
+    Ok(substitutions)
+  }
+}
+
+/// Wraps the statement in a match on each of the substituted `Result`s.
+fn gen_match(current: &mut Stmt, substitutions: Substitutions, tail: bool) -> Result<(), String> {
+  if substitutions.is_empty() {
+    return Ok(());
+  }
+  if !tail {
+    return Err(
+      "The '?' operator can only be used where the statements that follow return from the function."
+        .to_string(),
+    );
+  }
+  *current = substitutions.into_iter().rfold(std::mem::take(current), |acc, (var, val)| {
+    let err_val = Expr::Var { nam: Name::new(format!("{var}.val")) };
+    let err = Expr::Call {
+      fun: Box::new(Expr::Var { nam: Name::new(RESULT_ERR) }),
+      args: vec![err_val],
+      kwargs: vec![],
+    };
+    let arms = vec![
+      MatchArm { lft: Pattern::Var(Some(Name::new(RESULT_OK))), guard: None, rgt: acc },
+      MatchArm {
+        lft: Pattern::Var(Some(Name::new(RESULT_ERR))),
+        guard: None,
+        rgt: Stmt::Return { term: Box::new(err) },
+      },
+    ];
+    Stmt::Match { arg: Box::new(val), bnd: Some(var), with_bnd: vec![], with_arg: vec![], arms, nxt: None }
+  });
+  Ok(())
+}
+
+fn gen_try_var(id: &mut usize) -> Name {
+  let name = Name::new(format!("try%{}", id));
+  *id += 1;
+  name
+}
+
+#[allow(clippy::mutable_key_type)] // Safe to allow, we know how `Name` works.
+impl Expr {
+  /// Checks if the expression is visibly an `Option`:
+  /// an `Option` constructor or a call to a function that visibly returns one.
+  fn is_option(&self, option_fns: &OptionFns) -> bool {
+    match self {
+      Expr::Var { nam } => nam == OPTION_NONE,
+      Expr::Ctr { name, .. } => name == OPTION_SOME || name == OPTION_NONE,
+      Expr::Call { fun, .. } => match fun.as_ref() {
+        Expr::Var { nam } => nam == OPTION_SOME || option_fns.contains(nam),
+        _ => false,
+      },
+      _ => false,
+    }
+  }
+}
+
+impl Stmt {
+  /// Checks if one of the values returned by this block is an `Option` constructor.
+  /// The returns inside a 'fold' or 'bend' are from their recursive step, so they are not considered.
+  fn returns_option(&self) -> bool {
+    let nxt_returns = |nxt: &Option<Box<Stmt>>| nxt.as_ref().is_some_and(|nxt| nxt.returns_option());
+    match self {
+      Stmt::Return { term } => term.is_option(&OptionFns::new()),
+      Stmt::If { then, otherwise, nxt, .. } => {
+        then.returns_option() || otherwise.returns_option() || nxt_returns(nxt)
+      }
+      Stmt::Match { arms, nxt, .. } => arms.iter().any(|arm| arm.rgt.returns_option()) || nxt_returns(nxt),
+      Stmt::Switch { arms, nxt, .. } => arms.iter().any(Stmt::returns_option) || nxt_returns(nxt),
+      Stmt::With { bod, nxt, .. } => bod.returns_option() || nxt_returns(nxt),
+      Stmt::Assign { nxt, .. }
+      | Stmt::Ask { nxt, .. }
+      | Stmt::InPlace { nxt, .. }
+      | Stmt::Bend { nxt, .. }
+      | Stmt::Fold { nxt, .. }
+      | Stmt::While { nxt, .. }
+      | Stmt::For { nxt, .. } => nxt_returns(nxt),
+      Stmt::LocalDef { nxt, .. } | Stmt::Open { nxt, .. } | Stmt::Use { nxt, .. } => nxt.returns_option(),
+      Stmt::Err => false,
+    }
+  }
+}
+
+/// Checks if one of the results of a functional term is an `Option` constructor.
+fn returns_option(term: &fun::Term) -> bool {
+  match term {
+    fun::Term::Var { nam } | fun::Term::Ref { nam } => nam == OPTION_NONE,
+    fun::Term::App { fun, .. } => {
+      let mut fun = fun.as_ref();
+      while let fun::Term::App { fun: head, .. } = fun {
+        fun = head;
+      }
+      matches!(fun, fun::Term::Var { nam } | fun::Term::Ref { nam } if nam == OPTION_SOME)
+    }
+    fun::Term::Let { nxt, .. }
+    | fun::Term::Use { nxt, .. }
+    | fun::Term::Ask { nxt, .. }
+    | fun::Term::Def { nxt, .. }
+    | fun::Term::Open { bod: nxt, .. } => returns_option(nxt),
+    fun::Term::Mat { arms, .. } => arms.iter().any(|(_, _, bod, _)| returns_option(bod)),
+    fun::Term::Swt { arms, .. } => arms.iter().any(returns_option),
+    _ => false,
+  }
+}
@@ -0,0 +1,360 @@
+use super::{AssignPattern, Definition, Expr, FStrPart, Stmt};
+use crate::fun::Name;
+use indexmap::IndexSet;
+
+impl Definition {
+  /// Finds the state of each `while` and `for` loop of the definition:
+  /// the variables already defined before the loop that are assigned inside its body.
+  ///
+  /// They are passed to each iteration of the loop and hold their final values after it.
+  /// The other variables assigned in the body are local to a single iteration.
+  pub fn find_loop_state(&mut self) {
+    let scope = self.params.iter().cloned().collect();
+    self.body.find_loop_state(&scope);
+  }
+}
+
+impl Stmt {
+  fn find_loop_state(&mut self, scope: &IndexSet<Name>) {
+    match self {
+      Stmt::LocalDef { def, nxt } => {
+        def.find_loop_state();
+        nxt.find_loop_state(scope);
+      }
+      Stmt::Assign { pat, val, nxt } | Stmt::Ask { pat, val, nxt } => {
+        val.find_loop_state(scope);
+        if let Some(nxt) = nxt {
+          let mut scope = scope.clone();
+          pat.binds(&mut scope);
+          nxt.find_loop_state(&scope);
+        }
+      }
+      Stmt::InPlace { op: _, pat: _, val, nxt } => {
+        val.find_loop_state(scope);
+        if let Some(nxt) = nxt {
+          nxt.find_loop_state(scope);
+        }
+      }
+      Stmt::If { cond, then, otherwise, nxt } => {
+        cond.find_loop_state(scope);
+        then.find_loop_state(scope);
+        otherwise.find_loop_state(scope);
+        if let Some(nxt) = nxt {
+          nxt.find_loop_state(&then.scope_after(scope));
+        }
+      }
+      Stmt::Match { arg, bnd, with_bnd, with_arg, arms, nxt }
+      | Stmt::Fold { arg, bnd, with_bnd, with_arg, arms, nxt } => {
+        arg.find_loop_state(scope);
+        with_arg.iter_mut().for_each(|arg| arg.find_loop_state(scope));
+        let mut arm_scope = scope.clone();
+        arm_scope.extend(bnd.iter().chain(with_bnd.iter().flatten()).cloned());
+        for arm in arms.iter_mut() {
+          if let Some(guard) = &mut arm.guard {
+            guard.find_loop_state(&arm_scope);
+          }
+          arm.rgt.find_loop_state(&arm_scope);
+        }
+        if let Some(nxt) = nxt {
+          nxt.find_loop_state(&arms[0].rgt.scope_after(scope));
+        }
+      }
+      Stmt::Switch { arg, bnd, with_bnd, with_arg, arms, nxt } => {
+        arg.find_loop_state(scope);
+        with_arg.iter_mut().for_each(|arg| arg.find_loop_state(scope));
+        let mut arm_scope = scope.clone();
+        arm_scope.extend(with_bnd.iter().flatten().cloned());
+        if let Some(bnd) = bnd {
+          arm_scope.insert(Name::new(format!("{bnd}-1")));
+          arm_scope.insert(bnd.clone());
+        }
+        for arm in arms.iter_mut() {
+          arm.find_loop_state(&arm_scope);
+        }
+        if let Some(nxt) = nxt {
+          nxt.find_loop_state(&arms[0].scope_after(scope));
+        }
+      }
+      Stmt::Bend { bnd, arg, cond, step, base, nxt } => {
+        arg.iter_mut().for_each(|arg| arg.find_loop_state(scope));
+        let mut bend_scope = scope.clone();
+        bend_scope.extend(bnd.iter().flatten().cloned());
+        cond.find_loop_state(&bend_scope);
+        step.find_loop_state(&bend_scope);
+        base.find_loop_state(&bend_scope);
+        if let Some(nxt) = nxt {
+          nxt.find_loop_state(&step.scope_after(scope));
+        }
+      }
+      Stmt::While { cond, bod, state, nxt } => {
+        *state = bod.assigned_vars().into_iter().filter(|var| scope.contains(var)).collect();
+        cond.find_loop_state(scope);
+        bod.find_loop_state(scope);
+        if let Some(nxt) = nxt {
+          nxt.find_loop_state(scope);
+        }
+      }
+      Stmt::For { pat, iter, bod, state, nxt } => {
+        let mut pat_vars = IndexSet::new();
+        pat.binds(&mut pat_vars);
+        *state = bod
+          .assigned_vars()
+          .into_iter()
+          .filter(|var| scope.contains(var) && !pat_vars.contains(var))
+          .collect();
+        iter.find_loop_state(scope);
+        let mut bod_scope = scope.clone();
+        bod_scope.extend(pat_vars);
+        bod.find_loop_state(&bod_scope);
+        if let Some(nxt) = nxt {
+          nxt.find_loop_state(scope);
+        }
+      }
+      Stmt::With { typ: _, bod, nxt } => {
+        bod.find_loop_state(scope);
+        if let Some(nxt) = nxt {
+          nxt.find_loop_state(&bod.scope_after(scope));
+        }
+      }
+      Stmt::Return { term } => term.find_loop_state(scope),
+      Stmt::Open { typ: _, var: _, nxt } => nxt.find_loop_state(scope),
+      Stmt::Use { nam: _, val, nxt } => {
+        val.find_loop_state(scope);
+        nxt.find_loop_state(scope);
+      }
+      Stmt::Err => {}
+    }
+  }
+
+  /// Returns the scope after a block of statements that ends with an assignment,
+  /// extending `scope` with the variables of the final assignment.
+  fn scope_after(&self, scope: &IndexSet<Name>) -> IndexSet<Name> {
+    let mut scope = scope.clone();
+    let mut stmt = self;
+    loop {
+      match stmt {
+        Stmt::Assign { nxt: Some(nxt), .. }
+        | Stmt::Ask { nxt: Some(nxt), .. }
+        | Stmt::InPlace { nxt: Some(nxt), .. }
+        | Stmt::If { nxt: Some(nxt), .. }
+        | Stmt::Match { nxt: Some(nxt), .. }
+        | Stmt::Switch { nxt: Some(nxt), .. }
+        | Stmt::Bend { nxt: Some(nxt), .. }
+        | Stmt::Fold { nxt: Some(nxt), .. }
+        | Stmt::While { nxt: Some(nxt), .. }
+        | Stmt::For { nxt: Some(nxt), .. }
+        | Stmt::With { nxt: Some(nxt), .. }
+        | Stmt::Open { nxt, .. }
+        | Stmt::Use { nxt, .. }
+        | Stmt::LocalDef { nxt, .. } => stmt = nxt,
+        Stmt::Assign { pat, nxt: None, .. } | Stmt::Ask { pat, nxt: None, .. } => {
+          pat.binds(&mut scope);
+          return scope;
+        }
+        Stmt::If { then: stmt_, nxt: None, .. }
+        | Stmt::Bend { step: stmt_, nxt: None, .. }
+        | Stmt::With { bod: stmt_, nxt: None, .. } => stmt = stmt_,
+        Stmt::Match { arms, nxt: None, .. } | Stmt::Fold { arms, nxt: None, .. } => stmt = &arms[0].rgt,
+        Stmt::Switch { arms, nxt: None, .. } => stmt = &arms[0],
+        Stmt::InPlace { nxt: None, .. }
+        | Stmt::While { nxt: None, .. }
+        | Stmt::For { nxt: None, .. }
+        | Stmt::Return { .. }
+        | Stmt::Err => return scope,
+      }
+    }
+  }
+
+  /// Returns the variables of the final assignment of a block of statements,
+  /// joining the ones of all the branches when it ends with an `if`, `match` or `switch`.
+  /// Returns `None` if a path of the block doesn't end with a plain assignment.
+  pub(super) fn final_binds(&self) -> Option<IndexSet<Name>> {
+    match self {
+      Stmt::Assign { nxt: Some(nxt), .. }
+      | Stmt::Ask { nxt: Some(nxt), .. }
+      | Stmt::InPlace { nxt: Some(nxt), .. }
+      | Stmt::If { nxt: Some(nxt), .. }
+      | Stmt::Match { nxt: Some(nxt), .. }
+      | Stmt::Switch { nxt: Some(nxt), .. }
+      | Stmt::Bend { nxt: Some(nxt), .. }
+      | Stmt::Fold { nxt: Some(nxt), .. }
+      | Stmt::While { nxt: Some(nxt), .. }
+      | Stmt::For { nxt: Some(nxt), .. }
+      | Stmt::With { nxt: Some(nxt), .. }
+      | Stmt::Open { nxt, .. }
+      | Stmt::Use { nxt, .. }
+      | Stmt::LocalDef { nxt, .. } => nxt.final_binds(),
+      Stmt::Assign { pat, nxt: None, .. } => {
+        let mut vars = IndexSet::new();
+        pat.binds(&mut vars);
+        Some(vars)
+      }
+      Stmt::InPlace { pat, nxt: None, .. } => {
+        let mut vars = IndexSet::new();
+        pat.binds(&mut vars);
+        Some(vars)
+      }
+      Stmt::While { state, nxt: None, .. } | Stmt::For { state, nxt: None, .. } => {
+        Some(state.iter().cloned().collect())
+      }
+      Stmt::If { then, otherwise, nxt: None, .. } => {
+        let mut vars = then.final_binds()?;
+        vars.extend(otherwise.final_binds()?);
+        Some(vars)
+      }
+      Stmt::Match { arms, nxt: None, .. } => {
+        arms.iter().map(|arm| arm.rgt.final_binds()).try_fold(IndexSet::new(), |mut vars, arm| {
+          vars.extend(arm?);
+          Some(vars)
+        })
+      }
+      Stmt::Switch { arms, nxt: None, .. } => {
+        arms.iter().map(Stmt::final_binds).try_fold(IndexSet::new(), |mut vars, arm| {
+          vars.extend(arm?);
+          Some(vars)
+        })
+      }
+      Stmt::Ask { nxt: None, .. }
+      | Stmt::Bend { nxt: None, .. }
+      | Stmt::Fold { nxt: None, .. }
+      | Stmt::With { nxt: None, .. }
+      | Stmt::Return { .. }
+      | Stmt::Err => None,
+    }
+  }
+
+  /// Returns the variables assigned anywhere in a block of statements, in order of appearance.
+  pub(super) fn assigned_vars(&self) -> IndexSet<Name> {
+    fn go(stmt: &Stmt, vars: &mut IndexSet<Name>) {
+      match stmt {
+        Stmt::Assign { pat, nxt, .. } | Stmt::Ask { pat, nxt, .. } => {
+          pat.binds(vars);
+          if let Some(nxt) = nxt {
+            go(nxt, vars);
+          }
+        }
+        Stmt::InPlace { pat, nxt, .. } => {
+          pat.binds(vars);
+          if let Some(nxt) = nxt {
+            go(nxt, vars);
+          }
+        }
+        Stmt::If { then, otherwise, nxt, .. } => {
+          go(then, vars);
+          go(otherwise, vars);
+          if let Some(nxt) = nxt {
+            go(nxt, vars);
+          }
+        }
+        Stmt::Match { arms, nxt, .. } | Stmt::Fold { arms, nxt, .. } => {
+          for arm in arms {
+            go(&arm.rgt, vars);
+          }
+          if let Some(nxt) = nxt {
+            go(nxt, vars);
+          }
+        }
+        Stmt::Switch { arms, nxt, .. } => {
+          for arm in arms {
+            go(arm, vars);
+          }
+          if let Some(nxt) = nxt {
+            go(nxt, vars);
+          }
+        }
+        Stmt::Bend { step, base, nxt, .. } => {
+          go(step, vars);
+          go(base, vars);
+          if let Some(nxt) = nxt {
+            go(nxt, vars);
+          }
+        }
+        Stmt::While { bod, nxt, .. } | Stmt::For { bod, nxt, .. } | Stmt::With { bod, nxt, .. } => {
+          go(bod, vars);
+          if let Some(nxt) = nxt {
+            go(nxt, vars);
+          }
+        }
+        Stmt::Open { nxt, .. } | Stmt::Use { nxt, .. } | Stmt::LocalDef { nxt, .. } => go(nxt, vars),
+        Stmt::Return { .. } | Stmt::Err => {}
+      }
+    }
+    let mut vars = IndexSet::new();
+    go(self, &mut vars);
+    vars
+  }
+}
+
+impl AssignPattern {
+  /// Adds the variables bound by this pattern to `vars`.
+  /// A map assignment rebinds the map variable.
+  pub(super) fn binds(&self, vars: &mut IndexSet<Name>) {
+    match self {
+      AssignPattern::Var(nam) | AssignPattern::MapSet(nam, _) => {
+        vars.insert(nam.clone());
+      }
+      AssignPattern::Tup(pats) | AssignPattern::Sup(pats) => pats.iter().for_each(|pat| pat.binds(vars)),
+      AssignPattern::Eraser | AssignPattern::Chn(_) => {}
+    }
+  }
+}
+
+impl Expr {
+  /// Finds the state of the loops inside the block lambdas of this expression.
+  fn find_loop_state(&mut self, scope: &IndexSet<Name>) {
+    match self {
+      Expr::Lam { names, bod } => {
+        let mut scope = scope.clone();
+        scope.extend(names.iter().filter(|(_, link)| !link).map(|(nam, _)| nam.clone()));
+        bod.find_loop_state(&scope);
+      }
+      Expr::Block { block } => block.find_loop_state(scope),
+      Expr::Call { fun, args, kwargs } => {
+        fun.find_loop_state(scope);
+        args.iter_mut().for_each(|arg| arg.find_loop_state(scope));
+        kwargs.iter_mut().for_each(|(_, arg)| arg.find_loop_state(scope));
+      }
+      Expr::Opr { lhs, rhs, .. } | Expr::TreeNode { left: lhs, right: rhs } => {
+        lhs.find_loop_state(scope);
+        rhs.find_loop_state(scope);
+      }
+      Expr::Lst { els } | Expr::Tup { els } | Expr::Sup { els } => {
+        els.iter_mut().for_each(|el| el.find_loop_state(scope));
+      }
+      Expr::Ctr { args, kwargs, .. } => {
+        args.iter_mut().for_each(|arg| arg.find_loop_state(scope));
+        kwargs.iter_mut().for_each(|(_, arg)| arg.find_loop_state(scope));
+      }
+      Expr::LstMap { term, bind, iter, cond } => {
+        iter.find_loop_state(scope);
+        let mut scope = scope.clone();
+        scope.insert(bind.clone());
+        term.find_loop_state(&scope);
+        if let Some(cond) = cond {
+          cond.find_loop_state(&scope);
+        }
+      }
+      Expr::Map { entries } => {
+        for (key, val) in entries {
+          key.find_loop_state(scope);
+          val.find_loop_state(scope);
+        }
+      }
+      Expr::FStr { parts } => {
+        for part in parts {
+          if let FStrPart::Expr { val, .. } = part {
+            val.find_loop_state(scope);
+          }
+        }
+      }
+      Expr::MapGet { key: val, .. } | Expr::TreeLeaf { val } | Expr::Try { val } | Expr::Proj { val, .. } => {
+        val.find_loop_state(scope)
+      }
+      Expr::Upd { val, fields, .. } => {
+        val.find_loop_state(scope);
+        fields.iter_mut().flat_map(|(_, val)| val).for_each(|val| val.find_loop_state(scope));
+      }
+      Expr::Era | Expr::Var { .. } | Expr::Chn { .. } | Expr::Num { .. } | Expr::Str { .. } => {}
+    }
+  }
+}
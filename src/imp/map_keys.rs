@@ -0,0 +1,477 @@
+use super::{AssignPattern, Definition, Expr, FStrPart, MatchArm, Stmt};
+use crate::fun::Name;
+
+/// The kind of the keys of a map, which decides how they are stored.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyKind {
+  /// Keys used directly as the path in the tree.
+  U24,
+  /// Keys hashed with `String/hash` and compared with `String/equals`.
+  Str,
+  /// Keys hashed and compared with the combined functions of each element.
+  Tup(Vec<KeyKind>),
+}
+
+impl KeyKind {
+  /// Returns the kind of a key from its form, or `None` if it can't be known.
+  /// The elements of a tuple key that can't be known are taken as `u24`.
+  pub fn of(key: &Expr) -> Option<KeyKind> {
+    match key {
+      Expr::Num { .. } => Some(KeyKind::U24),
+      Expr::Str { .. } | Expr::FStr { .. } => Some(KeyKind::Str),
+      Expr::Tup { els } => {
+        Some(KeyKind::Tup(els.iter().map(|el| KeyKind::of(el).unwrap_or(KeyKind::U24)).collect()))
+      }
+      _ => None,
+    }
+  }
+
+  /// Returns the single kind of all the given keys, or an error if there is more than one.
+  /// Keys whose kind can't be known are ignored.
+  pub fn of_keys<'a>(keys: impl IntoIterator<Item = &'a Expr>) -> Result<Option<KeyKind>, String> {
+    let mut kind = None;
+    for key in keys {
+      kind = KeyKind::join(kind, KeyKind::of(key))
+        .map_err(|e| format!("Map created with keys of different kinds: {e}."))?;
+    }
+    Ok(kind)
+  }
+
+  fn join(a: Option<KeyKind>, b: Option<KeyKind>) -> Result<Option<KeyKind>, String> {
+    match (a, b) {
+      (Some(a), Some(b)) if a != b => Err(format!("{a} and {b}")),
+      (a, b) => Ok(a.or(b)),
+    }
+  }
+
+  /// Returns the hash and equality functions for keys of this kind,
+  /// or `None` if they are used directly as `u24` keys.
+  pub fn functions(&self) -> Option<(Expr, Expr)> {
+    match self {
+      KeyKind::U24 => None,
+      KeyKind::Str => Some((var("String/hash"), var("String/equals"))),
+      KeyKind::Tup(els) => {
+        let mut funs =
+          els.iter().map(|el| el.functions().unwrap_or_else(|| (var("Map/hash_u24"), var("Map/equals_u24"))));
+        let last = funs.next_back()?;
+        Some(funs.rfold(last, |(hash_b, equals_b), (hash_a, equals_a)| {
+          (call("Map/hash_pair", vec![hash_a, hash_b]), call("Map/equals_pair", vec![equals_a, equals_b]))
+        }))
+      }
+    }
+  }
+
+  /// Builds the expression that creates a map with keys of this kind and the given entries.
+  pub fn init(kind: Option<&KeyKind>, entries: Vec<(Expr, Expr)>) -> Expr {
+    let empty = match kind.and_then(KeyKind::functions) {
+      Some((hash, equals)) => call("Map/hashed", vec![hash, equals]),
+      None => var("Map/empty"),
+    };
+    entries.into_iter().fold(empty, |map, (key, value)| call("Map/set", vec![map, key, value]))
+  }
+}
+
+impl std::fmt::Display for KeyKind {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      KeyKind::U24 => write!(f, "u24"),
+      KeyKind::Str => write!(f, "String"),
+      KeyKind::Tup(els) => {
+        let els = els.iter().map(|el| el.to_string()).collect::<Vec<_>>();
+        write!(f, "({})", els.join(", "))
+      }
+    }
+  }
+}
+
+fn var(nam: &str) -> Expr {
+  Expr::Var { nam: Name::new(nam) }
+}
+
+fn call(fun: &str, args: Vec<Expr>) -> Expr {
+  Expr::Call { fun: Box::new(var(fun)), args, kwargs: vec![] }
+}
+
+impl Definition {
+  /// Chooses the kind of keys of the maps created in this definition.
+  ///
+  /// A map literal assigned to a variable takes its kind from its own keys
+  /// and from the keys used to read and write that variable afterwards,
+  /// so `m = {}` followed by `m["a"] = 1` creates a map with string keys.
+  /// A map created with `Map/empty` only takes `u24` keys.
+  ///
+  /// Returns an error if keys of different kinds are used with the same map.
+  pub fn choose_map_keys(&mut self) -> Result<(), String> {
+    self.body.choose_map_keys()
+  }
+}
+
+impl Stmt {
+  fn choose_map_keys(&mut self) -> Result<(), String> {
+    match self {
+      Stmt::LocalDef { def, nxt } => {
+        def.choose_map_keys().map_err(|e| format!("In function '{}': {e}", def.name))?;
+        nxt.choose_map_keys()?;
+      }
+      Stmt::Assign { pat, val, nxt } => {
+        if let (AssignPattern::Var(nam), Some(nxt)) = (&*pat, nxt.as_deref()) {
+          let mut uses = Vec::new();
+          nxt.map_key_uses(nam, &mut uses);
+          if !uses.is_empty() {
+            choose_var_keys(nam, val, uses)?;
+          }
+        }
+        pat.choose_map_keys()?;
+        val.choose_map_keys()?;
+        if let Some(nxt) = nxt {
+          nxt.choose_map_keys()?;
+        }
+      }
+      Stmt::InPlace { pat, val, nxt, .. } => {
+        pat.choose_map_keys()?;
+        val.choose_map_keys()?;
+        if let Some(nxt) = nxt {
+          nxt.choose_map_keys()?;
+        }
+      }
+      Stmt::Ask { pat: _, val, nxt } => {
+        val.choose_map_keys()?;
+        if let Some(nxt) = nxt {
+          nxt.choose_map_keys()?;
+        }
+      }
+      Stmt::If { cond, then, otherwise, nxt } => {
+        cond.choose_map_keys()?;
+        then.choose_map_keys()?;
+        otherwise.choose_map_keys()?;
+        if let Some(nxt) = nxt {
+          nxt.choose_map_keys()?;
+        }
+      }
+      Stmt::Match { arg, with_arg, arms, nxt, .. } | Stmt::Fold { arg, with_arg, arms, nxt, .. } => {
+        arg.choose_map_keys()?;
+        for arg in with_arg {
+          arg.choose_map_keys()?;
+        }
+        for MatchArm { lft: _, guard, rgt } in arms {
+          if let Some(guard) = guard {
+            guard.choose_map_keys()?;
+          }
+          rgt.choose_map_keys()?;
+        }
+        if let Some(nxt) = nxt {
+          nxt.choose_map_keys()?;
+        }
+      }
+      Stmt::Switch { arg, with_arg, arms, nxt, .. } => {
+        arg.choose_map_keys()?;
+        for arg in with_arg {
+          arg.choose_map_keys()?;
+        }
+        for arm in arms {
+          arm.choose_map_keys()?;
+        }
+        if let Some(nxt) = nxt {
+          nxt.choose_map_keys()?;
+        }
+      }
+      Stmt::Bend { bnd: _, arg, cond, step, base, nxt } => {
+        for arg in arg {
+          arg.choose_map_keys()?;
+        }
+        cond.choose_map_keys()?;
+        step.choose_map_keys()?;
+        base.choose_map_keys()?;
+        if let Some(nxt) = nxt {
+          nxt.choose_map_keys()?;
+        }
+      }
+      Stmt::While { cond, bod, state: _, nxt } => {
+        cond.choose_map_keys()?;
+        bod.choose_map_keys()?;
+        if let Some(nxt) = nxt {
+          nxt.choose_map_keys()?;
+        }
+      }
+      Stmt::For { pat: _, iter, bod, state: _, nxt } => {
+        iter.choose_map_keys()?;
+        bod.choose_map_keys()?;
+        if let Some(nxt) = nxt {
+          nxt.choose_map_keys()?;
+        }
+      }
+      Stmt::With { typ: _, bod, nxt } => {
+        bod.choose_map_keys()?;
+        if let Some(nxt) = nxt {
+          nxt.choose_map_keys()?;
+        }
+      }
+      Stmt::Return { term } => term.choose_map_keys()?,
+      Stmt::Open { typ: _, var: _, nxt } => nxt.choose_map_keys()?,
+      Stmt::Use { nam: _, val, nxt } => {
+        val.choose_map_keys()?;
+        nxt.choose_map_keys()?;
+      }
+      Stmt::Err => {}
+    }
+    Ok(())
+  }
+
+  /// Collects the kinds of the keys used to read and write the map in `nam`,
+  /// until the variable is assigned something else.
+  fn map_key_uses(&self, nam: &Name, uses: &mut Vec<KeyKind>) {
+    let nxt = match self {
+      Stmt::Assign { pat, val, nxt } | Stmt::Ask { pat, val, nxt } => {
+        pat.map_key_uses(nam, uses);
+        val.map_key_uses(nam, uses);
+        if pat.rebinds(nam) {
+          return;
+        }
+        nxt.as_deref()
+      }
+      Stmt::InPlace { op: _, pat, val, nxt } => {
+        pat.map_key_uses(nam, uses);
+        val.map_key_uses(nam, uses);
+        nxt.as_deref()
+      }
+      Stmt::If { cond, then, otherwise, nxt } => {
+        cond.map_key_uses(nam, uses);
+        then.map_key_uses(nam, uses);
+        otherwise.map_key_uses(nam, uses);
+        nxt.as_deref()
+      }
+      Stmt::Match { arg, with_arg, arms, nxt, .. } | Stmt::Fold { arg, with_arg, arms, nxt, .. } => {
+        arg.map_key_uses(nam, uses);
+        with_arg.iter().for_each(|arg| arg.map_key_uses(nam, uses));
+        for arm in arms {
+          if let Some(guard) = &arm.guard {
+            guard.map_key_uses(nam, uses);
+          }
+          arm.rgt.map_key_uses(nam, uses);
+        }
+        nxt.as_deref()
+      }
+      Stmt::Switch { arg, with_arg, arms, nxt, .. } => {
+        arg.map_key_uses(nam, uses);
+        with_arg.iter().for_each(|arg| arg.map_key_uses(nam, uses));
+        arms.iter().for_each(|arm| arm.map_key_uses(nam, uses));
+        nxt.as_deref()
+      }
+      Stmt::Bend { bnd: _, arg, cond, step, base, nxt } => {
+        arg.iter().for_each(|arg| arg.map_key_uses(nam, uses));
+        cond.map_key_uses(nam, uses);
+        step.map_key_uses(nam, uses);
+        base.map_key_uses(nam, uses);
+        nxt.as_deref()
+      }
+      Stmt::While { cond, bod, state: _, nxt } => {
+        cond.map_key_uses(nam, uses);
+        bod.map_key_uses(nam, uses);
+        nxt.as_deref()
+      }
+      Stmt::For { pat: _, iter, bod, state: _, nxt } => {
+        iter.map_key_uses(nam, uses);
+        bod.map_key_uses(nam, uses);
+        nxt.as_deref()
+      }
+      Stmt::With { typ: _, bod, nxt } => {
+        bod.map_key_uses(nam, uses);
+        nxt.as_deref()
+      }
+      Stmt::Return { term } => {
+        term.map_key_uses(nam, uses);
+        None
+      }
+      Stmt::Use { nam: _, val, nxt } => {
+        val.map_key_uses(nam, uses);
+        Some(&**nxt)
+      }
+      Stmt::Open { nxt, .. } | Stmt::LocalDef { nxt, .. } => Some(&**nxt),
+      Stmt::Err => None,
+    };
+    if let Some(nxt) = nxt {
+      nxt.map_key_uses(nam, uses);
+    }
+  }
+}
+
+/// Chooses the kind of keys of the map assigned to `nam` from its uses,
+/// checking that they agree with how the map is created.
+fn choose_var_keys(nam: &Name, val: &mut Expr, uses: Vec<KeyKind>) -> Result<(), String> {
+  let mut used = None;
+  for kind in uses {
+    used = KeyKind::join(used, Some(kind))
+      .map_err(|e| format!("Map '{nam}' is used with keys of different kinds: {e}."))?;
+  }
+  let Some(used) = used else { return Ok(()) };
+  match val {
+    Expr::Map { entries } => {
+      let created = KeyKind::of_keys(entries.iter().map(|(key, _)| key))?;
+      let created = KeyKind::join(created, Some(used))
+        .map_err(|e| format!("Map '{nam}' is used with keys of different kinds: {e}."))?;
+      *val = KeyKind::init(created.as_ref(), std::mem::take(entries));
+    }
+    Expr::Var { nam: fun } if fun == "Map/empty" && used != KeyKind::U24 => {
+      return Err(format!(
+        "Map '{nam}' is created with 'Map/empty', which only takes u24 keys, but is used with {used} keys. Use '{{}}' or 'Map/hashed' to create it instead."
+      ));
+    }
+    _ => {}
+  }
+  Ok(())
+}
+
+impl AssignPattern {
+  fn choose_map_keys(&mut self) -> Result<(), String> {
+    match self {
+      AssignPattern::MapSet(_, key) => key.choose_map_keys(),
+      AssignPattern::Tup(pats) | AssignPattern::Sup(pats) => {
+        pats.iter_mut().try_for_each(|pat| pat.choose_map_keys())
+      }
+      AssignPattern::Eraser | AssignPattern::Var(_) | AssignPattern::Chn(_) => Ok(()),
+    }
+  }
+
+  fn map_key_uses(&self, nam: &Name, uses: &mut Vec<KeyKind>) {
+    match self {
+      AssignPattern::MapSet(map, key) => {
+        key.map_key_uses(nam, uses);
+        if map == nam {
+          uses.extend(KeyKind::of(key));
+        }
+      }
+      AssignPattern::Tup(pats) | AssignPattern::Sup(pats) => {
+        pats.iter().for_each(|pat| pat.map_key_uses(nam, uses))
+      }
+      AssignPattern::Eraser | AssignPattern::Var(_) | AssignPattern::Chn(_) => {}
+    }
+  }
+
+  /// Whether this pattern assigns a new value to `nam`.
+  fn rebinds(&self, nam: &Name) -> bool {
+    match self {
+      AssignPattern::Var(var) => var == nam,
+      AssignPattern::Tup(pats) | AssignPattern::Sup(pats) => pats.iter().any(|pat| pat.rebinds(nam)),
+      AssignPattern::Eraser | AssignPattern::Chn(_) | AssignPattern::MapSet(..) => false,
+    }
+  }
+}
+
+impl Expr {
+  /// Chooses the kind of keys of the maps created inside the block lambdas of this expression.
+  fn choose_map_keys(&mut self) -> Result<(), String> {
+    match self {
+      Expr::Block { block } => block.choose_map_keys()?,
+      Expr::Lam { bod, .. }
+      | Expr::TreeLeaf { val: bod }
+      | Expr::Try { val: bod }
+      | Expr::Proj { val: bod, .. } => bod.choose_map_keys()?,
+      Expr::MapGet { key, .. } => key.choose_map_keys()?,
+      Expr::Call { fun, args, kwargs } => {
+        fun.choose_map_keys()?;
+        args.iter_mut().try_for_each(|arg| arg.choose_map_keys())?;
+        kwargs.iter_mut().try_for_each(|(_, arg)| arg.choose_map_keys())?;
+      }
+      Expr::Opr { lhs, rhs, .. } | Expr::TreeNode { left: lhs, right: rhs } => {
+        lhs.choose_map_keys()?;
+        rhs.choose_map_keys()?;
+      }
+      Expr::Lst { els } | Expr::Tup { els } | Expr::Sup { els } => {
+        els.iter_mut().try_for_each(|el| el.choose_map_keys())?;
+      }
+      Expr::Ctr { args, kwargs, .. } => {
+        args.iter_mut().try_for_each(|arg| arg.choose_map_keys())?;
+        kwargs.iter_mut().try_for_each(|(_, arg)| arg.choose_map_keys())?;
+      }
+      Expr::LstMap { term, iter, cond, .. } => {
+        term.choose_map_keys()?;
+        iter.choose_map_keys()?;
+        if let Some(cond) = cond {
+          cond.choose_map_keys()?;
+        }
+      }
+      Expr::Map { entries } => {
+        for (key, val) in entries {
+          key.choose_map_keys()?;
+          val.choose_map_keys()?;
+        }
+      }
+      Expr::FStr { parts } => {
+        for part in parts {
+          if let FStrPart::Expr { val, .. } = part {
+            val.choose_map_keys()?;
+          }
+        }
+      }
+      Expr::Upd { val, fields, .. } => {
+        val.choose_map_keys()?;
+        fields.iter_mut().flat_map(|(_, val)| val).try_for_each(|val| val.choose_map_keys())?;
+      }
+      Expr::Era | Expr::Var { .. } | Expr::Chn { .. } | Expr::Num { .. } | Expr::Str { .. } => {}
+    }
+    Ok(())
+  }
+
+  /// Collects the kinds of the keys used to read the map in `nam`.
+  /// Lambdas and comprehensions that bind another variable with the same name are skipped.
+  fn map_key_uses(&self, nam: &Name, uses: &mut Vec<KeyKind>) {
+    match self {
+      Expr::MapGet { nam: map, key } => {
+        key.map_key_uses(nam, uses);
+        if map == nam {
+          uses.extend(KeyKind::of(key));
+        }
+      }
+      Expr::Lam { names, bod } => {
+        if names.iter().all(|(var, _)| var != nam) {
+          bod.map_key_uses(nam, uses);
+        }
+      }
+      Expr::Block { block } => block.map_key_uses(nam, uses),
+      Expr::TreeLeaf { val } | Expr::Try { val } | Expr::Proj { val, .. } => val.map_key_uses(nam, uses),
+      Expr::Call { fun, args, kwargs } => {
+        fun.map_key_uses(nam, uses);
+        args.iter().for_each(|arg| arg.map_key_uses(nam, uses));
+        kwargs.iter().for_each(|(_, arg)| arg.map_key_uses(nam, uses));
+      }
+      Expr::Opr { lhs, rhs, .. } | Expr::TreeNode { left: lhs, right: rhs } => {
+        lhs.map_key_uses(nam, uses);
+        rhs.map_key_uses(nam, uses);
+      }
+      Expr::Lst { els } | Expr::Tup { els } | Expr::Sup { els } => {
+        els.iter().for_each(|el| el.map_key_uses(nam, uses));
+      }
+      Expr::Ctr { args, kwargs, .. } => {
+        args.iter().for_each(|arg| arg.map_key_uses(nam, uses));
+        kwargs.iter().for_each(|(_, arg)| arg.map_key_uses(nam, uses));
+      }
+      Expr::LstMap { term, bind, iter, cond } => {
+        iter.map_key_uses(nam, uses);
+        if bind != nam {
+          term.map_key_uses(nam, uses);
+          if let Some(cond) = cond {
+            cond.map_key_uses(nam, uses);
+          }
+        }
+      }
+      Expr::Map { entries } => {
+        for (key, val) in entries {
+          key.map_key_uses(nam, uses);
+          val.map_key_uses(nam, uses);
+        }
+      }
+      Expr::FStr { parts } => {
+        for part in parts {
+          if let FStrPart::Expr { val, .. } = part {
+            val.map_key_uses(nam, uses);
+          }
+        }
+      }
+      Expr::Upd { val, fields, .. } => {
+        val.map_key_uses(nam, uses);
+        fields.iter().flat_map(|(_, val)| val).for_each(|val| val.map_key_uses(nam, uses));
+      }
+      Expr::Era | Expr::Var { .. } | Expr::Chn { .. } | Expr::Num { .. } | Expr::Str { .. } => {}
+    }
+  }
+}
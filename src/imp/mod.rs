@@ -1,9 +1,13 @@
+mod fstr_kinds;
 pub mod gen_map_get;
+mod gen_try;
+mod loop_state;
+mod map_keys;
 mod order_kwargs;
 pub mod parser;
 pub mod to_fun;
 
-use crate::fun::{CtrField, Name, Num, Op, Source};
+use crate::fun::{derive::Derive, CtrField, Inline, Name, Num, Op, Pattern, Source};
 use indexmap::{IndexMap, IndexSet};
 use interner::global::GlobalString;
 
@@ -21,10 +25,15 @@ pub enum Expr {
   Call { fun: Box<Expr>, args: Vec<Expr>, kwargs: Vec<(Name, Expr)> },
   // "lambda" {names}* ":" {bod}
   Lam { names: Vec<(Name, bool)>, bod: Box<Expr> },
+  // {newline} {statements}
+  // The indented block body of a lambda, ending with a return.
+  Block { block: Box<Stmt> },
   // {lhs} {op} {rhs}
   Opr { op: Op, lhs: Box<Expr>, rhs: Box<Expr> },
   // "\"" ... "\""
   Str { val: GlobalString },
+  // "f\"" ... "\""
+  FStr { parts: Vec<FStrPart> },
   // "[" ... "]"
   Lst { els: Vec<Expr> },
   // "(" ... ")"
@@ -43,11 +52,29 @@ pub enum Expr {
   TreeNode { left: Box<Expr>, right: Box<Expr> },
   // "!" {val}
   TreeLeaf { val: Box<Expr> },
+  // {val} "?"
+  Try { val: Box<Expr> },
+  // {val} "." {field}
+  Proj { val: Box<Expr>, field: Name },
+  // {val} "{" {field} "=" {new_val} ("," {field} "=" {new_val})* "}"
+  // The constructor is resolved when ordering the keyword arguments,
+  // which also fills in the untouched fields with `None`.
+  Upd { val: Box<Expr>, ctr: Option<Name>, fields: Vec<(Name, Option<Expr>)> },
 }
 
+#[derive(Clone, Debug)]
+pub enum FStrPart {
+  // ...
+  Str(GlobalString),
+  // "{" {val} (":" {show})? "}"
+  Expr { val: Box<Expr>, show: Option<Name> },
+}
+
+// "case" {lft} ("if" {guard})? ":" {rgt}
 #[derive(Clone, Debug)]
 pub struct MatchArm {
-  pub lft: Option<Name>,
+  pub lft: Pattern,
+  pub guard: Option<Expr>,
   pub rgt: Stmt,
 }
 
@@ -88,12 +115,12 @@ pub enum Stmt {
     val: Box<Expr>,
     nxt: Option<Box<Stmt>>,
   },
-  // {var} += {val} ";"? {nxt}
+  // {var} += {val} ";"? {nxt}?
   InPlace {
     op: InPlaceOp,
     pat: Box<AssignPattern>,
     val: Box<Expr>,
-    nxt: Box<Stmt>,
+    nxt: Option<Box<Stmt>>,
   },
   // "if" {cond} ":"
   //  {then}
@@ -107,7 +134,7 @@ pub enum Stmt {
     nxt: Option<Box<Stmt>>,
   },
   // "match" ({bind} "=")? {arg} ("with" (({bind}) | ({bind} "=" {arg}) ","?)*)? ":"
-  //   "case" {lft} ":"
+  //   "case" {lft} ("if" {guard})? ":"
   //     {rgt}
   //   ...
   // <nxt>?
@@ -149,7 +176,7 @@ pub enum Stmt {
     nxt: Option<Box<Stmt>>,
   },
   // "fold" ({bind} "=")? {arg} ("with" (({bind}) | ({bind} "=" {arg}) ","?)*)? ":"
-  //   case {lft} ":"
+  //   case {lft} ("if" {guard})? ":"
   //     {rgt}
   //   ...
   // {nxt}?
@@ -161,6 +188,27 @@ pub enum Stmt {
     arms: Vec<MatchArm>,
     nxt: Option<Box<Stmt>>,
   },
+  // "while" {cond} ":"
+  //   {bod}
+  // {nxt}?
+  // The state is the variables of the enclosing scope that are assigned in the body,
+  // found by `find_loop_state`.
+  While {
+    cond: Box<Expr>,
+    bod: Box<Stmt>,
+    state: Vec<Name>,
+    nxt: Option<Box<Stmt>>,
+  },
+  // "for" {pat} "in" {iter} ":"
+  //   {bod}
+  // {nxt}?
+  For {
+    pat: AssignPattern,
+    iter: Box<Expr>,
+    bod: Box<Stmt>,
+    state: Vec<Name>,
+    nxt: Option<Box<Stmt>>,
+  },
   // "with" {typ} ":"
   //   "ask" {id} = {expr} ";"?
   //   ...
@@ -208,20 +256,30 @@ pub struct Variant {
   pub fields: Vec<CtrField>,
 }
 
-// "def" {name} "(" {params} ")" ":" {body}
+// "def" {name} "(" {params} ("," "*" "," {kw_params})? ")" ":" {body}
+// {param} = {name} ("=" {default})?
 #[derive(Clone, Debug)]
 pub struct Definition {
   pub name: Name,
   pub params: Vec<Name>,
+  /// The default values of the parameters that have one.
+  /// Each is either a number or a reference to a generated definition that returns the value.
+  pub defaults: IndexMap<Name, Expr>,
+  /// How many of the last parameters can only be given as named arguments.
+  pub kw_only: usize,
   pub body: Stmt,
   pub source: Source,
+  /// Whether the definition was annotated with `@inline` or `@noinline`.
+  pub inline: Inline,
 }
 
-// "type" {name} ":" {variant}*
+// "type" {name} ("(" {param}* ")")? ("deriving" "(" {derive}* ")")? ":" {variant}*
 #[derive(Clone, Debug)]
 pub struct Enum {
   pub name: Name,
+  pub params: Vec<Name>,
   pub variants: Vec<Variant>,
+  pub deriving: Vec<Derive>,
 }
 
 impl InPlaceOp {
@@ -1,12 +1,30 @@
 use crate::{
   fun::{parser::ParseBook, Name},
-  imp::{Definition, Expr, Stmt},
+  imp::{Definition, Expr, FStrPart, Stmt},
 };
 use indexmap::IndexMap;
 
+impl ParseBook {
+  /// Orders the keyword arguments of all the imp definitions.
+  ///
+  /// It's done before any of them is converted to a fun definition,
+  /// so that every imp function can be called with keyword arguments.
+  pub fn order_kwargs(&mut self) -> Result<(), String> {
+    for idx in 0..self.imp_defs.len() {
+      let mut body = std::mem::take(&mut self.imp_defs[idx].body);
+      let res = body.order_kwargs(self, &mut IndexMap::new());
+      let def = &mut self.imp_defs[idx];
+      def.body = body;
+      res.map_err(|e| format!("In function '{}':\n  {}", def.name, e))?;
+    }
+    Ok(())
+  }
+}
+
 impl Definition {
   /// Traverses the program's definitions and adjusts the order of keyword arguments
   /// in call/constructor expressions to match the order specified in the function or constructor definition.
+  /// Missing arguments are filled with the default values of the function's parameters.
   pub fn order_kwargs(&mut self, book: &ParseBook) -> Result<(), String> {
     let use_map = &mut IndexMap::new();
     self.body.order_kwargs(book, use_map).map_err(|e| format!("In function '{}':\n  {}", self.name, e))
@@ -34,7 +52,9 @@ impl Stmt {
       }
       Stmt::InPlace { val, nxt, .. } => {
         val.order_kwargs(book, use_map)?;
-        nxt.order_kwargs(book, use_map)?;
+        if let Some(nxt) = nxt {
+          nxt.order_kwargs(book, use_map)?;
+        }
       }
       Stmt::If { cond, then, otherwise, nxt } => {
         cond.order_kwargs(book, use_map)?;
@@ -47,6 +67,9 @@ impl Stmt {
       Stmt::Match { arg, arms, nxt, .. } => {
         arg.order_kwargs(book, use_map)?;
         for arm in arms {
+          if let Some(guard) = &mut arm.guard {
+            guard.order_kwargs(book, use_map)?;
+          }
           arm.rgt.order_kwargs(book, use_map)?;
         }
         if let Some(nxt) = nxt {
@@ -65,6 +88,9 @@ impl Stmt {
       Stmt::Fold { arg, arms, nxt, .. } => {
         arg.order_kwargs(book, use_map)?;
         for arm in arms {
+          if let Some(guard) = &mut arm.guard {
+            guard.order_kwargs(book, use_map)?;
+          }
           arm.rgt.order_kwargs(book, use_map)?;
         }
         if let Some(nxt) = nxt {
@@ -82,6 +108,20 @@ impl Stmt {
           nxt.order_kwargs(book, use_map)?;
         }
       }
+      Stmt::While { cond, bod, state: _, nxt } => {
+        cond.order_kwargs(book, use_map)?;
+        bod.order_kwargs(book, use_map)?;
+        if let Some(nxt) = nxt {
+          nxt.order_kwargs(book, use_map)?;
+        }
+      }
+      Stmt::For { pat: _, iter, bod, state: _, nxt } => {
+        iter.order_kwargs(book, use_map)?;
+        bod.order_kwargs(book, use_map)?;
+        if let Some(nxt) = nxt {
+          nxt.order_kwargs(book, use_map)?;
+        }
+      }
       Stmt::With { typ: _, bod, nxt } => {
         bod.order_kwargs(book, use_map)?;
         if let Some(nxt) = nxt {
@@ -113,32 +153,28 @@ impl Expr {
     match self {
       // Named arguments are only allowed when directly calling a named function.
       Expr::Call { fun, args, kwargs } => {
-        if !kwargs.is_empty() {
-          if let Expr::Var { nam } = fun.as_ref() {
-            if let Some(names) = get_args_def_or_ctr(nam, book, use_map) {
-              go_order_kwargs(&names, args, kwargs)?;
-            } else {
-              return Err(format!(
-                "Named args are only allowed when calling a named function, not when calling variable '{nam}'."
-              ));
-            }
-          } else {
-            // TODO: Print expression
-            return Err(
-              "Named args are only allowed when calling a named function, not when calling an expression."
-                .to_string(),
-            );
+        if let Expr::Var { nam } = fun.as_ref() {
+          if let Some(sig) = get_signature(nam, book, use_map) {
+            sig.complete_call(nam, args, kwargs)?;
+          } else if !kwargs.is_empty() {
+            return Err(format!(
+              "Named args are only allowed when calling a named function, not when calling variable '{nam}'."
+            ));
           }
+        } else if !kwargs.is_empty() {
+          // TODO: Print expression
+          return Err(
+            "Named args are only allowed when calling a named function, not when calling an expression."
+              .to_string(),
+          );
         }
         fun.order_kwargs(book, use_map)?;
         for arg in args {
           arg.order_kwargs(book, use_map)?;
         }
-        for (_, arg) in kwargs {
-          arg.order_kwargs(book, use_map)?;
-        }
       }
       Expr::Lam { bod, .. } => bod.order_kwargs(book, use_map)?,
+      Expr::Block { block } => block.order_kwargs(book, use_map)?,
       Expr::Opr { lhs, rhs, .. } => {
         lhs.order_kwargs(book, use_map)?;
         rhs.order_kwargs(book, use_map)?;
@@ -155,9 +191,9 @@ impl Expr {
           cond.order_kwargs(book, use_map)?;
         }
       }
-      Expr::Ctr { name, args, kwargs } => match get_args_def_or_ctr(name, book, use_map) {
-        Some(names) => {
-          go_order_kwargs(&names, args, kwargs)?;
+      Expr::Ctr { name, args, kwargs } => match get_signature(name, book, use_map) {
+        Some(sig) => {
+          sig.complete_call(name, args, kwargs)?;
           for arg in args {
             arg.order_kwargs(book, use_map)?;
           }
@@ -176,49 +212,126 @@ impl Expr {
         left.order_kwargs(book, use_map)?;
         right.order_kwargs(book, use_map)?;
       }
-      Expr::TreeLeaf { val } => {
+      Expr::TreeLeaf { val } | Expr::Try { val } | Expr::Proj { val, .. } => {
         val.order_kwargs(book, use_map)?;
       }
+      Expr::Upd { val, ctr, fields } => {
+        val.order_kwargs(book, use_map)?;
+        for new_val in fields.iter_mut().filter_map(|(_, new_val)| new_val.as_mut()) {
+          new_val.order_kwargs(book, use_map)?;
+        }
+        let (ctr_nam, ctr_fields) = get_record_ctr(fields, book)?;
+        let mut new_vals = IndexMap::new();
+        for (field, new_val) in fields.drain(..) {
+          if new_vals.insert(field.clone(), new_val).is_some() {
+            return Err(format!("Field '{field}' is updated more than once."));
+          }
+        }
+        *fields = ctr_fields.into_iter().map(|f| (f.clone(), new_vals.shift_remove(&f).flatten())).collect();
+        *ctr = Some(ctr_nam);
+      }
+      Expr::FStr { parts } => {
+        for part in parts {
+          if let FStrPart::Expr { val, .. } = part {
+            val.order_kwargs(book, use_map)?;
+          }
+        }
+      }
       Expr::Era | Expr::Var { .. } | Expr::Chn { .. } | Expr::Num { .. } | Expr::Str { .. } => {}
     }
     Ok(())
   }
 }
 
-fn go_order_kwargs(
-  names: &[Name],
-  args: &mut Vec<Expr>,
-  kwargs: &mut Vec<(Name, Expr)>,
-) -> Result<(), String> {
-  if args.len() + kwargs.len() != names.len() {
-    return Err(
-      "Named args are only allowed when calling a function with the exact number of arguments.".to_string(),
-    );
-  }
-  let mut kwargs: IndexMap<Name, Expr> = IndexMap::from_iter(kwargs.drain(..));
-  let remaining_names = &names[args.len()..];
-  for name in remaining_names {
-    if let Some(arg) = kwargs.shift_remove(name) {
-      args.push(arg);
-    } else {
-      return Err(format!("Named arg '{name}' is missing."));
+/// The parameters of a function or constructor, used to resolve the arguments of a call to it.
+struct Signature {
+  params: Vec<Name>,
+  defaults: IndexMap<Name, Expr>,
+  kw_only: usize,
+}
+
+impl Signature {
+  /// Puts the keyword arguments of a call in the positions of their parameters,
+  /// filling the missing ones with their default values.
+  ///
+  /// Calls to functions without default values or keyword-only parameters that
+  /// only have positional arguments are left as they are, so they can be partially applied.
+  fn complete_call(
+    &self,
+    fun: &Name,
+    args: &mut Vec<Expr>,
+    kwargs: &mut Vec<(Name, Expr)>,
+  ) -> Result<(), String> {
+    if kwargs.is_empty() && self.defaults.is_empty() && self.kw_only == 0 {
+      return Ok(());
     }
+    let n_positional = self.params.len() - self.kw_only;
+    if args.len() > n_positional {
+      return Err(format!(
+        "Too many positional arguments in call to '{fun}'. Expected at most {n_positional}, but found {}.",
+        args.len()
+      ));
+    }
+
+    let mut named = IndexMap::new();
+    for (name, arg) in kwargs.drain(..) {
+      let is_positional = self.params[..args.len()].contains(&name);
+      if is_positional || named.contains_key(&name) {
+        return Err(format!("Argument '{name}' given more than once in call to '{fun}'."));
+      }
+      named.insert(name, arg);
+    }
+    for param in &self.params[args.len()..] {
+      if let Some(arg) = named.shift_remove(param) {
+        args.push(arg);
+      } else if let Some(default) = self.defaults.get(param) {
+        args.push(default.clone());
+      } else {
+        return Err(format!("Missing argument '{param}' in call to '{fun}'."));
+      }
+    }
+    if let Some(name) = named.keys().next() {
+      return Err(format!("Unknown named argument '{name}' in call to '{fun}'."));
+    }
+    Ok(())
   }
-  if let Some(name) = kwargs.keys().next() {
-    return Err(format!("Unexpected named arg in function call {}.", name));
-  }
-  Ok(())
 }
 
-fn get_args_def_or_ctr(name: &Name, book: &ParseBook, use_map: &IndexMap<Name, Name>) -> Option<Vec<Name>> {
+fn get_signature(name: &Name, book: &ParseBook, use_map: &IndexMap<Name, Name>) -> Option<Signature> {
   let name = use_map.get(name).unwrap_or(name);
 
-  #[allow(clippy::manual_map)]
+  let params = |params| Signature { params, defaults: IndexMap::new(), kw_only: 0 };
   if let Some(adt_nam) = book.ctrs.get(name) {
-    Some(book.adts[adt_nam].ctrs[name].iter().map(|f| f.nam.clone()).collect())
-  } else if let Some(def) = book.fun_defs.get(name) {
-    Some(def.rules[0].pats.iter().flat_map(|p| p.binds().flatten().cloned()).collect())
+    Some(params(book.adts[adt_nam].ctrs[name].iter().map(|f| f.nam.clone()).collect()))
+  } else if let Some(def) = book.imp_defs.get(name) {
+    Some(Signature { params: def.params.clone(), defaults: def.defaults.clone(), kw_only: def.kw_only })
   } else {
-    None
+    book
+      .fun_defs
+      .get(name)
+      .map(|def| params(def.rules[0].pats.iter().flat_map(|p| p.binds().flatten().cloned()).collect()))
+  }
+}
+
+/// Finds the constructor of the type with a single constructor that has all the updated fields.
+fn get_record_ctr(fields: &[(Name, Option<Expr>)], book: &ParseBook) -> Result<(Name, Vec<Name>), String> {
+  let records = book.adts.iter().filter(|(_, adt)| adt.ctrs.len() == 1).map(|(typ, adt)| {
+    let (ctr, ctr_fields) = adt.ctrs.first().unwrap();
+    (typ, ctr, ctr_fields.iter().map(|f| f.nam.clone()).collect::<Vec<_>>())
+  });
+  let mut candidates =
+    records.filter(|(_, _, ctr_fields)| fields.iter().all(|(f, _)| ctr_fields.contains(f)));
+
+  let Some((fst_typ, fst_ctr, fst_fields)) = candidates.next() else {
+    let names = fields.iter().map(|(f, _)| format!("'{f}'")).collect::<Vec<_>>().join(", ");
+    return Err(format!(
+      "Unknown fields in update. No type with a single constructor has all of the updated fields: {names}."
+    ));
+  };
+  if let Some((other_typ, ..)) = candidates.find(|(_, _, ctr_fields)| *ctr_fields != fst_fields) {
+    return Err(format!(
+      "Ambiguous update. The types '{fst_typ}' and '{other_typ}' both have the updated fields. Use 'open' or 'match' to update it instead."
+    ));
   }
+  Ok((fst_ctr.clone(), fst_fields))
 }
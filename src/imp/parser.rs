@@ -1,11 +1,12 @@
 use crate::{
   fun::{
-    parser::{is_num_char, Indent, ParseResult, ParserCommons},
-    CtrField, Name, Num, Op, STRINGS,
+    parser::{is_name_char, is_native_switch, is_num_char, Indent, ParseResult, ParserCommons},
+    CtrField, FanKind, Inline, Name, Num, Op, Pattern, Tag, STRINGS,
   },
-  imp::{AssignPattern, Definition, Enum, Expr, InPlaceOp, MatchArm, Stmt, Variant},
+  imp::{AssignPattern, Definition, Enum, Expr, FStrPart, InPlaceOp, MatchArm, Stmt, Variant},
   maybe_grow,
 };
+use indexmap::IndexMap;
 use TSPL::Parser;
 
 use super::RepeatedNames;
@@ -95,8 +96,15 @@ impl<'a> PyParser<'a> {
         }
       }
       let names = self.list_like(|p| parse_lam_var(p), "", ":", ",", false, 1)?;
-      let bod = self.parse_expr(inline, true)?;
-      Ok(Expr::Lam { names, bod: Box::new(bod) })
+      self.skip_trivia_inline()?;
+      if self.starts_with("\n") || self.starts_with("\r\n") {
+        // Block lambda, with an indented body of statements.
+        let block = self.parse_lam_block()?;
+        Ok(Expr::Lam { names, bod: Box::new(Expr::Block { block: Box::new(block) }) })
+      } else {
+        let bod = self.parse_expr(inline, true)?;
+        Ok(Expr::Lam { names, bod: Box::new(bod) })
+      }
     } else if self.starts_with("(") {
       self.advance_one();
       let expr = self.parse_expr(inline, true)?;
@@ -120,6 +128,9 @@ impl<'a> PyParser<'a> {
     } else if self.starts_with("\"") {
       // String
       Ok(Expr::Str { val: STRINGS.get(self.parse_quoted_string()?) })
+    } else if self.starts_with("f\"") {
+      // Formatted string
+      self.parse_fstring()
     } else if self.starts_with("'") {
       // Char
       Ok(Expr::Num { val: Num::U24(self.parse_quoted_char()? as u32 & 0x00ff_ffff) })
@@ -145,7 +156,39 @@ impl<'a> PyParser<'a> {
     }
   }
 
+  /// <call_or_postfix> "?"?
+  fn call_or_try(&mut self, inline: bool) -> ParseResult<Expr> {
+    let expr = self.call_or_postfix(inline)?;
+    if self.try_consume_exactly("?") {
+      Ok(Expr::Try { val: Box::new(expr) })
+    } else {
+      Ok(expr)
+    }
+  }
+
   fn call_or_postfix(&mut self, inline: bool) -> ParseResult<Expr> {
+    let mut expr = self.call_or_ctr(inline)?;
+    loop {
+      // field projection
+      if self.starts_with(".") && self.input()[*self.index() + 1..].starts_with(is_name_char) {
+        self.advance_one();
+        let field = self.parse_bend_name()?;
+        expr = Expr::Proj { val: Box::new(expr), field };
+        continue;
+      }
+
+      // record update
+      if self.starts_with_update() {
+        let fields = self.list_like(|p| p.update_field(), "{", "}", ",", true, 1)?;
+        expr = Expr::Upd { val: Box::new(expr), ctr: None, fields };
+        continue;
+      }
+
+      return Ok(expr);
+    }
+  }
+
+  fn call_or_ctr(&mut self, inline: bool) -> ParseResult<Expr> {
     let ini_idx = *self.index();
     let base = self.parse_primary_expr(inline)?;
     if inline {
@@ -201,7 +244,7 @@ impl<'a> PyParser<'a> {
     }
 
     // ctr
-    if self.starts_with("{") {
+    if self.starts_with("{") && !self.starts_with_update() {
       if let Expr::Var { nam } = base {
         let kwargs = self.list_like(|p| p.data_kwarg(), "{", "}", ",", true, 0)?;
         return Ok(Expr::Ctr { name: nam, args: Vec::new(), kwargs });
@@ -268,6 +311,47 @@ impl<'a> PyParser<'a> {
     Ok(Expr::TreeLeaf { val: Box::new(val) })
   }
 
+  /// "f\"" ({str} | "{" {expr} (":" {show})? "}")* "\""
+  fn parse_fstring(&mut self) -> ParseResult<Expr> {
+    self.consume_exactly("f\"")?;
+    let mut parts = vec![];
+    let mut str = String::new();
+    loop {
+      if self.try_consume_exactly("{{") {
+        str.push('{');
+      } else if self.try_consume_exactly("}}") {
+        str.push('}');
+      } else if self.starts_with("{") {
+        self.advance_one();
+        if !str.is_empty() {
+          parts.push(FStrPart::Str(STRINGS.get(std::mem::take(&mut str))));
+        }
+        let val = self.parse_expr(true, true)?;
+        self.skip_trivia_inline()?;
+        let show = if self.try_consume_exactly(":") {
+          self.skip_trivia_inline()?;
+          Some(self.parse_bend_name()?)
+        } else {
+          None
+        };
+        self.skip_trivia_inline()?;
+        self.consume_exactly("}")?;
+        parts.push(FStrPart::Expr { val: Box::new(val), show });
+      } else if self.starts_with("}") {
+        return self.expected("'}}' or an interpolated expression");
+      } else if self.starts_with("\"") {
+        self.advance_one();
+        break;
+      } else {
+        str.push(self.parse_char()?);
+      }
+    }
+    if !str.is_empty() {
+      parts.push(FStrPart::Str(STRINGS.get(str)));
+    }
+    Ok(Expr::FStr { parts })
+  }
+
   fn data_kwarg(&mut self) -> ParseResult<(Name, Expr)> {
     self.skip_trivia();
     let nam = self.parse_bend_name()?;
@@ -276,6 +360,30 @@ impl<'a> PyParser<'a> {
     Ok((nam, expr))
   }
 
+  /// Checks if the input is at the start of a record update, `{ field = ...`.
+  fn starts_with_update(&mut self) -> bool {
+    let ini_idx = *self.index();
+    let is_update = self.try_consume_exactly("{")
+      && {
+        self.skip_trivia();
+        self.parse_bend_name().is_ok()
+      }
+      && {
+        self.skip_trivia();
+        self.starts_with("=") && !self.starts_with("==")
+      };
+    *self.index() = ini_idx;
+    is_update
+  }
+
+  fn update_field(&mut self) -> ParseResult<(Name, Option<Expr>)> {
+    self.skip_trivia();
+    let nam = self.parse_bend_name()?;
+    self.consume("=")?;
+    let expr = self.parse_expr(false, false)?;
+    Ok((nam, Some(expr)))
+  }
+
   fn parse_map_entry(&mut self) -> ParseResult<(Expr, Expr)> {
     let key = self.parse_expr(false, false)?;
     self.consume(":")?;
@@ -392,7 +500,7 @@ impl<'a> PyParser<'a> {
         self.skip_trivia();
       }
       if prec > Op::max_precedence() {
-        return self.call_or_postfix(inline);
+        return self.call_or_try(inline);
       }
       let mut lhs = self.parse_infix_expr(prec + 1, inline)?;
       if inline {
@@ -432,6 +540,30 @@ impl<'a> PyParser<'a> {
     }
   }
 
+  /// Parses the indented body of a block lambda, starting at the end of the `lambda x:` line.
+  /// The body is indented one level deeper than the line where the lambda starts.
+  ///
+  /// Afterwards, goes back to the end of the last line of the body,
+  /// so that the enclosing expression or statement can continue from there.
+  fn parse_lam_block(&mut self) -> ParseResult<Stmt> {
+    let line_start = self.input()[..*self.index()].rfind('\n').map_or(0, |i| i + 1);
+    let line_indent = self.input()[line_start..].chars().take_while(|c| *c == ' ').count() as isize;
+    let mut indent = Indent::Val(line_indent);
+    indent.enter_level();
+
+    self.consume_indent_exactly(indent)?;
+    let (block, nxt_indent) = self.parse_statement(&mut indent)?;
+    if let Indent::Val(nxt) = nxt_indent {
+      if nxt > line_indent {
+        return self.expected_indent(Indent::Val(line_indent), nxt_indent);
+      }
+    }
+
+    let end_idx = self.input()[..*self.index()].trim_end_matches([' ', '\t', '\r', '\n']).len();
+    *self.index() = end_idx;
+    Ok(block)
+  }
+
   /// Parses a statement and returns the indentation of the next statement.
   fn parse_statement(&mut self, indent: &mut Indent) -> ParseResult<(Stmt, Indent)> {
     maybe_grow(|| {
@@ -449,6 +581,10 @@ impl<'a> PyParser<'a> {
         self.parse_fold(indent)
       } else if self.try_parse_keyword("bend") {
         self.parse_bend(indent)
+      } else if self.try_parse_keyword("while") {
+        self.parse_while(indent)
+      } else if self.try_parse_keyword("for") {
+        self.parse_for(indent)
       } else if self.try_parse_keyword("with") {
         self.parse_with(indent)
       } else if self.try_parse_keyword("open") {
@@ -517,10 +653,18 @@ impl<'a> PyParser<'a> {
       let val = self.parse_expr(true, false)?;
       self.skip_trivia_inline()?;
       self.try_consume_exactly(";");
-      self.consume_indent_exactly(*indent)?;
-      let (nxt, nxt_indent) = self.parse_statement(indent)?;
-      let stmt = Stmt::InPlace { op, pat: Box::new(pat), val: Box::new(val), nxt: Box::new(nxt) };
-      return Ok((stmt, nxt_indent));
+      if !self.is_eof() {
+        self.consume_new_line()?;
+      }
+      let nxt_indent = self.advance_newlines()?;
+      if nxt_indent == *indent {
+        let (nxt, nxt_indent) = self.parse_statement(indent)?;
+        let stmt = Stmt::InPlace { op, pat: Box::new(pat), val: Box::new(val), nxt: Some(Box::new(nxt)) };
+        return Ok((stmt, nxt_indent));
+      } else {
+        let stmt = Stmt::InPlace { op, pat: Box::new(pat), val: Box::new(val), nxt: None };
+        return Ok((stmt, nxt_indent));
+      }
     }
 
     self.expected_spanned("statement", ini_idx..end_idx)
@@ -700,12 +844,9 @@ impl<'a> PyParser<'a> {
   fn parse_match_case(&mut self, indent: &mut Indent) -> ParseResult<(MatchArm, Indent)> {
     self.parse_keyword("case")?;
     self.skip_trivia_inline()?;
-    let pat = if self.try_consume_exactly("_") {
-      None
-    } else {
-      let nam = self.labelled(|p| p.parse_bend_name(), "name or '_'")?;
-      Some(nam)
-    };
+    let pat = self.parse_match_pattern()?;
+    self.skip_trivia_inline()?;
+    let guard = if self.try_parse_keyword("if") { Some(self.parse_expr(true, false)?) } else { None };
     self.skip_trivia_inline()?;
     self.consume_exactly(":")?;
     self.consume_new_line()?;
@@ -715,10 +856,68 @@ impl<'a> PyParser<'a> {
     let (body, nxt_indent) = self.parse_statement(indent)?;
     indent.exit_level();
 
-    let stmt = MatchArm { lft: pat, rgt: body };
+    let stmt = MatchArm { lft: pat, guard, rgt: body };
     Ok((stmt, nxt_indent))
   }
 
+  /// <pat1> ("|" <pat1>)*
+  fn parse_match_pattern(&mut self) -> ParseResult<Pattern> {
+    let head = self.parse_primary_match_pattern()?;
+    self.skip_trivia_inline()?;
+    if self.starts_with("|") {
+      let mut alts = vec![head];
+      while self.try_consume_exactly("|") {
+        self.skip_trivia_inline()?;
+        alts.push(self.parse_primary_match_pattern()?);
+        self.skip_trivia_inline()?;
+      }
+      Ok(Pattern::Or(alts))
+    } else {
+      Ok(head)
+    }
+  }
+
+  /// "_" | "*"
+  /// | <nam> ("(" (<pat0> ("," <pat0>)*)? ")")?
+  /// | (<num> | <char>) (".." (<num> | <char>))? | <str>
+  /// | "(" <pat0> ("," <pat0>)* ")"
+  /// | "[" (<pat0> ("," <pat0>)*)? "]"
+  fn parse_primary_match_pattern(&mut self) -> ParseResult<Pattern> {
+    maybe_grow(|| {
+      if self.try_consume_exactly("*") {
+        Ok(Pattern::Var(None))
+      } else if self.starts_with("(") {
+        self.advance_one();
+        let head = self.parse_match_pattern()?;
+        if self.try_consume(",") {
+          let mut els = self.list_like(|p| p.parse_match_pattern(), "", ")", ",", true, 1)?;
+          els.insert(0, head);
+          Ok(Pattern::Fan(FanKind::Tup, Tag::Static, els))
+        } else {
+          self.consume(")")?;
+          Ok(head)
+        }
+      } else if self.starts_with("[") {
+        let els = self.list_like(|p| p.parse_match_pattern(), "[", "]", ",", true, 0)?;
+        Ok(Pattern::Lst(els))
+      } else if self.starts_with("\"") {
+        Ok(Pattern::Str(STRINGS.get(self.parse_quoted_string()?)))
+      } else if self.starts_with("'") || self.peek_one().is_some_and(|c| c.is_ascii_digit()) {
+        self.parse_num_pattern()
+      } else {
+        let nam = self.labelled(|p| p.parse_bend_name(), "pattern")?;
+        if nam == "_" {
+          Ok(Pattern::Var(None))
+        } else if self.starts_with("(") {
+          let els = self.list_like(|p| p.parse_match_pattern(), "(", ")", ",", true, 0)?;
+          Ok(Pattern::Ctr(nam, els))
+        } else {
+          Ok(Pattern::Var(Some(nam)))
+        }
+      }
+    })
+  }
+
   fn parse_switch(&mut self, indent: &mut Indent) -> ParseResult<(Stmt, Indent)> {
     let (bnd, arg) = self.parse_match_arg()?;
     self.skip_trivia_inline()?;
@@ -726,15 +925,9 @@ impl<'a> PyParser<'a> {
     indent.enter_level();
 
     self.consume_indent_exactly(*indent)?;
-    let ini_idx = *self.index();
     let (fst_case, fst_stmt, mut nxt_indent) = self.parse_switch_case(indent)?;
-    let end_idx = *self.index();
-    if fst_case != Some(0) {
-      return self.expected_spanned("case 0", ini_idx..end_idx);
-    }
-    let mut arms = vec![fst_stmt];
-    let mut should_continue = fst_case == Some(0);
-    let mut expected_num = 1;
+    let mut should_continue = fst_case != Pattern::Var(None);
+    let mut arms = vec![(fst_case, fst_stmt)];
     while should_continue {
       if nxt_indent != *indent {
         return self
@@ -743,44 +936,35 @@ impl<'a> PyParser<'a> {
       }
       let (case, stmt, nxt_indent_) = self.parse_switch_case(indent)?;
       nxt_indent = nxt_indent_;
-      if let Some(case) = case {
-        if case != expected_num {
-          return self.expected(&format!("case {}", expected_num));
-        }
-        should_continue = true;
-        arms.push(stmt);
-        expected_num += 1;
-      } else {
-        should_continue = false;
-        arms.push(stmt);
-      }
+      should_continue = case != Pattern::Var(None);
+      arms.push((case, stmt));
     }
     indent.exit_level();
-    if nxt_indent == *indent {
-      let (nxt, nxt_indent) = self.parse_statement(indent)?;
-      let stmt = Stmt::Switch { arg: Box::new(arg), bnd, with_bnd, with_arg, arms, nxt: Some(Box::new(nxt)) };
-      Ok((stmt, nxt_indent))
+
+    let nxt = if nxt_indent == *indent {
+      let (nxt, nxt_indent_) = self.parse_statement(indent)?;
+      nxt_indent = nxt_indent_;
+      Some(Box::new(nxt))
     } else {
-      let stmt = Stmt::Switch { arg: Box::new(arg), bnd, with_bnd, with_arg, arms, nxt: None };
-      Ok((stmt, nxt_indent))
-    }
+      None
+    };
+
+    // Switches that don't have consecutive cases starting from 0 are compiled like a match with patterns.
+    let stmt = if is_native_switch(arms.iter().map(|(case, _)| case)) {
+      let arms = arms.into_iter().map(|(_, stmt)| stmt).collect();
+      Stmt::Switch { arg: Box::new(arg), bnd, with_bnd, with_arg, arms, nxt }
+    } else {
+      let arms = arms.into_iter().map(|(lft, rgt)| MatchArm { lft, guard: None, rgt }).collect();
+      Stmt::Match { arg: Box::new(arg), bnd, with_bnd, with_arg, arms, nxt }
+    };
+    Ok((stmt, nxt_indent))
   }
 
-  fn parse_switch_case(&mut self, indent: &mut Indent) -> ParseResult<(Option<u32>, Stmt, Indent)> {
+  fn parse_switch_case(&mut self, indent: &mut Indent) -> ParseResult<(Pattern, Stmt, Indent)> {
     self.parse_keyword("case")?;
     self.skip_trivia_inline()?;
-    let case = if let Some(c) = self.peek_one() {
-      match c {
-        '_' => {
-          self.advance_one();
-          None
-        }
-        c if c.is_ascii_digit() => Some(self.parse_u32()?),
-        _ => return self.expected("number or '_'"),
-      }
-    } else {
-      return self.expected("number or '_'")?;
-    };
+    let case =
+      if self.try_consume_exactly("_") { Pattern::Var(None) } else { self.parse_switch_pattern()? };
 
     self.skip_trivia_inline()?;
     self.consume_exactly(":")?;
@@ -887,6 +1071,65 @@ impl<'a> PyParser<'a> {
     }
   }
 
+  /// "while" <cond> ":"
+  ///   <bod>
+  /// <nxt>?
+  fn parse_while(&mut self, indent: &mut Indent) -> ParseResult<(Stmt, Indent)> {
+    let cond = self.parse_expr(true, false)?;
+    self.skip_trivia_inline()?;
+    self.consume_exactly(":")?;
+    self.consume_new_line()?;
+    indent.enter_level();
+
+    self.consume_indent_exactly(*indent)?;
+    let (bod, nxt_indent) = self.parse_statement(indent)?;
+    indent.exit_level();
+
+    if nxt_indent == *indent {
+      let (nxt, nxt_indent) = self.parse_statement(indent)?;
+      let stmt =
+        Stmt::While { cond: Box::new(cond), bod: Box::new(bod), state: vec![], nxt: Some(Box::new(nxt)) };
+      Ok((stmt, nxt_indent))
+    } else {
+      let stmt = Stmt::While { cond: Box::new(cond), bod: Box::new(bod), state: vec![], nxt: None };
+      Ok((stmt, nxt_indent))
+    }
+  }
+
+  /// "for" <pat> "in" <iter> ":"
+  ///   <bod>
+  /// <nxt>?
+  fn parse_for(&mut self, indent: &mut Indent) -> ParseResult<(Stmt, Indent)> {
+    self.skip_trivia_inline()?;
+    let ini_idx = *self.index();
+    let pat = self.parse_assign_pattern()?;
+    let end_idx = *self.index();
+    if let AssignPattern::MapSet(..) = pat {
+      return self.expected_spanned("variable or tuple pattern", ini_idx..end_idx);
+    }
+    self.skip_trivia_inline()?;
+    self.parse_keyword("in")?;
+    let iter = self.parse_expr(true, false)?;
+    self.skip_trivia_inline()?;
+    self.consume_exactly(":")?;
+    self.consume_new_line()?;
+    indent.enter_level();
+
+    self.consume_indent_exactly(*indent)?;
+    let (bod, nxt_indent) = self.parse_statement(indent)?;
+    indent.exit_level();
+
+    if nxt_indent == *indent {
+      let (nxt, nxt_indent) = self.parse_statement(indent)?;
+      let stmt =
+        Stmt::For { pat, iter: Box::new(iter), bod: Box::new(bod), state: vec![], nxt: Some(Box::new(nxt)) };
+      Ok((stmt, nxt_indent))
+    } else {
+      let stmt = Stmt::For { pat, iter: Box::new(iter), bod: Box::new(bod), state: vec![], nxt: None };
+      Ok((stmt, nxt_indent))
+    }
+  }
+
   /// "with" <typ> ":"
   ///   <bod>
   /// <nxt>?
@@ -1022,11 +1265,15 @@ impl<'a> PyParser<'a> {
     self.skip_trivia_inline()?;
     let name = self.parse_top_level_name()?;
     self.skip_trivia_inline()?;
-    let params = if self.starts_with("(") {
-      self.list_like(|p| p.parse_bend_name(), "(", ")", ",", true, 0)?
-    } else {
-      vec![]
-    };
+    let ini_idx = *self.index();
+    let (params, defaults, kw_only) =
+      if self.starts_with("(") { self.parse_def_params()? } else { Default::default() };
+    // Local functions are called through variables, so their calls can't be completed.
+    if indent != Indent::Val(0) && (!defaults.is_empty() || kw_only != 0) {
+      let msg = "Local functions can't have default values or named-only parameters.";
+      let end_idx = *self.index();
+      return self.with_ctx(Err(msg), ini_idx..end_idx);
+    }
     self.skip_trivia_inline()?;
     self.consume_exactly(":")?;
     self.consume_new_line()?;
@@ -1037,10 +1284,66 @@ impl<'a> PyParser<'a> {
     indent.exit_level();
 
     // Temporary source, should be overwritten later
-    let def = Definition { name, params, body, source: crate::fun::Source::Generated };
+    let def = Definition {
+      name,
+      params,
+      defaults,
+      kw_only,
+      body,
+      source: crate::fun::Source::Generated,
+      inline: Inline::Auto,
+    };
     Ok((def, nxt_indent))
   }
 
+  /// Parses the parameters of a function, their default values and the
+  /// `*` separating the ones that can only be given as named arguments.
+  fn parse_def_params(&mut self) -> ParseResult<(Vec<Name>, IndexMap<Name, Expr>, usize)> {
+    fn parse_param(p: &mut PyParser) -> ParseResult<Option<(Name, Option<Expr>)>> {
+      p.skip_trivia();
+      if p.try_consume_exactly("*") {
+        return Ok(None);
+      }
+      let nam = p.parse_bend_name()?;
+      p.skip_trivia();
+      let default = if p.try_consume_exactly("=") { Some(p.parse_expr(false, false)?) } else { None };
+      Ok(Some((nam, default)))
+    }
+
+    let ini_idx = *self.index();
+    let items = self.list_like(parse_param, "(", ")", ",", true, 0)?;
+    let end_idx = *self.index();
+
+    let mut params = vec![];
+    let mut defaults = IndexMap::new();
+    let mut kw_only = None;
+    for item in items {
+      match item {
+        None if kw_only.is_some() => {
+          return self.with_ctx(Err("Found more than one '*' in the parameters."), ini_idx..end_idx);
+        }
+        None => kw_only = Some(params.len()),
+        Some((nam, default)) => {
+          if let Some(default) = default {
+            defaults.insert(nam.clone(), default);
+          } else if kw_only.is_none() && !defaults.is_empty() {
+            let msg = format!("Parameter '{nam}' without a default value can't follow a parameter with one.");
+            return self.with_ctx(Err(msg), ini_idx..end_idx);
+          }
+          params.push(nam);
+        }
+      }
+    }
+    let kw_only = match kw_only {
+      Some(idx) if idx == params.len() => {
+        return self.with_ctx(Err("Expected named parameters after '*'."), ini_idx..end_idx);
+      }
+      Some(idx) => params.len() - idx,
+      None => 0,
+    };
+    Ok((params, defaults, kw_only))
+  }
+
   pub fn parse_type(&mut self, mut indent: Indent) -> ParseResult<(Enum, Indent)> {
     if indent != Indent::Val(0) {
       let msg = "Indentation error. Types defined with 'type' must be at the start of the line.";
@@ -1050,6 +1353,9 @@ impl<'a> PyParser<'a> {
 
     self.skip_trivia_inline()?;
     let typ_name = self.parse_top_level_name()?;
+    let params = self.parse_type_params()?;
+    self.skip_trivia_inline()?;
+    let deriving = self.parse_deriving()?;
     self.skip_trivia_inline()?;
     self.consume_exactly(":")?;
     self.consume_new_line()?;
@@ -1059,7 +1365,7 @@ impl<'a> PyParser<'a> {
     let mut variants = Vec::new();
     let mut nxt_indent = indent;
     while nxt_indent == indent {
-      variants.push(self.parse_enum_variant(&typ_name)?);
+      variants.push(self.parse_enum_variant(&typ_name, &params)?);
       if !self.is_eof() {
         self.consume_new_line()?;
       }
@@ -1067,17 +1373,17 @@ impl<'a> PyParser<'a> {
     }
     indent.exit_level();
 
-    let enum_ = Enum { name: typ_name, variants };
+    let enum_ = Enum { name: typ_name, params, variants, deriving };
     Ok((enum_, nxt_indent))
   }
 
-  pub fn parse_enum_variant(&mut self, typ_name: &Name) -> ParseResult<Variant> {
+  pub fn parse_enum_variant(&mut self, typ_name: &Name, params: &[Name]) -> ParseResult<Variant> {
     let ctr_name = self.parse_top_level_name()?;
     let ctr_name = Name::new(format!("{typ_name}/{ctr_name}"));
     let mut fields = Vec::new();
     self.skip_trivia_inline()?;
     if self.starts_with("{") {
-      fields = self.list_like(|p| p.parse_variant_field(), "{", "}", ",", true, 0)?;
+      fields = self.list_like(|p| p.parse_variant_field(params), "{", "}", ",", true, 0)?;
     }
     if let Some(field) = fields.find_repeated_names().into_iter().next() {
       return Err(format!("Found a repeated field '{field}' in constructor {ctr_name}."));
@@ -1085,7 +1391,8 @@ impl<'a> PyParser<'a> {
     Ok(Variant { name: ctr_name, fields })
   }
 
-  pub fn parse_object(&mut self, indent: Indent) -> ParseResult<(Variant, Indent)> {
+  /// Parses an `object` definition as a type with a single constructor with the same name as the type.
+  pub fn parse_object(&mut self, indent: Indent) -> ParseResult<(Enum, Indent)> {
     if indent != Indent::Val(0) {
       let msg = "Indentation error. Types defined with 'object' must be at the start of the line.";
       let idx = *self.index();
@@ -1094,9 +1401,12 @@ impl<'a> PyParser<'a> {
 
     self.skip_trivia_inline()?;
     let name = self.parse_top_level_name()?;
+    let params = self.parse_type_params()?;
+    self.skip_trivia_inline()?;
+    let deriving = self.parse_deriving()?;
     self.skip_trivia_inline()?;
     let fields = if self.starts_with("{") {
-      self.list_like(|p| p.parse_variant_field(), "{", "}", ",", true, 0)?
+      self.list_like(|p| p.parse_variant_field(&params), "{", "}", ",", true, 0)?
     } else {
       vec![]
     };
@@ -1107,14 +1417,16 @@ impl<'a> PyParser<'a> {
       self.consume_new_line()?;
     }
     let nxt_indent = self.advance_newlines()?;
-    Ok((Variant { name, fields }, nxt_indent))
+    let variants = vec![Variant { name: name.clone(), fields }];
+    Ok((Enum { name, params, variants, deriving }, nxt_indent))
   }
 
-  fn parse_variant_field(&mut self) -> ParseResult<CtrField> {
+  fn parse_variant_field(&mut self, params: &[Name]) -> ParseResult<CtrField> {
     let rec = self.try_consume_exactly("~");
     self.skip_trivia();
     let nam = self.parse_bend_name()?;
-    Ok(CtrField { nam, rec })
+    let typ = if self.try_consume(":") { Some(self.parse_type_expr(params)?) } else { None };
+    Ok(CtrField { nam, rec, typ })
   }
 
   fn expected_indent<T>(&mut self, expected: Indent, got: Indent) -> ParseResult<T> {
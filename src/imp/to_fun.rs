@@ -1,16 +1,24 @@
-use super::{AssignPattern, Definition, Expr, InPlaceOp, Stmt};
+use super::{map_keys::KeyKind, AssignPattern, Definition, Expr, FStrPart, InPlaceOp, Stmt};
 use crate::fun::{
   self,
   builtins::{LCONS, LNIL},
   parser::ParseBook,
+  transform::desugar_bend::RECURSIVE_KW,
   Book, Name,
 };
+use itertools::Itertools;
 
 impl ParseBook {
+  #[allow(clippy::mutable_key_type)] // Safe to allow, we know how `Name` works.
   pub fn to_fun(mut self) -> Result<Book, String> {
+    self.order_kwargs()?;
+    let option_fns = self.option_fns();
     for (name, mut def) in std::mem::take(&mut self.imp_defs) {
-      def.order_kwargs(&self)?;
+      def.choose_map_keys().map_err(|e| format!("In function '{name}': {e}"))?;
+      def.infer_fstr_kinds();
       def.gen_map_get();
+      def.gen_try(&option_fns).map_err(|e| format!("In function '{name}': {e}"))?;
+      def.find_loop_state();
 
       if self.fun_defs.contains_key(&name) {
         panic!("Def names collision should be checked at parse time")
@@ -20,7 +28,15 @@ impl ParseBook {
     }
 
     let ParseBook { fun_defs: defs, hvm_defs, adts, ctrs, import_ctx, .. } = self;
-    Ok(Book { defs, hvm_defs, adts, ctrs, entrypoint: None, imports: import_ctx.to_imports() })
+    Ok(Book {
+      defs,
+      hvm_defs,
+      adts,
+      ctrs,
+      entrypoint: None,
+      imports: import_ctx.to_imports(),
+      lazy_recursion_notes: Default::default(),
+    })
   }
 }
 
@@ -37,7 +53,8 @@ impl Definition {
     let rule =
       fun::Rule { pats: self.params.into_iter().map(|param| fun::Pattern::Var(Some(param))).collect(), body };
 
-    let def = fun::Definition::new(self.name, vec![rule], self.source);
+    let mut def = fun::Definition::new(self.name, vec![rule], self.source);
+    def.inline = self.inline;
     Ok(def)
   }
 }
@@ -93,84 +110,68 @@ impl Stmt {
           pat: Box::new(fun::Pattern::Var(Some(map.clone()))),
           val: Box::new(fun::Term::call(
             fun::Term::Ref { nam: fun::Name::new("Map/set") },
-            [fun::Term::Var { nam: map }, key.to_fun(), val.to_fun()],
+            [fun::Term::Var { nam: map }, key.to_fun()?, val.to_fun()?],
           )),
           nxt: Box::new(nxt),
         };
         wrap(nxt_pat, term, ask)
       }
-      Stmt::Assign { pat: AssignPattern::MapSet(..), val: _, nxt: None } => {
-        return Err("Branch ends with map assignment.".to_string());
+      Stmt::Assign { pat: AssignPattern::MapSet(map, key), val, nxt: None } => {
+        let val = fun::Term::call(
+          fun::Term::Ref { nam: fun::Name::new("Map/set") },
+          [fun::Term::Var { nam: map.clone() }, key.to_fun()?, val.to_fun()?],
+        );
+        StmtToFun::Assign(false, fun::Pattern::Var(Some(map)), val)
       }
       Stmt::Assign { pat, val, nxt: Some(nxt) } => {
         let pat = pat.into_fun();
-        let val = val.to_fun();
+        let val = val.to_fun()?;
         let (ask, nxt_pat, nxt) = take(*nxt)?;
         let term = fun::Term::Let { pat: Box::new(pat), val: Box::new(val), nxt: Box::new(nxt) };
         wrap(nxt_pat, term, ask)
       }
       Stmt::Assign { pat, val, nxt: None } => {
         let pat = pat.into_fun();
-        let val = val.to_fun();
+        let val = val.to_fun()?;
         StmtToFun::Assign(false, pat, val)
       }
       Stmt::InPlace { op, pat, val, nxt } => {
-        let (ask, nxt_pat, nxt) = take(*nxt)?;
-        // if it is a mapper operation
-        if let InPlaceOp::Map = op {
-          let term = match &*pat {
-            AssignPattern::MapSet(map, key) => {
-              let rhs = fun::Term::call(
-                fun::Term::r#ref("Map/map"),
-                [fun::Term::Var { nam: map.clone() }, key.clone().to_fun(), val.clone().to_fun()],
-              );
-              fun::Term::Let {
-                pat: Box::new(fun::Pattern::Var(Some(map.clone()))),
-                val: Box::new(rhs),
-                nxt: Box::new(nxt),
-              }
-            }
-            _ => {
-              let rhs = fun::Term::call(val.to_fun(), [pat.clone().into_fun().to_term()]);
-              fun::Term::Let { pat: Box::new(pat.into_fun()), val: Box::new(rhs), nxt: Box::new(nxt) }
-            }
-          };
-
-          return Ok(wrap(nxt_pat, term, ask));
-        }
-
-        // otherwise
-        match *pat {
-          AssignPattern::Var(var) => {
-            let term = fun::Term::Let {
-              pat: Box::new(fun::Pattern::Var(Some(var.clone()))),
-              val: Box::new(fun::Term::Oper {
-                opr: op.to_lang_op(),
-                fst: Box::new(fun::Term::Var { nam: var }),
-                snd: Box::new(val.to_fun()),
-              }),
-              nxt: Box::new(nxt),
+        let (pat, rhs) = match (op, *pat) {
+          // if it is a mapper operation
+          (InPlaceOp::Map, AssignPattern::MapSet(map, key)) => {
+            let rhs = fun::Term::call(
+              fun::Term::r#ref("Map/map"),
+              [fun::Term::Var { nam: map.clone() }, key.to_fun()?, val.to_fun()?],
+            );
+            (fun::Pattern::Var(Some(map)), rhs)
+          }
+          (InPlaceOp::Map, pat) => {
+            let rhs = fun::Term::call(val.to_fun()?, [pat.clone().into_fun().to_term()]);
+            (pat.into_fun(), rhs)
+          }
+          // otherwise
+          (op, AssignPattern::Var(var)) => {
+            let rhs = fun::Term::Oper {
+              opr: op.to_lang_op(),
+              fst: Box::new(fun::Term::Var { nam: var.clone() }),
+              snd: Box::new(val.to_fun()?),
             };
-            wrap(nxt_pat, term, ask)
+            (fun::Pattern::Var(Some(var)), rhs)
           }
-          AssignPattern::MapSet(map, key) => {
+          (op, AssignPattern::MapSet(map, key)) => {
             let temp = Name::new("%0");
             let partial =
               Expr::Opr { op: op.to_lang_op(), lhs: Box::new(Expr::Var { nam: temp.clone() }), rhs: val };
             let map_fn = Expr::Lam { names: vec![(temp, false)], bod: Box::new(partial) };
-            let map_term = fun::Term::call(
+            let rhs = fun::Term::call(
               fun::Term::r#ref("Map/map"),
-              [fun::Term::Var { nam: map.clone() }, key.to_fun(), map_fn.to_fun()],
+              [fun::Term::Var { nam: map.clone() }, key.to_fun()?, map_fn.to_fun()?],
             );
-            let term = fun::Term::Let {
-              pat: Box::new(fun::Pattern::Var(Some(map))),
-              val: Box::new(map_term),
-              nxt: Box::new(nxt),
-            };
-            wrap(nxt_pat, term, ask)
+            (fun::Pattern::Var(Some(map)), rhs)
           }
           _ => unreachable!(),
-        }
+        };
+        wrap_nxt_assign_stmt(rhs, nxt, Some(pat), false)?
       }
       Stmt::If { cond, then, otherwise, nxt } => {
         let (ask, pat, then, else_) = match (then.into_fun()?, otherwise.into_fun()?) {
@@ -194,7 +195,7 @@ impl Stmt {
         };
         let arms = vec![else_, then];
         let term = fun::Term::Swt {
-          arg: Box::new(cond.to_fun()),
+          arg: Box::new(cond.to_fun()?),
           bnd: Some(Name::new("%pred")),
           with_bnd: vec![],
           with_arg: vec![],
@@ -204,13 +205,13 @@ impl Stmt {
         wrap_nxt_assign_stmt(term, nxt, pat, ask)?
       }
       Stmt::Match { arg, bnd, with_bnd, with_arg, arms, nxt } => {
-        let arg = arg.to_fun();
+        let arg = arg.to_fun()?;
         let mut fun_arms = vec![];
         let mut arms = arms.into_iter();
         let fst = arms.next().unwrap();
         let (fst_ask, fst_pat, fst_rgt) = take(fst.rgt)?;
-        let with_arg = with_arg.into_iter().map(Expr::to_fun).collect();
-        fun_arms.push((fst.lft, vec![], fst_rgt));
+        let with_arg = with_arg.into_iter().map(Expr::to_fun).collect::<Result<_, _>>()?;
+        fun_arms.push((fst.lft, guard_to_fun(fst.guard)?, fst_rgt));
         for arm in arms {
           let (arm_ask, arm_pat, arm_rgt) = take(arm.rgt)?;
           match (&arm_pat, &fst_pat) {
@@ -223,20 +224,33 @@ impl Stmt {
             (None, Some(_)) => {
               return Err("Expected 'match' arms to return, but it ends with assignment.".to_string());
             }
-            (Some(_), Some(_)) => fun_arms.push((arm.lft, vec![], arm_rgt)),
-            (None, None) => fun_arms.push((arm.lft, vec![], arm_rgt)),
+            (Some(_), Some(_)) | (None, None) => fun_arms.push((arm.lft, guard_to_fun(arm.guard)?, arm_rgt)),
           }
         }
-        let term = fun::Term::Mat { arg: Box::new(arg), bnd, with_bnd, with_arg, arms: fun_arms };
+        let term = if fun_arms.iter().all(|(lft, ..)| arm_names(lft).is_some()) {
+          let mut arms = vec![];
+          for (lft, guard, rgt) in fun_arms {
+            for nam in arm_names(&lft).unwrap() {
+              arms.push((nam, vec![], rgt.clone(), guard.clone()));
+            }
+          }
+          fun::Term::Mat { arg: Box::new(arg), bnd, with_bnd, with_arg, arms }
+        } else {
+          if fun_arms.last().is_some_and(|(_, guard, _)| guard.is_some()) {
+            return Err("Non-exhaustive 'match' expression. Its last case has a guard.".to_string());
+          }
+          fun::Term::check_switch_pred(&bnd, &fun_arms)?;
+          fun::Term::pattern_match(arg, bnd, with_bnd, with_arg, fun_arms)
+        };
         wrap_nxt_assign_stmt(term, nxt, fst_pat, fst_ask)?
       }
       Stmt::Switch { arg, bnd, with_bnd, with_arg, arms, nxt } => {
-        let arg = arg.to_fun();
+        let arg = arg.to_fun()?;
         let mut fun_arms = vec![];
         let mut arms = arms.into_iter();
         let fst = arms.next().unwrap();
         let (fst_ask, fst_pat, fst) = take(fst)?;
-        let with_arg = with_arg.into_iter().map(Expr::to_fun).collect();
+        let with_arg = with_arg.into_iter().map(Expr::to_fun).collect::<Result<_, _>>()?;
         fun_arms.push(fst);
         for arm in arms {
           let (arm_ask, arm_pat, arm) = take(arm)?;
@@ -259,13 +273,13 @@ impl Stmt {
         wrap_nxt_assign_stmt(term, nxt, fst_pat, fst_ask)?
       }
       Stmt::Fold { arg, bnd, with_bnd, with_arg, arms, nxt } => {
-        let arg = arg.to_fun();
+        let arg = arg.to_fun()?;
         let mut fun_arms = vec![];
         let mut arms = arms.into_iter();
         let fst = arms.next().unwrap();
         let (fst_ask, fst_pat, fst_rgt) = take(fst.rgt)?;
-        fun_arms.push((fst.lft, vec![], fst_rgt));
-        let with_arg = with_arg.into_iter().map(Expr::to_fun).collect();
+        push_fold_arms(&mut fun_arms, fst.lft, guard_to_fun(fst.guard)?, fst_rgt)?;
+        let with_arg = with_arg.into_iter().map(Expr::to_fun).collect::<Result<_, _>>()?;
         for arm in arms {
           let (arm_ask, arm_pat, arm_rgt) = take(arm.rgt)?;
           match (&arm_pat, &fst_pat) {
@@ -278,16 +292,17 @@ impl Stmt {
             (None, Some(_)) => {
               return Err("Expected 'fold' arms to return, but it ends with assignment.".to_string());
             }
-            (Some(_), Some(_)) => fun_arms.push((arm.lft, vec![], arm_rgt)),
-            (None, None) => fun_arms.push((arm.lft, vec![], arm_rgt)),
+            (Some(_), Some(_)) | (None, None) => {
+              push_fold_arms(&mut fun_arms, arm.lft, guard_to_fun(arm.guard)?, arm_rgt)?
+            }
           }
         }
         let term = fun::Term::Fold { arg: Box::new(arg), bnd, with_bnd, with_arg, arms: fun_arms };
         wrap_nxt_assign_stmt(term, nxt, fst_pat, fst_ask)?
       }
       Stmt::Bend { bnd, arg, cond, step, base, nxt } => {
-        let arg = arg.into_iter().map(Expr::to_fun).collect();
-        let cond = cond.to_fun();
+        let arg = arg.into_iter().map(Expr::to_fun).collect::<Result<_, _>>()?;
+        let cond = cond.to_fun()?;
         let (ask, pat, step, base) = match (step.into_fun()?, base.into_fun()?) {
           (StmtToFun::Return(s), StmtToFun::Return(b)) => (false, None, s, b),
           (StmtToFun::Assign(aa, sp, s), StmtToFun::Assign(ba, bp, b)) if sp == bp => {
@@ -311,6 +326,55 @@ impl Stmt {
           fun::Term::Bend { bnd, arg, cond: Box::new(cond), step: Box::new(step), base: Box::new(base) };
         wrap_nxt_assign_stmt(term, nxt, pat, ask)?
       }
+      Stmt::While { cond, bod, state, nxt } => {
+        if cond.has_map_get() {
+          return Err("Map accesses are not allowed in 'while' conditions.".to_string());
+        }
+        let (state_pat, base) = loop_state("while", &state)?;
+        let bod = loop_body("while", &state, *bod)?;
+        let fork = fun::Term::call(fun::Term::Var { nam: Name::new(RECURSIVE_KW) }, state_vars(&state));
+        let step =
+          fun::Term::Let { pat: Box::new(state_pat.clone()), val: Box::new(bod), nxt: Box::new(fork) };
+        let term = fun::Term::Bend {
+          bnd: state.iter().cloned().map(Some).collect(),
+          arg: state_vars(&state).collect(),
+          cond: Box::new(cond.to_fun()?),
+          step: Box::new(step),
+          base: Box::new(base),
+        };
+        wrap_nxt_assign_stmt(term, nxt, Some(state_pat), false)?
+      }
+      Stmt::For { pat, iter, bod, state, nxt } => {
+        const FOR: &str = "%for";
+        const FOR_HEAD: &str = "%for.head";
+        const FOR_TAIL: &str = "%for.tail";
+
+        // Folds the list into a function that receives the state and runs the remaining iterations.
+        let (state_pat, base) = loop_state("for", &state)?;
+        let bod = loop_body("for", &state, *bod)?;
+        let rec = fun::Term::call(fun::Term::Var { nam: Name::new(FOR_TAIL) }, state_vars(&state));
+        let step =
+          fun::Term::Let { pat: Box::new(state_pat.clone()), val: Box::new(bod), nxt: Box::new(rec) };
+        let step = fun::Term::Let {
+          pat: Box::new(pat.into_fun()),
+          val: Box::new(fun::Term::Var { nam: Name::new(FOR_HEAD) }),
+          nxt: Box::new(step),
+        };
+        let step = fun::Term::rfold_lams(step, state.iter().cloned().map(Some));
+        let base = fun::Term::rfold_lams(base, state.iter().cloned().map(Some));
+        let fold = fun::Term::Fold {
+          bnd: Some(Name::new(FOR)),
+          arg: Box::new(iter.to_fun()?),
+          with_bnd: vec![],
+          with_arg: vec![],
+          arms: vec![
+            (Some(Name::new(LNIL)), vec![], base, None),
+            (Some(Name::new(LCONS)), vec![], step, None),
+          ],
+        };
+        let term = fun::Term::call(fold, state_vars(&state));
+        wrap_nxt_assign_stmt(term, nxt, Some(state_pat), false)?
+      }
       Stmt::With { typ, bod, nxt } => {
         let (ask, pat, bod) = take(*bod)?;
         let term = fun::Term::With { typ, bod: Box::new(bod) };
@@ -319,12 +383,12 @@ impl Stmt {
       Stmt::Ask { pat, val, nxt: Some(nxt) } => {
         let (ask, nxt_pat, nxt) = take(*nxt)?;
         let term =
-          fun::Term::Ask { pat: Box::new(pat.into_fun()), val: Box::new(val.to_fun()), nxt: Box::new(nxt) };
+          fun::Term::Ask { pat: Box::new(pat.into_fun()), val: Box::new(val.to_fun()?), nxt: Box::new(nxt) };
         wrap(nxt_pat, term, ask)
       }
       Stmt::Ask { pat, val, nxt: None } => {
         let pat = pat.into_fun();
-        let val = val.to_fun();
+        let val = val.to_fun()?;
         StmtToFun::Assign(true, pat, val)
       }
       Stmt::Open { typ, var, nxt } => {
@@ -334,10 +398,10 @@ impl Stmt {
       }
       Stmt::Use { nam, val, nxt } => {
         let (ask, nxt_pat, nxt) = take(*nxt)?;
-        let term = fun::Term::Use { nam: Some(nam), val: Box::new(val.to_fun()), nxt: Box::new(nxt) };
+        let term = fun::Term::Use { nam: Some(nam), val: Box::new(val.to_fun()?), nxt: Box::new(nxt) };
         wrap(nxt_pat, term, ask)
       }
-      Stmt::Return { term } => StmtToFun::Return(term.to_fun()),
+      Stmt::Return { term } => StmtToFun::Return(term.to_fun()?),
       Stmt::LocalDef { def, nxt } => {
         let (ask, nxt_pat, nxt) = take(*nxt)?;
         let def = def.to_fun()?;
@@ -351,40 +415,49 @@ impl Stmt {
 }
 
 impl Expr {
-  pub fn to_fun(self) -> fun::Term {
-    match self {
+  pub fn to_fun(self) -> Result<fun::Term, String> {
+    let term = match self {
       Expr::Era => fun::Term::Era,
       Expr::Var { nam } => fun::Term::Var { nam },
       Expr::Chn { nam } => fun::Term::Link { nam },
       Expr::Num { val } => fun::Term::Num { val },
       Expr::Call { fun, args, kwargs } => {
         assert!(kwargs.is_empty());
-        let args = args.into_iter().map(Self::to_fun);
-        fun::Term::call(fun.to_fun(), args)
+        let args = args.into_iter().map(Self::to_fun).collect::<Result<Vec<_>, _>>()?;
+        fun::Term::call(fun.to_fun()?, args)
       }
-      Expr::Lam { names, bod } => names.into_iter().rfold(bod.to_fun(), |acc, (name, link)| fun::Term::Lam {
-        tag: fun::Tag::Static,
-        pat: Box::new(if link { fun::Pattern::Chn(name) } else { fun::Pattern::Var(Some(name)) }),
-        bod: Box::new(acc),
-      }),
+      Expr::Lam { names, bod } => {
+        names.into_iter().rfold(bod.to_fun()?, |acc, (name, link)| fun::Term::Lam {
+          tag: fun::Tag::Static,
+          pat: Box::new(if link { fun::Pattern::Chn(name) } else { fun::Pattern::Var(Some(name)) }),
+          bod: Box::new(acc),
+        })
+      }
+      Expr::Block { block } => match block.into_fun()? {
+        StmtToFun::Return(term) => term,
+        StmtToFun::Assign(..) => return Err("Lambda block doesn't end with a return statement.".to_string()),
+      },
       Expr::Opr { op, lhs, rhs } => {
-        fun::Term::Oper { opr: op, fst: Box::new(lhs.to_fun()), snd: Box::new(rhs.to_fun()) }
+        fun::Term::Oper { opr: op, fst: Box::new(lhs.to_fun()?), snd: Box::new(rhs.to_fun()?) }
       }
       Expr::Str { val } => fun::Term::Str { val },
-      Expr::Lst { els } => fun::Term::List { els: els.into_iter().map(Self::to_fun).collect() },
+      Expr::FStr { parts } => fstr_to_fun(parts)?,
+      Expr::Lst { els } => {
+        fun::Term::List { els: els.into_iter().map(Self::to_fun).collect::<Result<_, _>>()? }
+      }
       Expr::Tup { els } => fun::Term::Fan {
         fan: fun::FanKind::Tup,
         tag: fun::Tag::Static,
-        els: els.into_iter().map(Self::to_fun).collect(),
+        els: els.into_iter().map(Self::to_fun).collect::<Result<_, _>>()?,
       },
       Expr::Sup { els } => fun::Term::Fan {
         fan: fun::FanKind::Dup,
         tag: fun::Tag::Auto,
-        els: els.into_iter().map(Self::to_fun).collect(),
+        els: els.into_iter().map(Self::to_fun).collect::<Result<_, _>>()?,
       },
       Expr::Ctr { name, args, kwargs } => {
         assert!(kwargs.is_empty());
-        let args = args.into_iter().map(Self::to_fun);
+        let args = args.into_iter().map(Self::to_fun).collect::<Result<Vec<_>, _>>()?;
         fun::Term::call(fun::Term::Var { nam: name }, args)
       }
       Expr::LstMap { term, bind, iter, cond } => {
@@ -393,11 +466,11 @@ impl Expr {
 
         let cons_branch = fun::Term::call(
           fun::Term::r#ref(LCONS),
-          [term.to_fun(), fun::Term::Var { nam: Name::new(ITER_TAIL) }],
+          [term.to_fun()?, fun::Term::Var { nam: Name::new(ITER_TAIL) }],
         );
         let cons_branch = if let Some(cond) = cond {
           fun::Term::Swt {
-            arg: Box::new(cond.to_fun()),
+            arg: Box::new(cond.to_fun()?),
             bnd: Some(Name::new("%comprehension")),
             with_bnd: vec![],
             with_arg: vec![],
@@ -415,37 +488,284 @@ impl Expr {
 
         fun::Term::Fold {
           bnd: Some(Name::new("%iter")),
-          arg: Box::new(iter.to_fun()),
+          arg: Box::new(iter.to_fun()?),
           with_bnd: vec![],
           with_arg: vec![],
           arms: vec![
-            (Some(Name::new(LNIL)), vec![], fun::Term::r#ref(LNIL)),
-            (Some(Name::new(LCONS)), vec![], cons_branch),
+            (Some(Name::new(LNIL)), vec![], fun::Term::r#ref(LNIL), None),
+            (Some(Name::new(LCONS)), vec![], cons_branch, None),
           ],
         }
       }
-      Expr::Map { entries } => map_init(entries),
+      Expr::Map { entries } => map_init(entries)?,
       Expr::MapGet { .. } => unreachable!(),
+      Expr::Try { .. } => unreachable!(),
+      Expr::Proj { val, field } => {
+        // Projections of a variable are resolved by the fun desugaring of field accesses.
+        const PROJ: &str = "%proj";
+        fun::Term::Let {
+          pat: Box::new(fun::Pattern::Var(Some(Name::new(PROJ)))),
+          val: Box::new(val.to_fun()?),
+          nxt: Box::new(fun::Term::Var { nam: Name::new(format!("{PROJ}.{field}")) }),
+        }
+      }
+      Expr::Upd { val, ctr, fields } => {
+        const UPD: &str = "%upd";
+        let ctr = ctr.unwrap();
+        let args = fields
+          .into_iter()
+          .map(|(field, new_val)| match new_val {
+            Some(new_val) => new_val.to_fun(),
+            None => Ok(fun::Term::Var { nam: Name::new(format!("{UPD}.{field}")) }),
+          })
+          .collect::<Result<Vec<_>, _>>()?;
+        let body = fun::Term::call(fun::Term::Var { nam: ctr.clone() }, args);
+        fun::Term::Mat {
+          arg: Box::new(val.to_fun()?),
+          bnd: Some(Name::new(UPD)),
+          with_bnd: vec![],
+          with_arg: vec![],
+          arms: vec![(Some(ctr), vec![], body, None)],
+        }
+      }
       Expr::TreeNode { left, right } => {
-        let left = left.to_fun();
-        let right = right.to_fun();
+        let left = left.to_fun()?;
+        let right = right.to_fun()?;
         fun::Term::call(fun::Term::r#ref("Tree/Node"), [left, right])
       }
       Expr::TreeLeaf { val } => {
-        let val = val.to_fun();
+        let val = val.to_fun()?;
         fun::Term::app(fun::Term::r#ref("Tree/Leaf"), val)
       }
+    };
+    Ok(term)
+  }
+}
+
+fn map_init(entries: Vec<(Expr, Expr)>) -> Result<fun::Term, String> {
+  let kind = KeyKind::of_keys(entries.iter().map(|(key, _)| key))?;
+  KeyKind::init(kind.as_ref(), entries).to_fun()
+}
+
+/// Concatenates the parts of a formatted string, converting each interpolated value to a string.
+fn fstr_to_fun(parts: Vec<FStrPart>) -> Result<fun::Term, String> {
+  let parts = parts.into_iter().map(|part| match part {
+    FStrPart::Str(val) => Ok(fun::Term::Str { val }),
+    FStrPart::Expr { val, show } => {
+      let show = match show {
+        Some(show) if show == "String" => None,
+        Some(show) if matches!(show.as_ref(), "u24" | "i24" | "f24") => {
+          Some(Name::new(format!("{show}/to_string")))
+        }
+        Some(show) => Some(show),
+        None => {
+          let val = match val.as_ref() {
+            Expr::Var { nam } => format!("'{nam}'"),
+            _ => "an interpolated value".to_string(),
+          };
+          return Err(format!(
+            "The kind of {val} in a formatted string is not known. Write it after the value, like ':u24', ':i24', ':f24' or ':String', or the name of a function that converts it."
+          ));
+        }
+      };
+      match show {
+        Some(show) => Ok(fun::Term::app(fun::Term::Var { nam: show }, val.to_fun()?)),
+        None => val.to_fun(),
+      }
     }
+  });
+  let mut parts = parts.collect::<Result<Vec<_>, _>>()?;
+  let Some(last) = parts.pop() else {
+    return Ok(fun::Term::Str { val: fun::STRINGS.get("") });
+  };
+  Ok(
+    parts
+      .into_iter()
+      .rfold(last, |acc, part| fun::Term::call(fun::Term::r#ref("String/concat"), [part, acc])),
+  )
+}
+
+/// Converts the guard of a 'case', if it has one. Map accesses can't be split out of guards.
+fn guard_to_fun(guard: Option<Expr>) -> Result<Option<fun::Term>, String> {
+  match guard {
+    Some(guard) if guard.has_map_get() => Err(
+      "Map accesses are not allowed in 'case' guards. Read the value before the 'match' instead.".to_string(),
+    ),
+    guard => guard.map(Expr::to_fun).transpose(),
   }
 }
 
-fn map_init(entries: Vec<(Expr, Expr)>) -> fun::Term {
-  let mut map = fun::Term::Ref { nam: fun::Name::new("Map/empty") };
-  for (key, value) in entries {
-    map =
-      fun::Term::call(fun::Term::Ref { nam: fun::Name::new("Map/set") }, [map, key.to_fun(), value.to_fun()]);
+/// Returns the names matched by a case that only has constructor names, variables or '_'.
+/// A case with alternatives matches each of its names.
+fn arm_names(lft: &fun::Pattern) -> Option<Vec<Option<Name>>> {
+  match lft {
+    fun::Pattern::Var(nam) => Some(vec![nam.clone()]),
+    fun::Pattern::Or(alts) => {
+      alts.iter().map(arm_names).collect::<Option<Vec<_>>>().map(|nams| nams.concat())
+    }
+    _ => None,
+  }
+}
+
+fn push_fold_arms(
+  arms: &mut Vec<fun::MatchRule>,
+  lft: fun::Pattern,
+  guard: Option<fun::Term>,
+  rgt: fun::Term,
+) -> Result<(), String> {
+  let Some(nams) = arm_names(&lft) else {
+    return Err("'fold' cases can only match on constructor names or '_'.".to_string());
+  };
+  for nam in nams {
+    arms.push((nam, vec![], rgt.clone(), guard.clone()));
+  }
+  Ok(())
+}
+
+/// Returns the pattern that receives the final state of a loop and the term with its current value.
+fn loop_state(kind: &str, state: &[Name]) -> Result<(fun::Pattern, fun::Term), String> {
+  match state {
+    [] => Err(format!(
+      "The '{kind}' loop has no state, since it doesn't assign any of the variables defined before it."
+    )),
+    [var] => Ok((fun::Pattern::Var(Some(var.clone())), fun::Term::Var { nam: var.clone() })),
+    _ => Ok((
+      fun::Pattern::Fan(
+        fun::FanKind::Tup,
+        fun::Tag::Static,
+        state.iter().cloned().map(|var| fun::Pattern::Var(Some(var))).collect(),
+      ),
+      fun::Term::Fan { fan: fun::FanKind::Tup, tag: fun::Tag::Static, els: state_vars(state).collect() },
+    )),
+  }
+}
+
+fn state_vars(state: &[Name]) -> impl DoubleEndedIterator<Item = fun::Term> + '_ {
+  state.iter().map(|var| fun::Term::Var { nam: var.clone() })
+}
+
+/// Converts the body of a loop, returning the term with the values of the state at the end of an iteration.
+fn loop_body(kind: &str, state: &[Name], mut bod: Stmt) -> Result<fun::Term, String> {
+  let names = state.iter().map(|var| format!("'{var}'")).join(", ");
+  // Ends the body by assigning all the state, so that every update of the iteration is kept.
+  let (pat, val) = match state {
+    [var] => (AssignPattern::Var(var.clone()), Expr::Var { nam: var.clone() }),
+    _ => (
+      AssignPattern::Tup(state.iter().cloned().map(AssignPattern::Var).collect()),
+      Expr::Tup { els: state.iter().map(|var| Expr::Var { nam: var.clone() }).collect() },
+    ),
+  };
+  let end = Stmt::Assign { pat, val: Box::new(val), nxt: None };
+  if bod.has_return() {
+    return Err(format!(
+      "The '{kind}' loop with state {names} has a return, but loops can't return from the function."
+    ));
+  }
+  bod.append(end.clone());
+  bod.end_branches_with(state, &end);
+  match bod.into_fun().map_err(|e| format!("In '{kind}' loop with state {names}: {e}"))? {
+    StmtToFun::Assign(_, _, bod) => Ok(bod),
+    StmtToFun::Return(_) => unreachable!(),
+  }
+}
+
+impl Stmt {
+  /// Places `end` after the last statement of this block.
+  fn append(&mut self, end: Stmt) {
+    match self {
+      Stmt::Assign { nxt, .. }
+      | Stmt::Ask { nxt, .. }
+      | Stmt::InPlace { nxt, .. }
+      | Stmt::If { nxt, .. }
+      | Stmt::Match { nxt, .. }
+      | Stmt::Switch { nxt, .. }
+      | Stmt::Bend { nxt, .. }
+      | Stmt::Fold { nxt, .. }
+      | Stmt::While { nxt, .. }
+      | Stmt::For { nxt, .. }
+      | Stmt::With { nxt, .. } => match nxt {
+        Some(nxt) => nxt.append(end),
+        None => *nxt = Some(Box::new(end)),
+      },
+      Stmt::Open { nxt, .. } | Stmt::Use { nxt, .. } | Stmt::LocalDef { nxt, .. } => nxt.append(end),
+      Stmt::Return { .. } | Stmt::Err => {}
+    }
+  }
+
+  /// Ends each branch of the `if`, `match` and `switch` statements of a loop body with `end`,
+  /// the assignment of the whole state, when the branches only assign variables of the `state`.
+  /// This way, branches that assign different variables of the state end with the same assignment.
+  fn end_branches_with(&mut self, state: &[Name], end: &Stmt) {
+    let in_state =
+      |stmt: &Stmt| stmt.final_binds().is_some_and(|vars| vars.iter().all(|var| state.contains(var)));
+    match self {
+      Stmt::If { then, otherwise, nxt, .. } => {
+        if nxt.is_some() && in_state(then) && in_state(otherwise) {
+          then.append(end.clone());
+          otherwise.append(end.clone());
+        }
+        then.end_branches_with(state, end);
+        otherwise.end_branches_with(state, end);
+      }
+      Stmt::Match { arms, nxt, .. } => {
+        if nxt.is_some() && arms.iter().all(|arm| in_state(&arm.rgt)) {
+          arms.iter_mut().for_each(|arm| arm.rgt.append(end.clone()));
+        }
+        arms.iter_mut().for_each(|arm| arm.rgt.end_branches_with(state, end));
+      }
+      Stmt::Switch { arms, nxt, .. } => {
+        if nxt.is_some() && arms.iter().all(in_state) {
+          arms.iter_mut().for_each(|arm| arm.append(end.clone()));
+        }
+        arms.iter_mut().for_each(|arm| arm.end_branches_with(state, end));
+      }
+      _ => {}
+    }
+    match self {
+      Stmt::Assign { nxt: Some(nxt), .. }
+      | Stmt::Ask { nxt: Some(nxt), .. }
+      | Stmt::InPlace { nxt: Some(nxt), .. }
+      | Stmt::If { nxt: Some(nxt), .. }
+      | Stmt::Match { nxt: Some(nxt), .. }
+      | Stmt::Switch { nxt: Some(nxt), .. }
+      | Stmt::Bend { nxt: Some(nxt), .. }
+      | Stmt::Fold { nxt: Some(nxt), .. }
+      | Stmt::While { nxt: Some(nxt), .. }
+      | Stmt::For { nxt: Some(nxt), .. }
+      | Stmt::With { nxt: Some(nxt), .. }
+      | Stmt::Open { nxt, .. }
+      | Stmt::Use { nxt, .. }
+      | Stmt::LocalDef { nxt, .. } => nxt.end_branches_with(state, end),
+      _ => {}
+    }
+  }
+
+  /// Checks if any of the paths of this block returns from the function.
+  /// The branches of a 'bend' and the cases of a 'fold' return from their recursive step instead.
+  fn has_return(&self) -> bool {
+    match self {
+      Stmt::Return { .. } => true,
+      Stmt::If { then, otherwise, nxt, .. } => {
+        then.has_return() || otherwise.has_return() || nxt.as_ref().is_some_and(|nxt| nxt.has_return())
+      }
+      Stmt::Match { arms, nxt, .. } => {
+        arms.iter().any(|arm| arm.rgt.has_return()) || nxt.as_ref().is_some_and(|nxt| nxt.has_return())
+      }
+      Stmt::Switch { arms, nxt, .. } => {
+        arms.iter().any(Stmt::has_return) || nxt.as_ref().is_some_and(|nxt| nxt.has_return())
+      }
+      Stmt::With { bod, nxt, .. } => bod.has_return() || nxt.as_ref().is_some_and(|nxt| nxt.has_return()),
+      Stmt::Assign { nxt, .. }
+      | Stmt::Ask { nxt, .. }
+      | Stmt::InPlace { nxt, .. }
+      | Stmt::Bend { nxt, .. }
+      | Stmt::Fold { nxt, .. }
+      | Stmt::While { nxt, .. }
+      | Stmt::For { nxt, .. } => nxt.as_ref().is_some_and(|nxt| nxt.has_return()),
+      Stmt::Open { nxt, .. } | Stmt::Use { nxt, .. } | Stmt::LocalDef { nxt, .. } => nxt.has_return(),
+      Stmt::Err => false,
+    }
   }
-  map
 }
 
 /// If the statement was a return, returns it, erroring if there is another after it.
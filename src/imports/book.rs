@@ -81,6 +81,9 @@ impl ParseBook {
       package.apply_adts(&src, main_imports);
       package.apply_defs(&src, main_imports);
 
+      // The imp definitions are only converted with the importing book,
+      // so that it knows about their parameters when ordering keyword arguments.
+      let imp_defs = std::mem::take(&mut package.imp_defs);
       let Book { defs, hvm_defs, adts, .. } = package.to_fun()?;
 
       // Add the ADTs to the importing book,
@@ -96,6 +99,11 @@ impl ParseBook {
         self.add_imported_def(def, diag);
       }
 
+      // The names on the indexmap are the original ones, so we ignore them
+      for def in imp_defs.into_values() {
+        self.add_imported_imp_def(def, diag);
+      }
+
       // The names on the indexmap are the original ones, so we ignore them
       for def in hvm_defs.into_values() {
         self.add_imported_hvm_def(def, diag);
@@ -263,6 +271,12 @@ impl ParseBook {
     }
   }
 
+  fn add_imported_imp_def(&mut self, def: imp::Definition, diag: &mut Diagnostics) {
+    if !self.has_def_conflict(&def.name, diag) {
+      self.imp_defs.insert(def.name.clone(), def);
+    }
+  }
+
   fn add_imported_hvm_def(&mut self, def: HvmDefinition, diag: &mut Diagnostics) {
     if !self.has_def_conflict(&def.name, diag) {
       self.hvm_defs.insert(def.name.clone(), def);
@@ -370,6 +384,15 @@ impl Def for imp::Definition {
   fn apply_binds(&mut self, _maybe_constructor: bool, binds: &BindMap) {
     let bod = std::mem::take(&mut self.body);
     self.body = bod.fold_uses(binds.iter().rev());
+
+    // The default values are used outside of the body, so they must be renamed directly.
+    for default in self.defaults.values_mut() {
+      if let Expr::Var { nam } = default {
+        if let Some(alias) = binds.get(nam) {
+          *nam = alias.clone();
+        }
+      }
+    }
   }
 
   fn apply_types(&mut self, types: &BindMap) {
@@ -390,7 +413,9 @@ impl Def for imp::Definition {
           }
         }
         Stmt::InPlace { nxt, .. } => {
-          rename_with_type(nxt, types);
+          if let Some(nxt) = nxt {
+            rename_with_type(nxt, types);
+          }
         }
         Stmt::If { then, otherwise, nxt, .. } => {
           rename_with_type(then, types);
@@ -430,6 +455,12 @@ impl Def for imp::Definition {
             rename_with_type(nxt, types);
           }
         }
+        Stmt::While { bod, nxt, .. } | Stmt::For { bod, nxt, .. } => {
+          rename_with_type(bod, types);
+          if let Some(nxt) = nxt {
+            rename_with_type(nxt, types);
+          }
+        }
         Stmt::Ask { nxt, .. } => {
           if let Some(nxt) = nxt {
             rename_with_type(nxt, types);
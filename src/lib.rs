@@ -1,5 +1,5 @@
 use crate::{
-  fun::{book_to_hvm, net_to_term::net_to_term, term_to_net::Labels, Book, Ctx, Term},
+  fun::{book_to_hvm, net_to_term::net_to_term, term_to_net::Labels, Book, Ctx, Name, Term},
   hvm::{
     add_recursive_priority::add_recursive_priority,
     check_net_size::{check_net_sizes, MAX_NET_SIZE_CUDA},
@@ -7,11 +7,16 @@ use crate::{
     hvm_book_show_pretty,
     inline::inline_hvm_book,
     mutual_recursion,
+    net_cache::{DefCache, NetCache},
+    pre_reduce::pre_reduce_hvm_net,
     prune::prune_hvm_book,
   },
 };
 use diagnostics::{Diagnostics, DiagnosticsConfig, ERR_INDENT_SIZE};
 use net::hvm_to_net::hvm_to_net;
+use passes::{PassManager, TraceOpts};
+use std::{collections::BTreeSet, path::PathBuf};
+use utils::par_map;
 
 pub mod diagnostics;
 // `Name` triggers this warning, but it's safe because we're not using its internal mutability.
@@ -21,6 +26,7 @@ pub mod hvm;
 pub mod imp;
 pub mod imports;
 pub mod net;
+pub mod passes;
 mod utils;
 
 pub use fun::load_book::{load_file_to_book, load_to_book};
@@ -45,40 +51,102 @@ pub fn compile_book(
   diagnostics_cfg: DiagnosticsConfig,
   args: Option<Vec<Term>>,
 ) -> Result<CompileResult, Diagnostics> {
-  let mut diagnostics = desugar_book(book, opts.clone(), diagnostics_cfg, args)?;
+  let mut passes = PassManager::new(&opts.trace);
+  let res = compile_book_passes(book, &opts, diagnostics_cfg, args, &mut passes);
+  passes.report();
+  res
+}
+
+fn compile_book_passes(
+  book: &mut Book,
+  opts: &CompileOpts,
+  diagnostics_cfg: DiagnosticsConfig,
+  args: Option<Vec<Term>>,
+  passes: &mut PassManager,
+) -> Result<CompileResult, Diagnostics> {
+  // The definitions that didn't change since the last compilation are taken from the cache before desugaring.
+  let mut def_cache = match &opts.cache {
+    Some(dir) if opts.can_cache_defs() => Some(DefCache::new(dir, book, &opts.cache_key(&diagnostics_cfg))),
+    _ => None,
+  };
 
-  let (mut hvm_book, labels) = book_to_hvm(book, &mut diagnostics)?;
+  let mut diagnostics = desugar_book_passes(book, opts, diagnostics_cfg, args, def_cache.as_mut(), passes)?;
+
+  let (mut hvm_book, labels) = book_to_hvm(book, &mut diagnostics, opts.jobs)?;
+
+  if let Some(def_cache) = &def_cache {
+    def_cache.store(book, &hvm_book, &diagnostics);
+  }
+
+  // Only the result of `pre_reduce` is worth caching, and the keys come from the nets before any pass changes them.
+  let cache = match &opts.cache {
+    Some(dir) if opts.pre_reduce => {
+      Some(NetCache::new(dir, &hvm_book, &format!("eta={} inline={}", opts.eta, opts.inline)))
+    }
+    _ => None,
+  };
 
   if opts.eta {
-    hvm_book.defs.values_mut().for_each(eta_reduce_hvm_net);
+    passes.run("eta_reduce", &mut hvm_book, |hvm_book| {
+      par_map(hvm_book.defs.values_mut(), opts.jobs, eta_reduce_hvm_net)
+    });
   }
 
-  mutual_recursion::check_cycles(&hvm_book, &mut diagnostics)?;
+  passes.run("check_cycles", &mut hvm_book, |hvm_book| {
+    mutual_recursion::check_cycles(hvm_book, &book.lazy_recursion_notes, &mut diagnostics)
+  })?;
 
   if opts.eta {
-    hvm_book.defs.values_mut().for_each(eta_reduce_hvm_net);
+    passes.run("eta_reduce", &mut hvm_book, |hvm_book| {
+      par_map(hvm_book.defs.values_mut(), opts.jobs, eta_reduce_hvm_net)
+    });
   }
 
   if opts.inline {
     diagnostics.start_pass();
-    if let Err(e) = inline_hvm_book(&mut hvm_book) {
-      diagnostics.add_book_error(format!("During inlining:\n{:ERR_INDENT_SIZE$}{}", "", e));
-    }
+    passes.run("inline", &mut hvm_book, |hvm_book| {
+      if let Err(e) = inline_hvm_book(hvm_book) {
+        diagnostics.add_book_error(format!("During inlining:\n{:ERR_INDENT_SIZE$}{}", "", e));
+      }
+    });
     diagnostics.fatal(())?;
   }
 
+  if opts.pre_reduce {
+    passes.run("pre_reduce", &mut hvm_book, |hvm_book| {
+      par_map(hvm_book.defs.iter_mut(), opts.jobs, |(nam, net)| {
+        if let Some(cached) = cache.as_ref().and_then(|cache| cache.get(nam)) {
+          *net = cached;
+        } else {
+          pre_reduce_hvm_net(net);
+          if let Some(cache) = &cache {
+            cache.insert(nam, net);
+          }
+        }
+      })
+    });
+  }
+
   if opts.prune {
     let prune_entrypoints = vec![book.hvm_entrypoint().to_string()];
-    prune_hvm_book(&mut hvm_book, &prune_entrypoints);
+    passes.run("prune_hvm", &mut hvm_book, |hvm_book| prune_hvm_book(hvm_book, &prune_entrypoints));
   }
 
   if opts.check_net_size {
-    check_net_sizes(&hvm_book, &mut diagnostics, &opts.target_architecture)?;
+    passes.run("check_net_size", &mut hvm_book, |hvm_book| {
+      check_net_sizes(hvm_book, &mut diagnostics, &opts.target_architecture, opts.jobs)
+    })?;
   }
 
-  add_recursive_priority(&mut hvm_book);
+  passes.run("check_infinite_expansion", &mut hvm_book, |hvm_book| {
+    mutual_recursion::check_infinite_expansion(hvm_book, &mut diagnostics)
+  })?;
 
-  Ok(CompileResult { hvm_book, labels, diagnostics })
+  passes.run("add_recursive_priority", &mut hvm_book, add_recursive_priority);
+
+  #[allow(clippy::mutable_key_type)] // Safe to allow, we know how `Name` works.
+  let cached_defs = def_cache.map(|cache| cache.loaded().clone()).unwrap_or_default();
+  Ok(CompileResult { hvm_book, labels, diagnostics, cached_defs })
 }
 
 pub fn desugar_book(
@@ -86,77 +154,122 @@ pub fn desugar_book(
   opts: CompileOpts,
   diagnostics_cfg: DiagnosticsConfig,
   args: Option<Vec<Term>>,
+) -> Result<Diagnostics, Diagnostics> {
+  let mut passes = PassManager::new(&opts.trace);
+  let res = desugar_book_passes(book, &opts, diagnostics_cfg, args, None, &mut passes);
+  passes.report();
+  res
+}
+
+fn desugar_book_passes(
+  book: &mut Book,
+  opts: &CompileOpts,
+  diagnostics_cfg: DiagnosticsConfig,
+  args: Option<Vec<Term>>,
+  def_cache: Option<&mut DefCache>,
+  passes: &mut PassManager,
 ) -> Result<Diagnostics, Diagnostics> {
   let mut ctx = Ctx::new(book, diagnostics_cfg);
 
-  ctx.check_shared_names();
+  passes.run("check_shared_names", &mut ctx, |ctx| ctx.check_shared_names());
+
+  passes.run("set_entrypoint", &mut ctx, |ctx| ctx.set_entrypoint());
 
-  ctx.set_entrypoint();
+  if let Some(def_cache) = def_cache {
+    passes.run("load_cached_defs", &mut ctx, |ctx| def_cache.load(ctx.book));
+  }
+
+  passes.run("encode_adts", &mut ctx, |ctx| ctx.book.encode_adts(opts.adt_encoding));
 
-  ctx.book.encode_adts(opts.adt_encoding);
+  passes.run("fix_match_defs", &mut ctx, |ctx| ctx.fix_match_defs())?;
 
-  ctx.fix_match_defs()?;
+  passes.run("apply_args", &mut ctx, |ctx| ctx.apply_args(args))?;
 
-  ctx.apply_args(args)?;
+  passes.run("desugar_open", &mut ctx, |ctx| ctx.desugar_open())?;
 
-  ctx.desugar_open()?;
+  passes.run("encode_builtins", &mut ctx, |ctx| ctx.book.encode_builtins());
 
-  ctx.book.encode_builtins();
+  passes.run("resolve_refs", &mut ctx, |ctx| ctx.resolve_refs())?;
 
-  ctx.resolve_refs()?;
+  passes.run("desugar_match_defs", &mut ctx, |ctx| ctx.desugar_match_defs(opts.jobs))?;
 
-  ctx.desugar_match_defs()?;
+  passes.run("fix_match_terms", &mut ctx, |ctx| ctx.fix_match_terms())?;
 
-  ctx.fix_match_terms()?;
+  passes.run("lift_local_defs", &mut ctx, |ctx| ctx.book.lift_local_defs());
 
-  ctx.book.lift_local_defs();
+  if opts.fusion {
+    passes.run("fuse_traversals", &mut ctx, |ctx| ctx.book.fuse_traversals());
+  }
 
-  ctx.desugar_bend()?;
-  ctx.desugar_fold()?;
-  ctx.desugar_with_blocks()?;
+  passes.run("desugar_bend", &mut ctx, |ctx| ctx.desugar_bend())?;
+  passes.run("desugar_fold", &mut ctx, |ctx| ctx.desugar_fold())?;
+  passes.run("desugar_with_blocks", &mut ctx, |ctx| ctx.desugar_with_blocks())?;
+  passes.run("desugar_field_access", &mut ctx, |ctx| ctx.desugar_field_access())?;
 
-  ctx.check_unbound_vars()?;
+  passes.run("check_unbound_vars", &mut ctx, |ctx| ctx.check_unbound_vars())?;
+
+  if opts.float_combinators {
+    let linearize_matches = opts.linearize_matches.enabled();
+    passes.run("make_recursion_lazy", &mut ctx, |ctx| ctx.book.make_recursion_lazy(linearize_matches));
+  }
 
   // Auto match linearization
-  ctx.book.make_var_names_unique();
-  ctx.book.desugar_use();
+  passes.run("make_var_names_unique", &mut ctx, |ctx| ctx.book.make_var_names_unique());
+  passes.run("desugar_use", &mut ctx, |ctx| ctx.book.desugar_use());
   match opts.linearize_matches {
     OptLevel::Disabled => (),
-    OptLevel::Alt => ctx.book.linearize_match_binds(),
-    OptLevel::Enabled => ctx.book.linearize_matches(),
+    OptLevel::Alt => {
+      passes.run("linearize_match_binds", &mut ctx, |ctx| ctx.book.linearize_match_binds());
+      if opts.float_combinators {
+        passes.run("linearize_recursive_matches", &mut ctx, |ctx| ctx.book.linearize_recursive_matches());
+      }
+    }
+    OptLevel::Enabled => passes.run("linearize_matches", &mut ctx, |ctx| ctx.book.linearize_matches()),
   }
   // Manual match linearization
-  ctx.book.linearize_match_with();
+  passes.run("linearize_match_with", &mut ctx, |ctx| ctx.book.linearize_match_with());
+
+  if opts.inline_defs {
+    passes.run("inline_defs", &mut ctx, |ctx| ctx.inline_defs(opts.prune))?;
+  }
 
-  ctx.book.encode_matches(opts.adt_encoding);
+  if opts.constant_folding {
+    passes.run("fold_constants", &mut ctx, |ctx| ctx.book.fold_constants(opts.prune));
+  }
+
+  passes.run("encode_matches", &mut ctx, |ctx| ctx.book.encode_matches(opts.adt_encoding));
 
   // sanity check
-  ctx.check_unbound_vars()?;
+  passes.run("check_unbound_vars", &mut ctx, |ctx| ctx.check_unbound_vars())?;
+
+  passes.run("make_var_names_unique", &mut ctx, |ctx| ctx.book.make_var_names_unique());
+  passes.run("desugar_use", &mut ctx, |ctx| ctx.book.desugar_use());
 
-  ctx.book.make_var_names_unique();
-  ctx.book.desugar_use();
+  if opts.cse {
+    passes.run("eliminate_common_subterms", &mut ctx, |ctx| ctx.book.eliminate_common_subterms());
+  }
 
-  ctx.book.make_var_names_unique();
-  ctx.book.linearize_vars();
+  passes.run("make_var_names_unique", &mut ctx, |ctx| ctx.book.make_var_names_unique());
+  passes.run("linearize_vars", &mut ctx, |ctx| ctx.book.linearize_vars(opts.jobs));
 
   // sanity check
-  ctx.check_unbound_vars()?;
+  passes.run("check_unbound_vars", &mut ctx, |ctx| ctx.check_unbound_vars())?;
 
   if opts.float_combinators {
-    ctx.book.float_combinators(MAX_NET_SIZE_CUDA);
+    passes.run("float_combinators", &mut ctx, |ctx| ctx.book.float_combinators(MAX_NET_SIZE_CUDA));
   }
   // sanity check
-  ctx.check_unbound_refs()?;
+  passes.run("check_unbound_refs", &mut ctx, |ctx| ctx.check_unbound_refs())?;
 
   // Optimizing passes
-  ctx.prune(opts.prune);
+  passes.run("prune", &mut ctx, |ctx| ctx.prune(opts.prune));
   if opts.merge {
-    ctx.book.merge_definitions();
+    passes.run("merge_definitions", &mut ctx, |ctx| ctx.book.merge_definitions());
   }
 
-  ctx.book.expand_main();
+  passes.run("expand_main", &mut ctx, |ctx| ctx.book.expand_main());
 
-  ctx.book.make_var_names_unique();
+  passes.run("make_var_names_unique", &mut ctx, |ctx| ctx.book.make_var_names_unique());
 
   if !ctx.info.has_errors() {
     Ok(ctx.info)
@@ -173,7 +286,7 @@ pub fn run_book(
   args: Option<Vec<Term>>,
   cmd: &str,
 ) -> Result<Option<(Term, String, Diagnostics)>, Diagnostics> {
-  let CompileResult { hvm_book: core_book, labels, diagnostics } =
+  let CompileResult { hvm_book: core_book, labels, diagnostics, .. } =
     compile_book(&mut book, compile_opts.clone(), diagnostics_cfg, args)?;
 
   // TODO: Printing should be taken care by the cli module, but we'd
@@ -356,14 +469,38 @@ pub struct CompileOpts {
   /// Enables [fun::transform::definition_merge]
   pub merge: bool,
 
+  /// Enables [fun::transform::constant_folding].
+  pub constant_folding: bool,
+
+  /// Enables [fun::transform::inline_defs].
+  pub inline_defs: bool,
+
+  /// Enables [fun::transform::fuse_traversals].
+  pub fusion: bool,
+
+  /// Enables [fun::transform::common_subterms].
+  pub cse: bool,
+
   /// Enables [hvm::inline].
   pub inline: bool,
 
+  /// Enables [hvm::pre_reduce].
+  pub pre_reduce: bool,
+
   /// Enables [hvm::check_net_size].
   pub check_net_size: bool,
 
   /// Determines the encoding of constructors and matches.
   pub adt_encoding: AdtEncoding,
+
+  /// Timing and intermediate dumps of the compilation passes.
+  pub trace: TraceOpts,
+
+  /// Directory of the [hvm::net_cache], or `None` to always compile everything.
+  pub cache: Option<PathBuf>,
+
+  /// Number of threads used by the passes that compile each definition independently.
+  pub jobs: usize,
 }
 
 impl CompileOpts {
@@ -376,10 +513,18 @@ impl CompileOpts {
       prune: true,
       float_combinators: true,
       merge: true,
+      constant_folding: true,
+      inline_defs: true,
+      fusion: true,
+      cse: true,
       inline: true,
+      pre_reduce: true,
       linearize_matches: OptLevel::Enabled,
       check_net_size: self.check_net_size,
       adt_encoding: self.adt_encoding,
+      trace: self.trace,
+      cache: self.cache,
+      jobs: self.jobs,
     }
   }
 
@@ -393,12 +538,32 @@ impl CompileOpts {
       linearize_matches: OptLevel::Disabled,
       float_combinators: false,
       merge: false,
+      constant_folding: false,
+      inline_defs: false,
+      fusion: false,
+      cse: false,
       inline: false,
+      pre_reduce: false,
       check_net_size: self.check_net_size,
       adt_encoding: self.adt_encoding,
+      trace: self.trace,
+      cache: self.cache,
+      jobs: self.jobs,
     }
   }
 
+  /// Whether the compiled definitions can be stored in the cache, see [hvm::net_cache::DefCache].
+  /// The options that copy code between definitions would make them depend on how their dependencies were compiled.
+  pub fn can_cache_defs(&self) -> bool {
+    !(self.merge || self.inline_defs || self.constant_folding)
+  }
+
+  /// Identifies the options that change how each definition is compiled, to be used in the cache keys.
+  fn cache_key(&self, diagnostics_cfg: &DiagnosticsConfig) -> String {
+    let opts = CompileOpts { trace: TraceOpts::default(), cache: None, jobs: 1, ..self.clone() };
+    format!("{opts:?} {diagnostics_cfg:?}")
+  }
+
   pub fn check_for_strict(&self) {
     if !self.float_combinators {
       println!(
@@ -424,9 +589,17 @@ impl Default for CompileOpts {
       linearize_matches: OptLevel::Enabled,
       float_combinators: true,
       merge: false,
+      constant_folding: false,
+      inline_defs: false,
+      fusion: false,
+      cse: false,
       inline: false,
+      pre_reduce: false,
       check_net_size: true,
       adt_encoding: AdtEncoding::NumScott,
+      trace: TraceOpts::default(),
+      cache: None,
+      jobs: 1,
     }
   }
 }
@@ -450,6 +623,8 @@ pub struct CompileResult {
   pub diagnostics: Diagnostics,
   pub hvm_book: ::hvm::ast::Book,
   pub labels: Labels,
+  /// The definitions that were taken from the compilation cache instead of being compiled.
+  pub cached_defs: BTreeSet<Name>,
 }
 
 fn maybe_grow<R, F>(f: F) -> R
@@ -2,9 +2,14 @@ use bend::{
   check_book, compile_book, desugar_book,
   diagnostics::{Diagnostics, DiagnosticsConfig, Severity},
   fun::{Book, Name},
-  hvm::hvm_book_show_pretty,
+  hvm::{
+    hvm_book_show_pretty,
+    net_cache::{clean_cache, CACHE_DIR},
+  },
   imports::DefaultLoader,
-  load_file_to_book, run_book, AdtEncoding, CompileOpts, CompilerTarget, OptLevel, RunOpts,
+  load_file_to_book,
+  passes::TraceOpts,
+  run_book, AdtEncoding, CompileOpts, CompilerTarget, OptLevel, RunOpts,
 };
 use clap::{Args, CommandFactory, Parser, Subcommand};
 use std::{
@@ -26,6 +31,47 @@ struct Cli {
 
   #[arg(short = 'e', long, global = true, help = "Use other entrypoint rather than main or Main")]
   pub entrypoint: Option<String>,
+
+  #[arg(long, global = true, help = "Print the time taken by each compilation pass")]
+  pub time_passes: bool,
+
+  #[arg(
+    long,
+    global = true,
+    value_delimiter = ',',
+    value_name = "PASS",
+    help = "Print the program before the given compilation passes"
+  )]
+  pub dump_before: Vec<String>,
+
+  #[arg(
+    long,
+    global = true,
+    value_delimiter = ',',
+    value_name = "PASS",
+    help = "Print the program after the given compilation passes"
+  )]
+  pub dump_after: Vec<String>,
+
+  #[arg(
+    long,
+    global = true,
+    value_name = "NAME",
+    help = "Only print the given definition in the pass dumps"
+  )]
+  pub only_def: Option<String>,
+
+  #[arg(long, global = true, help = "Don't read or write the compilation cache")]
+  pub no_cache: bool,
+
+  #[arg(
+    short = 'j',
+    long,
+    global = true,
+    default_value_t = 1,
+    help = "Number of threads used to compile the definitions, or 0 to use all the available cores"
+  )]
+  pub jobs: usize,
 }
 
 #[derive(Subcommand, Clone, Debug)]
@@ -80,6 +126,11 @@ enum Mode {
     #[arg(help = "Path to the input file")]
     path: PathBuf,
   },
+  /// Removes the compilation cache of a program.
+  CleanCache {
+    #[arg(help = "Path to the program or to its directory", default_value = ".")]
+    path: PathBuf,
+  },
 }
 
 #[derive(Args, Clone, Debug)]
@@ -182,17 +233,34 @@ pub enum OptArgs {
   NoFloatCombinators,
   Merge,
   NoMerge,
+  ConstantFolding,
+  NoConstantFolding,
+  InlineDefs,
+  NoInlineDefs,
+  Fusion,
+  NoFusion,
+  Cse,
+  NoCse,
   Inline,
   NoInline,
+  PreReduce,
+  NoPreReduce,
   CheckNetSize,
   NoCheckNetSize,
   AdtScott,
   AdtNumScott,
 }
 
-fn compile_opts_from_cli(args: &Vec<OptArgs>, compiler_target: CompilerTarget) -> CompileOpts {
+fn compile_opts_from_cli(
+  args: &Vec<OptArgs>,
+  compiler_target: CompilerTarget,
+  trace: TraceOpts,
+  cache: Option<PathBuf>,
+  jobs: usize,
+) -> CompileOpts {
   use OptArgs::*;
-  let mut opts = CompileOpts { target_architecture: compiler_target, ..CompileOpts::default() };
+  let mut opts =
+    CompileOpts { target_architecture: compiler_target, trace, cache, jobs, ..CompileOpts::default() };
 
   for arg in args {
     match arg {
@@ -206,8 +274,18 @@ fn compile_opts_from_cli(args: &Vec<OptArgs>, compiler_target: CompilerTarget) -
       NoFloatCombinators => opts.float_combinators = false,
       Merge => opts.merge = true,
       NoMerge => opts.merge = false,
+      ConstantFolding => opts.constant_folding = true,
+      NoConstantFolding => opts.constant_folding = false,
+      InlineDefs => opts.inline_defs = true,
+      NoInlineDefs => opts.inline_defs = false,
+      Fusion => opts.fusion = true,
+      NoFusion => opts.fusion = false,
+      Cse => opts.cse = true,
+      NoCse => opts.cse = false,
       Inline => opts.inline = true,
       NoInline => opts.inline = false,
+      PreReduce => opts.pre_reduce = true,
+      NoPreReduce => opts.pre_reduce = false,
       CheckNetSize => opts.check_net_size = true,
       NoCheckNetSize => opts.check_net_size = false,
 
@@ -232,6 +310,7 @@ pub enum WarningArgs {
   UnusedDefinition,
   RepeatedBind,
   RecursionCycle,
+  InfiniteExpansion,
   ImportShadow,
   MissingMain,
 }
@@ -252,6 +331,23 @@ fn main() -> ExitCode {
 fn execute_cli_mode(mut cli: Cli) -> Result<(), Diagnostics> {
   let arg_verbose = cli.verbose;
   let entrypoint = cli.entrypoint.take();
+  let trace_opts = TraceOpts {
+    time_passes: cli.time_passes,
+    dump_before: std::mem::take(&mut cli.dump_before),
+    dump_after: std::mem::take(&mut cli.dump_after),
+    only_def: cli.only_def.take(),
+  };
+
+  let jobs = match cli.jobs {
+    0 => std::thread::available_parallelism().map_or(1, usize::from),
+    jobs => jobs,
+  };
+
+  // The cache is kept next to the program being compiled.
+  let no_cache = cli.no_cache;
+  let cache_dir = |path: &Path| -> Option<PathBuf> {
+    (!no_cache).then(|| path.parent().unwrap_or(Path::new("")).join(CACHE_DIR))
+  };
 
   let load_book = |path: &Path, diag: DiagnosticsConfig| -> Result<Book, Diagnostics> {
     let package_loader = DefaultLoader::new(path);
@@ -299,7 +395,8 @@ fn execute_cli_mode(mut cli: Cli) -> Result<(), Diagnostics> {
   match cli.mode {
     Mode::Check { comp_opts, warn_opts, path } => {
       let diagnostics_cfg = set_warning_cfg_from_cli(DiagnosticsConfig::default(), warn_opts);
-      let compile_opts = compile_opts_from_cli(&comp_opts, compiler_target);
+      let compile_opts =
+        compile_opts_from_cli(&comp_opts, compiler_target, trace_opts, cache_dir(&path), jobs);
 
       let mut book = load_book(&path, diagnostics_cfg)?;
       let diagnostics = check_book(&mut book, diagnostics_cfg, compile_opts)?;
@@ -308,7 +405,7 @@ fn execute_cli_mode(mut cli: Cli) -> Result<(), Diagnostics> {
 
     Mode::GenHvm(GenArgs { comp_opts, warn_opts, path, .. }) => {
       let diagnostics_cfg = set_warning_cfg_from_cli(DiagnosticsConfig::default(), warn_opts);
-      let opts = compile_opts_from_cli(&comp_opts, compiler_target);
+      let opts = compile_opts_from_cli(&comp_opts, compiler_target, trace_opts, cache_dir(&path), jobs);
 
       let mut book = load_book(&path, diagnostics_cfg)?;
       let compile_res = compile_book(&mut book, opts, diagnostics_cfg, None)?;
@@ -325,7 +422,8 @@ fn execute_cli_mode(mut cli: Cli) -> Result<(), Diagnostics> {
       let diagnostics_cfg =
         set_warning_cfg_from_cli(DiagnosticsConfig::new(Severity::Allow, arg_verbose), warn_opts);
 
-      let compile_opts = compile_opts_from_cli(&comp_opts, compiler_target);
+      let compile_opts =
+        compile_opts_from_cli(&comp_opts, compiler_target, trace_opts, cache_dir(&path), jobs);
 
       compile_opts.check_for_strict();
 
@@ -350,7 +448,7 @@ fn execute_cli_mode(mut cli: Cli) -> Result<(), Diagnostics> {
     Mode::GenC(GenArgs { comp_opts, warn_opts, path })
     | Mode::GenCu(GenArgs { comp_opts, warn_opts, path }) => {
       let diagnostics_cfg = set_warning_cfg_from_cli(DiagnosticsConfig::default(), warn_opts);
-      let opts = compile_opts_from_cli(&comp_opts, compiler_target);
+      let opts = compile_opts_from_cli(&comp_opts, compiler_target, trace_opts, cache_dir(&path), jobs);
 
       let mut book = load_book(&path, diagnostics_cfg)?;
       let compile_res = compile_book(&mut book, opts, diagnostics_cfg, None)?;
@@ -381,7 +479,7 @@ fn execute_cli_mode(mut cli: Cli) -> Result<(), Diagnostics> {
     Mode::Desugar { path, comp_opts, warn_opts, pretty } => {
       let diagnostics_cfg = set_warning_cfg_from_cli(DiagnosticsConfig::default(), warn_opts);
 
-      let opts = compile_opts_from_cli(&comp_opts, compiler_target);
+      let opts = compile_opts_from_cli(&comp_opts, compiler_target, trace_opts, cache_dir(&path), jobs);
 
       let mut book = load_book(&path, diagnostics_cfg)?;
       let diagnostics = desugar_book(&mut book, opts, diagnostics_cfg, None)?;
@@ -393,6 +491,11 @@ fn execute_cli_mode(mut cli: Cli) -> Result<(), Diagnostics> {
         println!("{book}");
       }
     }
+
+    Mode::CleanCache { path } => {
+      let dir = if path.is_dir() { path.join(CACHE_DIR) } else { cache_dir(&path).unwrap_or_default() };
+      clean_cache(&dir).map_err(|e| format!("Error removing the compilation cache. {e}"))?;
+    }
   };
   Ok(())
 }
@@ -407,6 +510,7 @@ fn set_warning_cfg_from_cli(mut cfg: DiagnosticsConfig, warn_opts: CliWarnOpts)
         cfg.unused_definition = severity;
         cfg.repeated_bind = severity;
         cfg.recursion_cycle = severity;
+        cfg.infinite_expansion = severity;
         cfg.import_shadow = severity;
       }
       WarningArgs::IrrefutableMatch => cfg.irrefutable_match = severity,
@@ -415,6 +519,7 @@ fn set_warning_cfg_from_cli(mut cfg: DiagnosticsConfig, warn_opts: CliWarnOpts)
       WarningArgs::UnusedDefinition => cfg.unused_definition = severity,
       WarningArgs::RepeatedBind => cfg.repeated_bind = severity,
       WarningArgs::RecursionCycle => cfg.recursion_cycle = severity,
+      WarningArgs::InfiniteExpansion => cfg.infinite_expansion = severity,
       WarningArgs::ImportShadow => cfg.import_shadow = severity,
       WarningArgs::MissingMain => cfg.missing_main = severity, // TODO: Should `WarningArgs::All` modify this as well?
     }
@@ -0,0 +1,101 @@
+use crate::{fun::Ctx, hvm::hvm_def_show_pretty};
+use std::time::{Duration, Instant};
+
+/// Options for observing the intermediate states of the compilation pipeline.
+#[derive(Clone, Debug, Default)]
+pub struct TraceOpts {
+  /// Prints how long each pass took at the end of the compilation.
+  pub time_passes: bool,
+
+  /// Passes before which the program is printed.
+  pub dump_before: Vec<String>,
+
+  /// Passes after which the program is printed.
+  pub dump_after: Vec<String>,
+
+  /// If set, only this definition, and the ones generated from it, are printed in the dumps.
+  pub only_def: Option<String>,
+}
+
+/// Runs the steps of the compilation pipeline, keeping track of
+/// how long each one takes and printing the program around them when requested.
+pub struct PassManager<'a> {
+  opts: &'a TraceOpts,
+  timings: Vec<(&'static str, Duration)>,
+}
+
+/// A compilation stage that can be printed in between passes.
+pub trait Ir {
+  /// Shows the definitions accepted by `filter`.
+  fn show(&self, filter: &dyn Fn(&str) -> bool) -> String;
+}
+
+impl<'a> PassManager<'a> {
+  pub fn new(opts: &'a TraceOpts) -> Self {
+    Self { opts, timings: vec![] }
+  }
+
+  /// Runs the pass called `name` over `ir`, dumping it before and after if requested.
+  pub fn run<T: Ir, R>(&mut self, name: &'static str, ir: &mut T, pass: impl FnOnce(&mut T) -> R) -> R {
+    if self.opts.dump_before.iter().any(|pass| pass == name) {
+      self.dump("Before", name, ir);
+    }
+
+    let start = Instant::now();
+    let res = pass(ir);
+    self.timings.push((name, start.elapsed()));
+
+    if self.opts.dump_after.iter().any(|pass| pass == name) {
+      self.dump("After", name, ir);
+    }
+    res
+  }
+
+  fn dump(&self, when: &str, name: &str, ir: &impl Ir) {
+    let filter = |def_name: &str| match &self.opts.only_def {
+      Some(only) => {
+        def_name == only || def_name.strip_prefix(only.as_str()).is_some_and(|s| s.starts_with("__"))
+      }
+      None => true,
+    };
+    eprintln!("// {when} `{name}`:\n{}\n", ir.show(&filter).trim_end());
+  }
+
+  /// Prints the collected pass timings, and warns about requested dumps of passes that never ran.
+  pub fn report(&self) {
+    for pass in self.opts.dump_before.iter().chain(&self.opts.dump_after) {
+      if !self.timings.iter().any(|(name, _)| name == pass) {
+        eprintln!("Warning: Pass '{pass}' was not run, so it wasn't dumped.");
+      }
+    }
+
+    if self.opts.time_passes {
+      let width = self.timings.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+      eprintln!("Pass timings:");
+      for (name, time) in &self.timings {
+        eprintln!("  {name:<width$}  {:>10.3}ms", time.as_secs_f64() * 1000.0);
+      }
+      let total = self.timings.iter().map(|(_, time)| *time).sum::<Duration>();
+      eprintln!("  {:<width$}  {:>10.3}ms", "total", total.as_secs_f64() * 1000.0);
+    }
+  }
+}
+
+impl Ir for Ctx<'_> {
+  fn show(&self, filter: &dyn Fn(&str) -> bool) -> String {
+    let mut s = String::new();
+    for def in self.book.defs.values().filter(|def| filter(&def.name)) {
+      s.push_str(&format!("{def}\n\n"));
+    }
+    for def in self.book.hvm_defs.values().filter(|def| filter(&def.name)) {
+      s.push_str(&format!("hvm {}:\n{}\n\n", def.name, def.body.show()));
+    }
+    s
+  }
+}
+
+impl Ir for ::hvm::ast::Book {
+  fn show(&self, filter: &dyn Fn(&str) -> bool) -> String {
+    self.defs.iter().filter(|(nam, _)| filter(nam)).map(|(nam, def)| hvm_def_show_pretty(nam, def)).collect()
+  }
+}
@@ -36,3 +36,35 @@ macro_rules! multi_iterator {
     }
   };
 }
+
+/// Maps `f` over `items` using up to `jobs` threads, each one taking a contiguous chunk of the items.
+///
+/// The results are in the same order as the items, so the output doesn't depend on the number of jobs.
+pub fn par_map<T: Send, R: Send>(
+  items: impl IntoIterator<Item = T>,
+  jobs: usize,
+  f: impl Fn(T) -> R + Sync,
+) -> Vec<R> {
+  let items = items.into_iter().collect::<Vec<_>>();
+  if jobs <= 1 || items.len() <= 1 {
+    return items.into_iter().map(f).collect();
+  }
+
+  let chunk_size = items.len().div_ceil(jobs);
+  let mut items = items.into_iter();
+  let chunks = std::iter::from_fn(|| Some(items.by_ref().take(chunk_size).collect::<Vec<_>>()))
+    .take_while(|chunk| !chunk.is_empty())
+    .collect::<Vec<_>>();
+
+  let f = &f;
+  std::thread::scope(|s| {
+    let threads = chunks
+      .into_iter()
+      .map(|chunk| s.spawn(move || chunk.into_iter().map(f).collect::<Vec<_>>()))
+      .collect::<Vec<_>>();
+    threads
+      .into_iter()
+      .flat_map(|thread| thread.join().unwrap_or_else(|err| std::panic::resume_unwind(err)))
+      .collect()
+  })
+}
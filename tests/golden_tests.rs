@@ -178,6 +178,23 @@ fn run_file() {
   )
 }
 
+#[test]
+fn run_file_o_all() {
+  run_golden_test_dir(function_name!(), &|code, path| {
+    let _guard = RUN_MUTEX.lock().unwrap();
+    let book = do_parse_book_default(code, path)?;
+    let diagnostics_cfg = DiagnosticsConfig {
+      unused_definition: Severity::Allow,
+      ..DiagnosticsConfig::new(Severity::Error, true)
+    };
+    let run_opts = RunOpts::default();
+
+    let compile_opts = CompileOpts::default().set_all();
+    let (term, _, diags) = run_book(book, run_opts, compile_opts, diagnostics_cfg, None, "run")?.unwrap();
+    Ok(format!("{diags}{term}"))
+  })
+}
+
 #[test]
 fn import_system() {
   run_golden_test_dir_multiple(
@@ -253,8 +270,10 @@ fn simplify_matches() {
     ctx.desugar_open()?;
     ctx.book.encode_builtins();
     ctx.resolve_refs()?;
-    ctx.desugar_match_defs()?;
+    ctx.desugar_match_defs(1)?;
     ctx.fix_match_terms()?;
+    // The builtins have switches on ranges, which are compiled into local definitions.
+    ctx.book.lift_local_defs();
     ctx.desugar_bend()?;
     ctx.desugar_fold()?;
     ctx.desugar_with_blocks()?;
@@ -282,7 +301,7 @@ fn parse_file() {
     ctx.book.encode_adts(AdtEncoding::NumScott);
     ctx.book.encode_builtins();
     ctx.resolve_refs().expect("Resolve refs");
-    ctx.desugar_match_defs().expect("Desugar match defs");
+    ctx.desugar_match_defs(1).expect("Desugar match defs");
     ctx.prune(false);
     Ok(book.to_string())
   })
@@ -303,8 +322,10 @@ fn encode_pattern_match() {
       ctx.desugar_open()?;
       ctx.book.encode_builtins();
       ctx.resolve_refs()?;
-      ctx.desugar_match_defs()?;
+      ctx.desugar_match_defs(1)?;
       ctx.fix_match_terms()?;
+      // The builtins have switches on ranges, which are compiled into local definitions.
+      ctx.book.lift_local_defs();
       ctx.desugar_bend()?;
       ctx.desugar_fold()?;
       ctx.desugar_with_blocks()?;
@@ -318,7 +339,7 @@ fn encode_pattern_match() {
       ctx.book.make_var_names_unique();
       ctx.book.desugar_use();
       ctx.book.make_var_names_unique();
-      ctx.book.linearize_vars();
+      ctx.book.linearize_vars(1);
       ctx.prune(false);
 
       writeln!(result, "{adt_encoding}\n{}\n", ctx.book).unwrap();
@@ -406,14 +427,60 @@ fn cli() {
     args_file.read_to_string(&mut args_buf).expect("Read args");
     let args = args_buf.lines();
 
-    let output =
-      std::process::Command::new(env!("CARGO_BIN_EXE_bend")).args(args).output().expect("Run command");
+    // The compilation cache is tested separately, so it doesn't leave files in the test directories.
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_bend"))
+      .arg("--no-cache")
+      .args(args)
+      .output()
+      .expect("Run command");
     let res =
       format!("{}{}", String::from_utf8_lossy(&output.stderr), String::from_utf8_lossy(&output.stdout));
     Ok(res)
   })
 }
 
+/// Compiles each program three times with the same compilation cache: without any entries,
+/// unchanged, and after replacing it with the version that follows the `# After the change:` line.
+/// Shows the definitions taken from the cache and if the result is the same as without the cache.
+#[test]
+fn compile_cache() {
+  run_golden_test_dir(function_name!(), &|code, path| {
+    let (before, after) = code.split_once("# After the change:\n").expect("Changed version of the program");
+    let dir = std::env::temp_dir().join(format!(
+      "bend-compile-cache-{}-{}",
+      std::process::id(),
+      path.file_stem().unwrap().to_string_lossy()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let mut res = String::new();
+    for (step, code) in [("Without entries", before), ("Unchanged", before), ("Changed", after)] {
+      let compile = |cache: Option<PathBuf>| {
+        let mut book = do_parse_book_default(code, path)?;
+        let opts = CompileOpts { cache, ..CompileOpts::default() };
+        compile_book(&mut book, opts, DiagnosticsConfig::default(), None)
+      };
+      let cached = compile(Some(dir.clone()))?;
+      let uncached = compile(None)?;
+
+      let book = do_parse_book_default(code, path)?;
+      let cached_defs = cached
+        .cached_defs
+        .iter()
+        .filter(|nam| book.defs.get(*nam).is_some_and(|def| !def.is_builtin()))
+        .map(|nam| nam.to_string())
+        .collect::<Vec<_>>();
+      let same = hvm_book_show_pretty(&cached.hvm_book) == hvm_book_show_pretty(&uncached.hvm_book);
+      writeln!(res, "{step}:").unwrap();
+      writeln!(res, "From the cache: [{}]", cached_defs.join(", ")).unwrap();
+      writeln!(res, "Same as without the cache: {same}").unwrap();
+    }
+
+    std::fs::remove_dir_all(&dir).map_err(|e| e.to_string())?;
+    Ok(res)
+  })
+}
+
 #[test]
 fn mutual_recursion() {
   run_golden_test_dir(function_name!(), &|code, path| {